@@ -0,0 +1,68 @@
+use std::{fs, path::PathBuf, sync::OnceLock};
+
+use echo_dvc_proto::{Error, PluginConfig};
+use log::{error, info};
+
+static CONFIG: OnceLock<PluginConfig> = OnceLock::new();
+
+/// Plugin configuration, loaded on first call along with the logs.
+///
+/// Read from a TOML file next to the shared object. An invalid
+/// configuration is reported and replaced by the defaults.
+pub fn load() -> &'static PluginConfig {
+    CONFIG.get_or_init(|| {
+        let Some(path) = config_file() else {
+            let config = PluginConfig::default();
+            crate::logs::init_logs(config.log_level);
+            return config;
+        };
+
+        let config = fs::read_to_string(&path)
+            .map_err(Error::from)
+            .and_then(|text| Ok(PluginConfig::from_toml(&text)?));
+
+        let log_level = config.as_ref().map_or_else(
+            |_| PluginConfig::default().log_level,
+            |config| config.log_level,
+        );
+        crate::logs::init_logs(log_level);
+
+        config
+            .inspect(|_| info!("configuration loaded from {}", path.display()))
+            .unwrap_or_else(|err| {
+                error!(
+                    "invalid configuration in {}, using defaults: {err}",
+                    path.display()
+                );
+                PluginConfig::default()
+            })
+    })
+}
+
+/// `<library name>.toml` next to the shared object, if it exists.
+fn config_file() -> Option<PathBuf> {
+    let path = module_path()?.with_extension("toml");
+    path.is_file().then_some(path)
+}
+
+#[cfg(unix)]
+fn module_path() -> Option<PathBuf> {
+    use std::{
+        ffi::{CStr, OsStr},
+        os::unix::ffi::OsStrExt,
+    };
+
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    let ret = unsafe { libc::dladdr(load as *const libc::c_void, &mut info) };
+    if ret == 0 || info.dli_fname.is_null() {
+        return None;
+    }
+
+    let path = unsafe { CStr::from_ptr(info.dli_fname) };
+    Some(PathBuf::from(OsStr::from_bytes(path.to_bytes())))
+}
+
+#[cfg(not(unix))]
+fn module_path() -> Option<PathBuf> {
+    None
+}
@@ -0,0 +1,604 @@
+use std::{
+    ffi::CString,
+    io, ptr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use echo_dvc_proto::{ChannelHandle, ChannelListener, ChannelWriter, ListenerConfig, OpenChannel};
+use log::{debug, error, info};
+
+use crate::ffi::{
+    BOOL, CHANNEL_RC_OK, DWORD, ERROR_INTERNAL_ERROR, ERROR_INVALID_PARAMETER, IWTSListener,
+    IWTSListenerCallback, IWTSPlugin, IWTSVirtualChannel, IWTSVirtualChannelCallback,
+    IWTSVirtualChannelManager, TRUE, UINT, wStream,
+};
+
+/// FreeRDP counterpart of the COM `EchoDvcPlugin`.
+///
+/// `iface` must stay the first field: FreeRDP hands back the `IWTSPlugin`
+/// pointer, which is cast to the whole struct.
+#[repr(C)]
+pub struct EchoDvcPlugin {
+    iface: IWTSPlugin,
+    /// Never resized after `create`, so the callbacks given to FreeRDP keep
+    /// a stable address.
+    listeners: Vec<EchoDvcListenerCallback>,
+}
+
+/// Listener callback registered for one entry of the listener table.
+#[repr(C)]
+struct EchoDvcListenerCallback {
+    iface: IWTSListenerCallback,
+    listener: Arc<ChannelListener>,
+    handle: *mut IWTSListener,
+}
+
+impl EchoDvcPlugin {
+    /// Allocate the plugin. Ownership goes to FreeRDP, which releases it
+    /// through `Terminated`.
+    pub fn create(listeners: Vec<ListenerConfig>) -> *mut IWTSPlugin {
+        let listeners = listeners
+            .into_iter()
+            .map(|config| EchoDvcListenerCallback {
+                iface: IWTSListenerCallback {
+                    OnNewChannelConnection: Some(on_new_channel_connection),
+                },
+                listener: ChannelListener::new(config),
+                handle: ptr::null_mut(),
+            })
+            .collect();
+
+        let plugin = Box::new(Self {
+            iface: IWTSPlugin {
+                Initialize: Some(initialize),
+                Connected: Some(connected),
+                Disconnected: Some(disconnected),
+                Terminated: Some(terminated),
+                Attached: None,
+                Detached: None,
+                pInterface: ptr::null_mut(),
+            },
+            listeners,
+        });
+
+        Box::into_raw(plugin).cast()
+    }
+}
+
+unsafe extern "C" fn initialize(
+    p_plugin: *mut IWTSPlugin,
+    p_channel_mgr: *mut IWTSVirtualChannelManager,
+) -> UINT {
+    info!("CALLED initialized");
+
+    let plugin = p_plugin.cast::<EchoDvcPlugin>();
+    let (Some(plugin), Some(channel_manager)) = (unsafe { plugin.as_mut() }, unsafe {
+        p_channel_mgr.as_mut()
+    }) else {
+        error!("channel manager is null");
+        return ERROR_INVALID_PARAMETER;
+    };
+
+    let Some(create_listener) = channel_manager.CreateListener else {
+        error!("CreateListener is not available");
+        return ERROR_INTERNAL_ERROR;
+    };
+
+    for callback in &mut plugin.listeners {
+        let name = callback.listener.name();
+        debug!("DVC name is {name:?}");
+
+        let Ok(c_name) = CString::new(name) else {
+            error!("invalid channel name: {name:?}");
+            return ERROR_INVALID_PARAMETER;
+        };
+
+        let flags = 0;
+        let ret = unsafe {
+            create_listener(
+                channel_manager,
+                c_name.as_ptr(),
+                flags,
+                &raw mut callback.iface,
+                &raw mut callback.handle,
+            )
+        };
+
+        if ret != CHANNEL_RC_OK {
+            error!("failed to create listener for {name}: {ret}");
+            return ret;
+        }
+
+        info!("listener created for channel: {name}");
+    }
+
+    CHANNEL_RC_OK
+}
+
+unsafe extern "C" fn connected(_p_plugin: *mut IWTSPlugin) -> UINT {
+    info!("client connected");
+    CHANNEL_RC_OK
+}
+
+unsafe extern "C" fn disconnected(_p_plugin: *mut IWTSPlugin, disconnect_code: DWORD) -> UINT {
+    info!("client disconnected with: {disconnect_code}");
+    CHANNEL_RC_OK
+}
+
+unsafe extern "C" fn terminated(p_plugin: *mut IWTSPlugin) -> UINT {
+    info!("client terminated");
+
+    if !p_plugin.is_null() {
+        let plugin = unsafe { Box::from_raw(p_plugin.cast::<EchoDvcPlugin>()) };
+        for callback in &plugin.listeners {
+            debug!(
+                "{}: {} channel(s) still open",
+                callback.listener.name(),
+                callback.listener.open_channels()
+            );
+        }
+    }
+    CHANNEL_RC_OK
+}
+
+unsafe extern "C" fn on_new_channel_connection(
+    p_listener_callback: *mut IWTSListenerCallback,
+    p_channel: *mut IWTSVirtualChannel,
+    _data: *mut u8,
+    pb_accept: *mut BOOL,
+    pp_callback: *mut *mut IWTSVirtualChannelCallback,
+) -> UINT {
+    let listener_callback = p_listener_callback.cast::<EchoDvcListenerCallback>();
+    let Some(listener_callback) = (unsafe { listener_callback.as_ref() }) else {
+        return ERROR_INVALID_PARAMETER;
+    };
+    let listener = &listener_callback.listener;
+
+    info!("CALLED OnNewChannelConnection on {}", listener.name());
+
+    if p_channel.is_null() || pp_callback.is_null() {
+        error!("failed to get channel ref");
+        return ERROR_INVALID_PARAMETER;
+    }
+
+    // The handler sees `on_open` once FreeRDP has opened the channel, through
+    // `OnOpen`.
+    let writer = FreerdpChannelWriter::new(p_channel);
+    let channel = listener.accept(ChannelHandle::new(writer.clone()));
+
+    unsafe { *pp_callback = EchoDvcChannelCallback::create(channel, writer) };
+    debug!("VirtualChannelCallback ok");
+
+    if let Some(accept) = unsafe { pb_accept.as_mut() } {
+        *accept = TRUE;
+    }
+
+    CHANNEL_RC_OK
+}
+
+/// [`ChannelWriter`] backed by the FreeRDP channel object, until `OnClose`
+/// invalidates it. Clones share the channel.
+#[derive(Clone)]
+struct FreerdpChannelWriter(Arc<Mutex<Option<ChannelPtr>>>);
+
+/// Channel object, only used by the writer with its lock held.
+struct ChannelPtr(*mut IWTSVirtualChannel);
+
+// SAFETY: handlers may write from threads of their own, which can outlive
+// the channel. The pointer is only used with the lock of the writer held,
+// and `OnClose` clears it before FreeRDP frees the channel, so that a write
+// either completes before the channel goes away or fails. FreeRDP
+// serializes writes on a channel internally.
+unsafe impl Send for ChannelPtr {}
+
+impl FreerdpChannelWriter {
+    fn new(channel: *mut IWTSVirtualChannel) -> Self {
+        Self(Arc::new(Mutex::new(Some(ChannelPtr(channel)))))
+    }
+
+    /// Make the next writes fail, waiting for the one in progress.
+    fn invalidate(&self) {
+        *self.lock() = None;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<ChannelPtr>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl ChannelWriter for FreerdpChannelWriter {
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        let channel = self.lock();
+        let Some(ChannelPtr(channel)) = *channel else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "channel closed",
+            ));
+        };
+        let Some(write) = (unsafe { channel.as_ref() }).and_then(|channel| channel.Write) else {
+            return Err(io::Error::other("no Write method"));
+        };
+
+        let size = u32::try_from(data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too large"))?;
+        let ret = unsafe { write(channel, size, data.as_ptr(), ptr::null_mut()) };
+        if ret != CHANNEL_RC_OK {
+            return Err(io::Error::other(format!("Write failed: {ret}")));
+        }
+
+        Ok(())
+    }
+}
+
+/// Per channel state dispatching events to the handler of an
+/// [`OpenChannel`], released by FreeRDP through `OnClose`.
+#[repr(C)]
+struct EchoDvcChannelCallback {
+    iface: IWTSVirtualChannelCallback,
+    channel: OpenChannel,
+    writer: FreerdpChannelWriter,
+}
+
+impl EchoDvcChannelCallback {
+    fn create(
+        channel: OpenChannel,
+        writer: FreerdpChannelWriter,
+    ) -> *mut IWTSVirtualChannelCallback {
+        let callback = Box::new(Self {
+            iface: IWTSVirtualChannelCallback {
+                OnDataReceived: Some(on_data_received),
+                OnOpen: Some(on_open),
+                OnClose: Some(on_close),
+            },
+            channel,
+            writer,
+        });
+
+        Box::into_raw(callback).cast()
+    }
+}
+
+unsafe extern "C" fn on_open(p_channel_callback: *mut IWTSVirtualChannelCallback) -> UINT {
+    info!("CALLED OnOpen");
+
+    let callback = p_channel_callback.cast::<EchoDvcChannelCallback>();
+    let Some(callback) = (unsafe { callback.as_mut() }) else {
+        return ERROR_INVALID_PARAMETER;
+    };
+
+    if let Err(err) = callback.channel.on_open() {
+        error!("failed to open channel handler: {err}");
+        return ERROR_INTERNAL_ERROR;
+    }
+
+    CHANNEL_RC_OK
+}
+
+unsafe extern "C" fn on_data_received(
+    p_channel_callback: *mut IWTSVirtualChannelCallback,
+    data: *mut wStream,
+) -> UINT {
+    info!("CALLED OnDataReceived");
+
+    let callback = p_channel_callback.cast::<EchoDvcChannelCallback>();
+    let (Some(callback), Some(data)) = (unsafe { callback.as_mut() }, unsafe { data.as_ref() })
+    else {
+        return ERROR_INVALID_PARAMETER;
+    };
+
+    let received_buffer = unsafe { data.remaining() };
+    debug!(
+        "received: {} ({:?})",
+        String::from_utf8_lossy(received_buffer),
+        received_buffer
+    );
+
+    if let Err(err) = callback.channel.on_data(received_buffer) {
+        error!("failed to handle data: {err}");
+        return ERROR_INTERNAL_ERROR;
+    }
+
+    CHANNEL_RC_OK
+}
+
+unsafe extern "C" fn on_close(p_channel_callback: *mut IWTSVirtualChannelCallback) -> UINT {
+    info!("CALLED OnClose");
+
+    if !p_channel_callback.is_null() {
+        let mut callback =
+            unsafe { Box::from_raw(p_channel_callback.cast::<EchoDvcChannelCallback>()) };
+        // FreeRDP frees the channel once this returns.
+        callback.writer.invalidate();
+        callback.channel.close();
+    }
+    CHANNEL_RC_OK
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use echo_dvc_proto::{ChannelHandler, DVC_NAME, EchoHandler};
+    use std::{
+        cell::RefCell,
+        ffi::{CStr, c_char, c_void},
+    };
+
+    /// Channel manager double: records the listener and the written data.
+    #[repr(C)]
+    struct MockChannelManager {
+        iface: IWTSVirtualChannelManager,
+        listeners: Vec<(String, *mut IWTSListenerCallback)>,
+    }
+
+    #[repr(C)]
+    struct MockChannel {
+        iface: IWTSVirtualChannel,
+        written: RefCell<Vec<Vec<u8>>>,
+    }
+
+    unsafe extern "C" fn mock_create_listener(
+        p_channel_mgr: *mut IWTSVirtualChannelManager,
+        psz_channel_name: *const c_char,
+        _ul_flags: u32,
+        p_listener_callback: *mut IWTSListenerCallback,
+        _pp_listener: *mut *mut IWTSListener,
+    ) -> UINT {
+        let manager = unsafe { &mut *p_channel_mgr.cast::<MockChannelManager>() };
+        let name = unsafe { CStr::from_ptr(psz_channel_name) };
+        manager
+            .listeners
+            .push((name.to_string_lossy().into_owned(), p_listener_callback));
+        CHANNEL_RC_OK
+    }
+
+    unsafe extern "C" fn mock_write(
+        p_channel: *mut IWTSVirtualChannel,
+        cb_size: u32,
+        p_buffer: *const u8,
+        _p_reserved: *mut c_void,
+    ) -> UINT {
+        let channel = unsafe { &*p_channel.cast::<MockChannel>() };
+        let data = unsafe { std::slice::from_raw_parts(p_buffer, cb_size as usize) };
+        channel.written.borrow_mut().push(data.to_vec());
+        CHANNEL_RC_OK
+    }
+
+    fn mock_channel_manager() -> MockChannelManager {
+        MockChannelManager {
+            iface: IWTSVirtualChannelManager {
+                CreateListener: Some(mock_create_listener),
+                GetChannelId: None,
+                FindChannelById: None,
+                GetChannelName: None,
+                DestroyListener: None,
+            },
+            listeners: Vec::new(),
+        }
+    }
+
+    fn mock_channel() -> MockChannel {
+        MockChannel {
+            iface: IWTSVirtualChannel {
+                Write: Some(mock_write),
+                Close: None,
+            },
+            written: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn stream(data: &mut [u8]) -> wStream {
+        wStream {
+            buffer: data.as_mut_ptr(),
+            pointer: data.as_mut_ptr(),
+            length: data.len(),
+            capacity: data.len(),
+            count: 1,
+            pool: ptr::null_mut(),
+            isAllocatedStream: 0,
+            isOwner: 0,
+        }
+    }
+
+    struct Reverse;
+
+    impl ChannelHandler for Reverse {
+        fn on_data(&mut self, channel: &ChannelHandle, data: &[u8]) -> io::Result<()> {
+            channel.write(&data.iter().rev().copied().collect::<Vec<_>>())
+        }
+    }
+
+    /// Greets the client as soon as the channel is open.
+    struct Greeter;
+
+    impl ChannelHandler for Greeter {
+        fn on_open(&mut self, channel: &ChannelHandle) -> io::Result<()> {
+            channel.write(b"hello")
+        }
+
+        fn on_data(&mut self, _channel: &ChannelHandle, _data: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Keeps the handle of its channel, as handlers writing from threads of
+    /// their own do.
+    struct Keeper(Arc<Mutex<Option<ChannelHandle>>>);
+
+    impl ChannelHandler for Keeper {
+        fn on_open(&mut self, channel: &ChannelHandle) -> io::Result<()> {
+            *self.0.lock().unwrap() = Some(channel.clone());
+            Ok(())
+        }
+
+        fn on_data(&mut self, _channel: &ChannelHandle, _data: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Open a channel on `listener_callback` the way FreeRDP does: accept it,
+    /// then report it open.
+    fn open_channel(
+        listener_callback: *mut IWTSListenerCallback,
+        channel: &mut MockChannel,
+    ) -> *mut IWTSVirtualChannelCallback {
+        let callback = accept_channel(listener_callback, channel);
+        let on_open = unsafe { (*callback).OnOpen.unwrap() };
+        assert_eq!(unsafe { on_open(callback) }, CHANNEL_RC_OK);
+        callback
+    }
+
+    fn accept_channel(
+        listener_callback: *mut IWTSListenerCallback,
+        channel: &mut MockChannel,
+    ) -> *mut IWTSVirtualChannelCallback {
+        let mut accept = 0;
+        let mut callback = ptr::null_mut();
+        let on_new_channel = unsafe { (*listener_callback).OnNewChannelConnection.unwrap() };
+        let ret = unsafe {
+            on_new_channel(
+                listener_callback,
+                &raw mut channel.iface,
+                ptr::null_mut(),
+                &raw mut accept,
+                &raw mut callback,
+            )
+        };
+        assert_eq!(ret, CHANNEL_RC_OK);
+        assert_eq!(accept, TRUE);
+        callback
+    }
+
+    fn send(callback: *mut IWTSVirtualChannelCallback, data: &[u8]) -> UINT {
+        let mut data = data.to_vec();
+        let mut data_stream = stream(&mut data);
+        let on_data_received = unsafe { (*callback).OnDataReceived.unwrap() };
+        unsafe { on_data_received(callback, &raw mut data_stream) }
+    }
+
+    fn close(callback: *mut IWTSVirtualChannelCallback) {
+        let on_close = unsafe { (*callback).OnClose.unwrap() };
+        assert_eq!(unsafe { on_close(callback) }, CHANNEL_RC_OK);
+    }
+
+    #[test]
+    fn initialize_creates_listener() {
+        let plugin = EchoDvcPlugin::create(ListenerConfig::defaults());
+        let mut manager = mock_channel_manager();
+
+        let initialize = unsafe { (*plugin).Initialize.unwrap() };
+        let ret = unsafe { initialize(plugin, &raw mut manager.iface) };
+
+        assert_eq!(ret, CHANNEL_RC_OK);
+        assert_eq!(manager.listeners.len(), 1);
+        assert_eq!(manager.listeners[0].0, DVC_NAME);
+        assert!(!manager.listeners[0].1.is_null());
+
+        unsafe { terminated(plugin) };
+    }
+
+    #[test]
+    fn initialize_without_manager() {
+        let plugin = EchoDvcPlugin::create(ListenerConfig::defaults());
+
+        let ret = unsafe { initialize(plugin, ptr::null_mut()) };
+        assert_eq!(ret, ERROR_INVALID_PARAMETER);
+
+        unsafe { terminated(plugin) };
+    }
+
+    #[test]
+    fn echo_received_data() {
+        let plugin = EchoDvcPlugin::create(ListenerConfig::defaults());
+        let mut manager = mock_channel_manager();
+        unsafe { initialize(plugin, &raw mut manager.iface) };
+
+        let mut channel = mock_channel();
+        let callback = open_channel(manager.listeners[0].1, &mut channel);
+
+        let mut data = *b"\0skip\0hello\0";
+        let mut data_stream = stream(&mut data);
+        data_stream.pointer = unsafe { data_stream.buffer.add(6) };
+
+        let on_data_received = unsafe { (*callback).OnDataReceived.unwrap() };
+        let ret = unsafe { on_data_received(callback, &raw mut data_stream) };
+        assert_eq!(ret, CHANNEL_RC_OK);
+        assert_eq!(*channel.written.borrow(), [b"hello\0".to_vec()]);
+
+        close(callback);
+        unsafe { terminated(plugin) };
+    }
+
+    #[test]
+    fn channels_are_routed_to_their_listener() {
+        let plugin = EchoDvcPlugin::create(vec![
+            ListenerConfig::new("ECHO", || Box::new(EchoHandler)),
+            ListenerConfig::new("REVERSE", || Box::new(Reverse)),
+        ]);
+        let mut manager = mock_channel_manager();
+        let ret = unsafe { initialize(plugin, &raw mut manager.iface) };
+        assert_eq!(ret, CHANNEL_RC_OK);
+
+        let names: Vec<_> = manager
+            .listeners
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["ECHO", "REVERSE"]);
+
+        let mut echo = mock_channel();
+        let mut reverse = mock_channel();
+        let echo_callback = open_channel(manager.listeners[0].1, &mut echo);
+        let reverse_callback = open_channel(manager.listeners[1].1, &mut reverse);
+
+        assert_eq!(send(echo_callback, b"abc"), CHANNEL_RC_OK);
+        assert_eq!(send(reverse_callback, b"abc"), CHANNEL_RC_OK);
+        assert_eq!(*echo.written.borrow(), [b"abc".to_vec()]);
+        assert_eq!(*reverse.written.borrow(), [b"cba".to_vec()]);
+
+        close(echo_callback);
+        close(reverse_callback);
+        unsafe { terminated(plugin) };
+    }
+
+    #[test]
+    fn no_write_after_close() {
+        let kept = Arc::new(Mutex::new(None));
+        let plugin = EchoDvcPlugin::create(vec![ListenerConfig::new("KEEP", {
+            let kept = kept.clone();
+            move || Box::new(Keeper(kept.clone()))
+        })]);
+        let mut manager = mock_channel_manager();
+        unsafe { initialize(plugin, &raw mut manager.iface) };
+
+        let mut channel = mock_channel();
+        let callback = open_channel(manager.listeners[0].1, &mut channel);
+        let handle = kept.lock().unwrap().clone().unwrap();
+        handle.write(b"open").unwrap();
+
+        close(callback);
+        let err = handle.write(b"closed").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        assert_eq!(*channel.written.borrow(), [b"open".to_vec()]);
+
+        unsafe { terminated(plugin) };
+    }
+
+    #[test]
+    fn write_on_open() {
+        let plugin =
+            EchoDvcPlugin::create(vec![ListenerConfig::new("GREET", || Box::new(Greeter))]);
+        let mut manager = mock_channel_manager();
+        unsafe { initialize(plugin, &raw mut manager.iface) };
+
+        let mut channel = mock_channel();
+        let callback = accept_channel(manager.listeners[0].1, &mut channel);
+        assert!(channel.written.borrow().is_empty());
+
+        let on_open = unsafe { (*callback).OnOpen.unwrap() };
+        assert_eq!(unsafe { on_open(callback) }, CHANNEL_RC_OK);
+        assert_eq!(*channel.written.borrow(), [b"hello".to_vec()]);
+
+        close(callback);
+        unsafe { terminated(plugin) };
+    }
+}
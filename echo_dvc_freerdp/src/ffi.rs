@@ -0,0 +1,170 @@
+//! Mirror of the FreeRDP dynamic channel API (`freerdp/dvc.h`).
+//!
+//! Only the members used by the plugin are given precise types, but every
+//! struct keeps the exact field order of its C counterpart.
+
+#![allow(non_snake_case, non_camel_case_types, clippy::upper_case_acronyms)]
+
+use std::ffi::{c_char, c_void};
+
+pub type UINT = u32;
+pub type ULONG = u32;
+pub type DWORD = u32;
+pub type BOOL = i32;
+
+pub const TRUE: BOOL = 1;
+
+pub const CHANNEL_RC_OK: UINT = 0;
+pub const CHANNEL_RC_ALREADY_INITIALIZED: UINT = 4;
+pub const ERROR_INVALID_PARAMETER: UINT = 87;
+pub const ERROR_INTERNAL_ERROR: UINT = 1359;
+
+/// winpr `wStream`. Data left to read is `pointer..buffer + length`.
+#[repr(C)]
+pub struct wStream {
+    pub buffer: *mut u8,
+    pub pointer: *mut u8,
+    pub length: usize,
+    pub capacity: usize,
+    pub count: DWORD,
+    pub pool: *mut c_void,
+    pub isAllocatedStream: BOOL,
+    pub isOwner: BOOL,
+}
+
+impl wStream {
+    /// Bytes between the current position and the end of the stream.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` and `pointer` must describe a valid stream.
+    pub unsafe fn remaining(&self) -> &[u8] {
+        if self.buffer.is_null() || self.pointer.is_null() {
+            return &[];
+        }
+
+        let position = unsafe { self.pointer.offset_from(self.buffer) };
+        match usize::try_from(position) {
+            Ok(position) if position < self.length => unsafe {
+                std::slice::from_raw_parts(self.pointer, self.length - position)
+            },
+            _ => &[],
+        }
+    }
+}
+
+#[repr(C)]
+pub struct IWTSListener {
+    pub GetConfiguration: Option<
+        unsafe extern "C" fn(pListener: *mut IWTSListener, ppPropertyBag: *mut *mut c_void) -> UINT,
+    >,
+    pub pInterface: *mut c_void,
+}
+
+#[repr(C)]
+pub struct IWTSVirtualChannel {
+    pub Write: Option<
+        unsafe extern "C" fn(
+            pChannel: *mut IWTSVirtualChannel,
+            cbSize: ULONG,
+            pBuffer: *const u8,
+            pReserved: *mut c_void,
+        ) -> UINT,
+    >,
+    pub Close: Option<unsafe extern "C" fn(pChannel: *mut IWTSVirtualChannel) -> UINT>,
+}
+
+#[repr(C)]
+pub struct IWTSVirtualChannelManager {
+    pub CreateListener: Option<
+        unsafe extern "C" fn(
+            pChannelMgr: *mut IWTSVirtualChannelManager,
+            pszChannelName: *const c_char,
+            ulFlags: ULONG,
+            pListenerCallback: *mut IWTSListenerCallback,
+            ppListener: *mut *mut IWTSListener,
+        ) -> UINT,
+    >,
+    pub GetChannelId: Option<unsafe extern "C" fn(channel: *mut IWTSVirtualChannel) -> u32>,
+    pub FindChannelById: Option<
+        unsafe extern "C" fn(
+            pChannelMgr: *mut IWTSVirtualChannelManager,
+            ChannelId: u32,
+        ) -> *mut IWTSVirtualChannel,
+    >,
+    pub GetChannelName:
+        Option<unsafe extern "C" fn(channel: *mut IWTSVirtualChannel) -> *const c_char>,
+    pub DestroyListener: Option<
+        unsafe extern "C" fn(
+            pChannelMgr: *mut IWTSVirtualChannelManager,
+            ppListener: *mut IWTSListener,
+        ) -> UINT,
+    >,
+}
+
+#[repr(C)]
+pub struct IWTSPlugin {
+    pub Initialize: Option<
+        unsafe extern "C" fn(
+            pPlugin: *mut IWTSPlugin,
+            pChannelMgr: *mut IWTSVirtualChannelManager,
+        ) -> UINT,
+    >,
+    pub Connected: Option<unsafe extern "C" fn(pPlugin: *mut IWTSPlugin) -> UINT>,
+    pub Disconnected:
+        Option<unsafe extern "C" fn(pPlugin: *mut IWTSPlugin, dwDisconnectCode: DWORD) -> UINT>,
+    pub Terminated: Option<unsafe extern "C" fn(pPlugin: *mut IWTSPlugin) -> UINT>,
+    pub Attached: Option<unsafe extern "C" fn(pPlugin: *mut IWTSPlugin) -> UINT>,
+    pub Detached: Option<unsafe extern "C" fn(pPlugin: *mut IWTSPlugin) -> UINT>,
+    pub pInterface: *mut c_void,
+}
+
+#[repr(C)]
+pub struct IWTSListenerCallback {
+    pub OnNewChannelConnection: Option<
+        unsafe extern "C" fn(
+            pListenerCallback: *mut IWTSListenerCallback,
+            pChannel: *mut IWTSVirtualChannel,
+            Data: *mut u8,
+            pbAccept: *mut BOOL,
+            ppCallback: *mut *mut IWTSVirtualChannelCallback,
+        ) -> UINT,
+    >,
+}
+
+#[repr(C)]
+pub struct IWTSVirtualChannelCallback {
+    pub OnDataReceived: Option<
+        unsafe extern "C" fn(
+            pChannelCallback: *mut IWTSVirtualChannelCallback,
+            data: *mut wStream,
+        ) -> UINT,
+    >,
+    pub OnOpen:
+        Option<unsafe extern "C" fn(pChannelCallback: *mut IWTSVirtualChannelCallback) -> UINT>,
+    pub OnClose:
+        Option<unsafe extern "C" fn(pChannelCallback: *mut IWTSVirtualChannelCallback) -> UINT>,
+}
+
+#[repr(C)]
+pub struct IDRDYNVC_ENTRY_POINTS {
+    pub RegisterPlugin: Option<
+        unsafe extern "C" fn(
+            pEntryPoints: *mut IDRDYNVC_ENTRY_POINTS,
+            name: *const c_char,
+            pPlugin: *mut IWTSPlugin,
+        ) -> UINT,
+    >,
+    pub GetPlugin: Option<
+        unsafe extern "C" fn(
+            pEntryPoints: *mut IDRDYNVC_ENTRY_POINTS,
+            name: *const c_char,
+        ) -> *mut IWTSPlugin,
+    >,
+    pub GetPluginData:
+        Option<unsafe extern "C" fn(pEntryPoints: *mut IDRDYNVC_ENTRY_POINTS) -> *const c_void>,
+    pub GetRdpSettings:
+        Option<unsafe extern "C" fn(pEntryPoints: *mut IDRDYNVC_ENTRY_POINTS) -> *mut c_void>,
+    pub GetRdpContext:
+        Option<unsafe extern "C" fn(pEntryPoints: *mut IDRDYNVC_ENTRY_POINTS) -> *mut c_void>,
+}
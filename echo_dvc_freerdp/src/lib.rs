@@ -0,0 +1,61 @@
+mod config;
+mod echo_plugin;
+mod ffi;
+mod logs;
+
+use std::ffi::CString;
+
+use echo_plugin::EchoDvcPlugin;
+use ffi::{
+    CHANNEL_RC_ALREADY_INITIALIZED, CHANNEL_RC_OK, ERROR_INTERNAL_ERROR, ERROR_INVALID_PARAMETER,
+    IDRDYNVC_ENTRY_POINTS, UINT,
+};
+use log::{debug, error, info};
+
+/// Name under which the plugin registers itself to FreeRDP.
+const PLUGIN_NAME: &str = "echochn";
+
+/// Entry point looked up by FreeRDP when loading the dynamic channel add-in.
+///
+/// # Safety
+///
+/// `p_entry_points` must be null or point to valid entry points, as provided
+/// by FreeRDP.
+#[unsafe(no_mangle)]
+#[allow(non_snake_case)]
+pub unsafe extern "C" fn DVCPluginEntry(p_entry_points: *mut IDRDYNVC_ENTRY_POINTS) -> UINT {
+    let config = crate::config::load();
+
+    info!("CALLED DVCPluginEntry");
+
+    let Some(entry_points) = (unsafe { p_entry_points.as_ref() }) else {
+        error!("entry points are null");
+        return ERROR_INVALID_PARAMETER;
+    };
+
+    let (Some(get_plugin), Some(register_plugin)) =
+        (entry_points.GetPlugin, entry_points.RegisterPlugin)
+    else {
+        error!("missing entry points");
+        return ERROR_INTERNAL_ERROR;
+    };
+
+    let name = CString::new(PLUGIN_NAME).unwrap();
+    if !unsafe { get_plugin(p_entry_points, name.as_ptr()) }.is_null() {
+        debug!("plugin {PLUGIN_NAME} already registered");
+        return CHANNEL_RC_ALREADY_INITIALIZED;
+    }
+
+    let plugin = EchoDvcPlugin::create(config.listeners.clone());
+    let ret = unsafe { register_plugin(p_entry_points, name.as_ptr(), plugin) };
+    if ret != CHANNEL_RC_OK {
+        error!("failed to register plugin: {ret}");
+        if let Some(terminated) = unsafe { (*plugin).Terminated } {
+            unsafe { terminated(plugin) };
+        }
+        return ret;
+    }
+
+    info!("ECHODVC plugin registered");
+    CHANNEL_RC_OK
+}
@@ -0,0 +1,7 @@
+use simplelog::{ColorChoice, ConfigBuilder, LevelFilter, TermLogger, TerminalMode};
+
+pub fn init_logs(log_level: LevelFilter) {
+    let config = ConfigBuilder::new().set_time_format_rfc2822().build();
+
+    let _ = TermLogger::init(log_level, config, TerminalMode::Mixed, ColorChoice::Auto);
+}
@@ -35,7 +35,8 @@ impl IClassFactory_Impl for EchoDVCClassFactory_Impl {
         match iid {
             IWTSPlugin::IID => {
                 debug!("IWTSPlugin request");
-                let plugin: IWTSPlugin = EchoDvcPlugin().into();
+                let plugin: IWTSPlugin =
+                    EchoDvcPlugin::new(crate::config::load().listeners.clone()).into();
                 *ppobject = unsafe { std::mem::transmute::<IWTSPlugin, *mut c_void>(plugin) };
             }
             _ => return Err(ws::core::Error::from(ws::Win32::Foundation::E_NOINTERFACE)),
@@ -0,0 +1,76 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use echo_dvc_proto::{Error, PluginConfig};
+use log::{error, info};
+use windows::{
+    self as ws,
+    Win32::System::LibraryLoader::{
+        GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS, GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+        GetModuleFileNameW, GetModuleHandleExW,
+    },
+};
+
+use crate::registry::rdp_config;
+
+static CONFIG: OnceLock<PluginConfig> = OnceLock::new();
+
+/// Plugin configuration, loaded on first call along with the logs.
+///
+/// A TOML file next to the DLL takes precedence over the registry values
+/// written under the AddIns key. An invalid configuration is reported and
+/// replaced by the defaults.
+pub fn load() -> &'static PluginConfig {
+    CONFIG.get_or_init(|| {
+        let (config, source) = match config_file() {
+            Some(path) => (read_config_file(&path), path.display().to_string()),
+            None => (rdp_config(), "registry".to_owned()),
+        };
+
+        let log_level = config.as_ref().map_or_else(
+            |_| PluginConfig::default().log_level,
+            |config| config.log_level,
+        );
+        crate::logs::init_logs(log_level);
+
+        config
+            .inspect(|_| info!("configuration loaded from {source}"))
+            .unwrap_or_else(|err| {
+                error!("invalid configuration in {source}, using defaults: {err}");
+                PluginConfig::default()
+            })
+    })
+}
+
+fn read_config_file(path: &Path) -> Result<PluginConfig, Error> {
+    let text = fs::read_to_string(path)?;
+    Ok(PluginConfig::from_toml(&text)?)
+}
+
+/// `<dll name>.toml` next to the DLL, if it exists.
+fn config_file() -> Option<PathBuf> {
+    let path = module_path().ok()?.with_extension("toml");
+    path.is_file().then_some(path)
+}
+
+fn module_path() -> io::Result<PathBuf> {
+    let mut module = ws::Win32::Foundation::HMODULE::default();
+    unsafe {
+        GetModuleHandleExW(
+            GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS | GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+            ws::core::PCWSTR(load as *const u16),
+            &mut module,
+        )
+    }?;
+
+    let mut buffer = [0u16; 1024];
+    let length = unsafe { GetModuleFileNameW(Some(module), &mut buffer) } as usize;
+    if length == 0 || length == buffer.len() {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(PathBuf::from(String::from_utf16_lossy(&buffer[..length])))
+}
@@ -1,20 +1,39 @@
+use std::{
+    io,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use echo_dvc_proto::{ChannelHandle, ChannelListener, ChannelWriter, ListenerConfig, OpenChannel};
 use log::{debug, error, info};
 
 use windows::Win32::System::RemoteDesktop::{
-    IWTSListenerCallback, IWTSListenerCallback_Impl, IWTSPlugin, IWTSPlugin_Impl,
+    IWTSListener, IWTSListenerCallback, IWTSListenerCallback_Impl, IWTSPlugin, IWTSPlugin_Impl,
     IWTSVirtualChannel, IWTSVirtualChannelCallback, IWTSVirtualChannelCallback_Impl,
     IWTSVirtualChannelManager,
 };
 use windows::{
     self as ws,
-    core::{ComObjectInterface, Error, GUID, PCSTR, implement},
+    core::{Error, GUID, PCSTR, implement},
 };
 
 pub const CLSID_ECHODVC_PLUGIN: GUID = GUID::from_u128(0xF5234ABFAC884D6EAA8D490DF08F194D);
-const DVC_NAME: &str = "ECHOCHN";
 
-#[implement(IWTSPlugin, IWTSListenerCallback)]
-pub struct EchoDvcPlugin();
+#[implement(IWTSPlugin)]
+pub struct EchoDvcPlugin {
+    listeners: Vec<Arc<ChannelListener>>,
+    /// Listener objects returned by the channel manager, kept alive until
+    /// the plugin is terminated.
+    registered: Mutex<Vec<IWTSListener>>,
+}
+
+impl EchoDvcPlugin {
+    pub fn new(listeners: Vec<ListenerConfig>) -> Self {
+        Self {
+            listeners: listeners.into_iter().map(ChannelListener::new).collect(),
+            registered: Mutex::new(Vec::new()),
+        }
+    }
+}
 
 impl IWTSPlugin_Impl for EchoDvcPlugin_Impl {
     fn Initialize(
@@ -22,29 +41,39 @@ impl IWTSPlugin_Impl for EchoDvcPlugin_Impl {
         p_channel_manager: ws::core::Ref<IWTSVirtualChannelManager>,
     ) -> Result<(), ws::core::Error> {
         info!("CALLED initialized");
-        debug!("DVC name is {DVC_NAME:?}");
-
-        match p_channel_manager.as_ref() {
-            None => {
-                return Err(Error::new(
-                    ws::Win32::Foundation::E_INVALIDARG,
-                    "channel manager is null",
-                ));
-            }
-            Some(channel_manager) => {
-                debug!("channel_manager ok");
-
-                let flags = 0;
-                let _ = unsafe {
-                    channel_manager.CreateListener(
-                        PCSTR(format!("{DVC_NAME}\0").as_ptr()),
-                        flags,
-                        self.as_interface_ref(),
-                    )?
-                };
-
-                info!("listener created for channel: {DVC_NAME}");
+
+        let Some(channel_manager) = p_channel_manager.as_ref() else {
+            return Err(Error::new(
+                ws::Win32::Foundation::E_INVALIDARG,
+                "channel manager is null",
+            ));
+        };
+        debug!("channel_manager ok");
+
+        let mut registered = self
+            .registered
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        for listener in &self.listeners {
+            let name = listener.name();
+            debug!("DVC name is {name:?}");
+
+            let callback: IWTSListenerCallback = EchoDvcListenerCallback {
+                listener: listener.clone(),
             }
+            .into();
+
+            let flags = 0;
+            let registered_listener = unsafe {
+                channel_manager.CreateListener(
+                    PCSTR(format!("{name}\0").as_ptr()),
+                    flags,
+                    &callback,
+                )?
+            };
+            registered.push(registered_listener);
+
+            info!("listener created for channel: {name}");
         }
 
         Ok(())
@@ -62,11 +91,30 @@ impl IWTSPlugin_Impl for EchoDvcPlugin_Impl {
 
     fn Terminated(&self) -> Result<(), ws::core::Error> {
         info!("client terminated");
+
+        for listener in &self.listeners {
+            debug!(
+                "{}: {} channel(s) still open",
+                listener.name(),
+                listener.open_channels()
+            );
+        }
+        self.registered
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+
         Ok(())
     }
 }
 
-impl IWTSListenerCallback_Impl for EchoDvcPlugin_Impl {
+/// Listener callback registered for one entry of the listener table.
+#[implement(IWTSListenerCallback)]
+struct EchoDvcListenerCallback {
+    listener: Arc<ChannelListener>,
+}
+
+impl IWTSListenerCallback_Impl for EchoDvcListenerCallback_Impl {
     fn OnNewChannelConnection(
         &self,
         channel_ref: ws::core::Ref<'_, IWTSVirtualChannel>,
@@ -74,12 +122,18 @@ impl IWTSListenerCallback_Impl for EchoDvcPlugin_Impl {
         paccept: *mut ws::core::BOOL,
         p_callback: ws::core::OutRef<'_, IWTSVirtualChannelCallback>,
     ) -> Result<(), windows_core::Error> {
-        info!("CALLED OnNewChannelConnection");
+        info!("CALLED OnNewChannelConnection on {}", self.listener.name());
 
         let channel = channel_ref
             .ok()
             .inspect_err(|err| error!("failed to get channel ref: {err}"))?;
 
+        let channel = ChannelHandle::new(ComChannelWriter(ws::core::AgileReference::new(channel)?));
+        let channel = self
+            .listener
+            .open(channel)
+            .inspect_err(|err| error!("failed to open channel handler: {err}"))?;
+
         let channel_callback: IWTSVirtualChannelCallback =
             EchoDvcChannelCallback::new(channel).into();
 
@@ -97,15 +151,27 @@ impl IWTSListenerCallback_Impl for EchoDvcPlugin_Impl {
     }
 }
 
+/// [`ChannelWriter`] backed by the COM channel object.
+struct ComChannelWriter(ws::core::AgileReference<IWTSVirtualChannel>);
+
+impl ChannelWriter for ComChannelWriter {
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        let channel = self.0.resolve()?;
+        unsafe { channel.Write(data, None) }?;
+        Ok(())
+    }
+}
+
+/// COM glue dispatching channel events to the handler of an [`OpenChannel`].
 #[implement(IWTSVirtualChannelCallback)]
 pub struct EchoDvcChannelCallback {
-    channel: ws::core::AgileReference<IWTSVirtualChannel>,
+    channel: Mutex<OpenChannel>,
 }
 
 impl EchoDvcChannelCallback {
-    fn new(channel: &IWTSVirtualChannel) -> Self {
+    fn new(channel: OpenChannel) -> Self {
         Self {
-            channel: ws::core::AgileReference::new(channel).unwrap(),
+            channel: Mutex::new(channel),
         }
     }
 }
@@ -121,17 +187,19 @@ impl IWTSVirtualChannelCallback_Impl for EchoDvcChannelCallback_Impl {
             received_buffer
         );
 
-        let to_send = received_buffer;
-        unsafe { self.channel.resolve().unwrap().Write(to_send, None) }
-            .inspect_err(|err| error!("failed to write to channel: {err}"))?;
-
-        debug!("sent: {} ({:?})", String::from_utf8_lossy(to_send), to_send);
+        let mut channel = self.channel.lock().unwrap_or_else(PoisonError::into_inner);
+        channel
+            .on_data(received_buffer)
+            .inspect_err(|err| error!("failed to handle data: {err}"))?;
 
         Ok(())
     }
 
     fn OnClose(&self) -> Result<(), ws::core::Error> {
         info!("CALLED OnClose");
+
+        let mut channel = self.channel.lock().unwrap_or_else(PoisonError::into_inner);
+        channel.close();
         Ok(())
     }
 }
@@ -1,4 +1,5 @@
 mod class_factory;
+mod config;
 mod echo_plugin;
 mod logs;
 mod registry;
@@ -17,7 +18,7 @@ pub extern "system" fn DllGetClassObject(
     riid: *const ws::core::GUID,
     ppv: ws::core::OutRef<IClassFactory>,
 ) -> ws::core::HRESULT {
-    crate::logs::init_logs(log::LevelFilter::Debug);
+    crate::config::load();
 
     info!("CALLED DllGetClassObject");
 
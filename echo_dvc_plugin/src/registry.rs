@@ -1,50 +1,99 @@
 use crate::echo_plugin::CLSID_ECHODVC_PLUGIN;
-use std::env;
+use echo_dvc_proto::{Error, PluginConfig};
+use std::{env, io};
 
 const RDP_ADDINS_PATH: &str = "Software\\Microsoft\\Terminal Server Client\\Default\\AddIns";
 
 const PLUGIN_NAME: &str = env!("CARGO_CRATE_NAME");
 const NAME_ENTRY: &str = "Name";
 const THREADING_MODEL_ENTRY: &str = "ThreadingModel";
+const LOG_LEVEL_ENTRY: &str = "LogLevel";
+const CHANNELS_KEY: &str = "Channels";
+
+/// Map an [`io::Error`] of winreg to [`Error::Registry`].
+fn registry_error(context: impl Into<String>) -> impl FnOnce(io::Error) -> Error {
+    move |source| Error::Registry {
+        context: context.into(),
+        source,
+    }
+}
 
-pub fn rdp_register() -> Result<(), String> {
+pub fn rdp_register() -> Result<(), Error> {
     let hkcu = winreg::RegKey::predef(winreg::enums::HKEY_CURRENT_USER);
 
     let (addins_key, _disp) = hkcu
         .create_subkey(RDP_ADDINS_PATH)
-        .map_err(|e| format!("failed to create addins: {e}"))?;
+        .map_err(registry_error("failed to create addins"))?;
 
     let (plugin, _disp) = addins_key
         .create_subkey(PLUGIN_NAME)
-        .map_err(|e| format!("failed to create entry: {e}"))?;
+        .map_err(registry_error("failed to create entry"))?;
 
     plugin
         .set_value(NAME_ENTRY, &format!("{{{CLSID_ECHODVC_PLUGIN:?}}}"))
-        .map_err(|e| format!("failed to set name: {e}"))?;
+        .map_err(registry_error("failed to set name"))?;
 
     Ok(())
 }
 
-pub fn rdp_unregister() -> Result<(), String> {
+pub fn rdp_unregister() -> Result<(), Error> {
     let hkcu = winreg::RegKey::predef(winreg::enums::HKEY_CURRENT_USER);
     let addins = hkcu
         .open_subkey_with_flags(RDP_ADDINS_PATH, winreg::enums::KEY_ALL_ACCESS)
-        .map_err(|err| format!("failed to open rdp addins path: {err}"))?;
+        .map_err(registry_error("failed to open rdp addins path"))?;
     addins
         .delete_subkey_all(PLUGIN_NAME)
-        .map_err(|err| format!("failed to delete plugin entry: {err}"))?;
+        .map_err(registry_error("failed to delete plugin entry"))?;
 
     Ok(())
 }
 
-pub fn com_register() -> Result<(), String> {
+/// Read the plugin configuration stored next to the AddIns entry.
+///
+/// `LogLevel` sets the log level and each subkey of `Channels` declares a
+/// listener named after it, its values being the handler settings.
+pub fn rdp_config() -> Result<PluginConfig, Error> {
+    let hkcu = winreg::RegKey::predef(winreg::enums::HKEY_CURRENT_USER);
+    let plugin = match hkcu.open_subkey(format!("{RDP_ADDINS_PATH}\\{PLUGIN_NAME}")) {
+        Ok(plugin) => plugin,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(PluginConfig::default()),
+        Err(err) => return Err(registry_error("failed to open plugin entry")(err)),
+    };
+
+    let log_level = match plugin.get_value::<String, _>(LOG_LEVEL_ENTRY) {
+        Ok(log_level) => Some(log_level),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(registry_error("failed to read log level")(err)),
+    };
+
+    let mut channels = Vec::new();
+    if let Ok(channels_key) = plugin.open_subkey(CHANNELS_KEY) {
+        for name in channels_key.enum_keys() {
+            let name = name.map_err(registry_error("failed to list channels"))?;
+            let values = channels_key
+                .open_subkey(&name)
+                .and_then(|channel| {
+                    channel
+                        .enum_values()
+                        .map(|value| value.map(|(key, value)| (key, value.to_string())))
+                        .collect::<io::Result<Vec<_>>>()
+                })
+                .map_err(registry_error(format!("failed to read channel {name}")))?;
+            channels.push((name, values));
+        }
+    }
+
+    Ok(PluginConfig::from_values(log_level.as_deref(), channels)?)
+}
+
+pub fn com_register() -> Result<(), Error> {
     let hkcr = winreg::RegKey::predef(winreg::enums::HKEY_CLASSES_ROOT);
 
     let (inproc, _disp) = hkcr
         .create_subkey(format!(
             "CLSID\\{{{CLSID_ECHODVC_PLUGIN:?}}}\\InprocServer32"
         ))
-        .map_err(|err| format!("failed to open clsid path: {err}"))?;
+        .map_err(registry_error("failed to open clsid path"))?;
 
     let mut dll = env::current_dir().unwrap();
     #[cfg(target_arch = "x86")]
@@ -55,23 +104,23 @@ pub fn com_register() -> Result<(), String> {
 
     inproc
         .set_value("", &dll_path)
-        .map_err(|err| format!("failed to set default inprocserver32 value: {err}"))?;
+        .map_err(registry_error("failed to set default inprocserver32 value"))?;
     inproc
         .set_value(THREADING_MODEL_ENTRY, &"Free")
-        .map_err(|err| format!("failed to set threading model value: {err}"))?;
+        .map_err(registry_error("failed to set threading model value"))?;
     Ok(())
 }
 
-pub fn com_unregister() -> Result<(), String> {
+pub fn com_unregister() -> Result<(), Error> {
     let hkcr = winreg::RegKey::predef(winreg::enums::HKEY_CLASSES_ROOT);
     let (clsid, _disp) = hkcr
         .create_subkey_with_flags("CLSID", winreg::enums::KEY_ALL_ACCESS)
-        .map_err(|err| format!("failed to open clsid path: {err}"))?;
+        .map_err(registry_error("failed to open clsid path"))?;
 
     let plugin_clsid_path = format!("{{{CLSID_ECHODVC_PLUGIN:?}}}");
     clsid
         .delete_subkey_all(plugin_clsid_path)
-        .map_err(|err| format!("failed to delete plugin clsid: {err}"))?;
+        .map_err(registry_error("failed to delete plugin clsid"))?;
 
     Ok(())
 }
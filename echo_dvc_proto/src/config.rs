@@ -0,0 +1,263 @@
+use std::{collections::BTreeMap, fmt};
+
+use log::LevelFilter;
+
+use crate::ListenerConfig;
+
+/// Options given to the handler of a channel, keyed by option name.
+pub type ChannelOptions = BTreeMap<String, String>;
+
+/// Settings read by the client plugins when they are loaded.
+///
+/// The same layout is used by every configuration source:
+///
+/// ```toml
+/// log_level = "info"
+///
+/// [channels.ECHOCHN]
+/// handler = "echo"
+/// ```
+///
+/// Each entry of `channels` registers one listener. Keys other than
+/// `handler` are handed to the handler as options.
+#[derive(Debug, Clone)]
+pub struct PluginConfig {
+    pub log_level: LevelFilter,
+    pub listeners: Vec<ListenerConfig>,
+}
+
+impl Default for PluginConfig {
+    fn default() -> Self {
+        Self {
+            log_level: LevelFilter::Debug,
+            listeners: ListenerConfig::defaults(),
+        }
+    }
+}
+
+impl PluginConfig {
+    /// Build the configuration from raw values, as stored in the registry.
+    ///
+    /// Missing values keep their default, and no channel at all means the
+    /// default listeners.
+    pub fn from_values<C, V>(log_level: Option<&str>, channels: C) -> Result<Self, ConfigError>
+    where
+        C: IntoIterator<Item = (String, V)>,
+        V: IntoIterator<Item = (String, String)>,
+    {
+        let mut config = Self::default();
+
+        if let Some(log_level) = log_level {
+            config.log_level = log_level
+                .trim()
+                .parse()
+                .map_err(|_| ConfigError::InvalidLogLevel(log_level.to_owned()))?;
+        }
+
+        let mut listeners: Vec<ListenerConfig> = Vec::new();
+        for (name, values) in channels {
+            if listeners.iter().any(|listener| listener.name == name) {
+                return Err(ConfigError::DuplicateChannel(name));
+            }
+            listeners.push(ListenerConfig::from_values(name, values)?);
+        }
+        if !listeners.is_empty() {
+            config.listeners = listeners;
+        }
+
+        Ok(config)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+        let table: toml::Table = text.parse().map_err(ConfigError::Toml)?;
+
+        let mut log_level = None;
+        let mut channels = Vec::new();
+        for (key, value) in &table {
+            match (key.as_str(), value) {
+                ("log_level", toml::Value::String(level)) => log_level = Some(level.as_str()),
+                ("channels", toml::Value::Table(entries)) => {
+                    for (name, values) in entries {
+                        let toml::Value::Table(values) = values else {
+                            return Err(ConfigError::InvalidValue(format!("channels.{name}")));
+                        };
+                        let values = values
+                            .iter()
+                            .map(|(key, value)| {
+                                toml_string(value)
+                                    .map(|value| (key.clone(), value))
+                                    .ok_or_else(|| {
+                                        ConfigError::InvalidValue(format!("channels.{name}.{key}"))
+                                    })
+                            })
+                            .collect::<Result<Vec<_>, _>>()?;
+                        channels.push((name.clone(), values));
+                    }
+                }
+                ("log_level" | "channels", _) => {
+                    return Err(ConfigError::InvalidValue(key.clone()));
+                }
+                _ => return Err(ConfigError::UnknownKey(key.clone())),
+            }
+        }
+
+        Self::from_values(log_level, channels)
+    }
+}
+
+/// Scalar TOML value as the string a registry value would hold.
+fn toml_string(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value.clone()),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Boolean(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Toml(toml::de::Error),
+    UnknownKey(String),
+    InvalidValue(String),
+    InvalidLogLevel(String),
+    /// Channel names are sent as C strings and cannot be empty.
+    InvalidChannelName(String),
+    DuplicateChannel(String),
+    UnknownHandler(String),
+    UnknownOption {
+        handler: String,
+        option: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Toml(err) => write!(f, "invalid TOML: {err}"),
+            ConfigError::UnknownKey(key) => write!(f, "unknown key: {key}"),
+            ConfigError::InvalidValue(key) => write!(f, "invalid value for {key}"),
+            ConfigError::InvalidLogLevel(level) => write!(f, "invalid log level: {level:?}"),
+            ConfigError::InvalidChannelName(name) => write!(f, "invalid channel name: {name:?}"),
+            ConfigError::DuplicateChannel(name) => write!(f, "channel {name} is defined twice"),
+            ConfigError::UnknownHandler(handler) => write!(f, "unknown handler: {handler}"),
+            ConfigError::UnknownOption { handler, option } => {
+                write!(f, "unknown option for handler {handler}: {option}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Toml(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DVC_NAME;
+
+    fn names(config: &PluginConfig) -> Vec<&str> {
+        config
+            .listeners
+            .iter()
+            .map(|listener| listener.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        let config = PluginConfig::from_toml("").unwrap();
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        assert_eq!(names(&config), [DVC_NAME]);
+    }
+
+    #[test]
+    fn toml_config() {
+        let config = PluginConfig::from_toml(
+            r#"
+            log_level = "warn"
+
+            [channels.ECHO1]
+            handler = "echo"
+
+            [channels.ECHO2]
+
+            [channels.MSG]
+            handler = "message"
+            connect = "localhost:22, 10.0.0.5:*"
+            listen = "127.0.0.1:*"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.log_level, LevelFilter::Warn);
+        assert_eq!(names(&config), ["ECHO1", "ECHO2", "MSG"]);
+    }
+
+    #[test]
+    fn registry_values() {
+        let channels = [
+            ("ECHOCHN".to_owned(), vec![]),
+            (
+                "OTHER".to_owned(),
+                vec![("Handler".to_owned(), "Echo".to_owned())],
+            ),
+        ];
+        let config = PluginConfig::from_values(Some("Info"), channels).unwrap();
+
+        assert_eq!(config.log_level, LevelFilter::Info);
+        assert_eq!(names(&config), ["ECHOCHN", "OTHER"]);
+    }
+
+    #[test]
+    fn invalid_configs() {
+        let err = |text| PluginConfig::from_toml(text).unwrap_err();
+
+        assert!(matches!(err("log_level ="), ConfigError::Toml(_)));
+        assert!(matches!(err("name = 'x'"), ConfigError::UnknownKey(_)));
+        assert!(matches!(err("log_level = 3"), ConfigError::InvalidValue(_)));
+        assert!(matches!(
+            err("log_level = 'loud'"),
+            ConfigError::InvalidLogLevel(_)
+        ));
+        assert!(matches!(
+            err("[channels.A]\nhandler = 'files'"),
+            ConfigError::UnknownHandler(_)
+        ));
+        assert!(matches!(
+            err("[channels.A]\nprefix = '>'"),
+            ConfigError::UnknownOption { .. }
+        ));
+        assert!(matches!(
+            err("[channels.A]\nhandler = 'message'\nprefix = '>'"),
+            ConfigError::UnknownOption { .. }
+        ));
+        assert!(matches!(
+            err("[channels.A]\nhandler = 'message'\nconnect = 'localhost'"),
+            ConfigError::InvalidValue(_)
+        ));
+        assert!(matches!(
+            err("[channels.A]\nhandler = ['echo']"),
+            ConfigError::InvalidValue(_)
+        ));
+        assert!(matches!(
+            err("[channels.\"\"]"),
+            ConfigError::InvalidChannelName(_)
+        ));
+    }
+
+    #[test]
+    fn duplicate_channel() {
+        let channels = [("A".to_owned(), Vec::new()), ("A".to_owned(), Vec::new())];
+        assert!(matches!(
+            PluginConfig::from_values(None, channels),
+            Err(ConfigError::DuplicateChannel(_))
+        ));
+    }
+}
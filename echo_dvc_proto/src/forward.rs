@@ -0,0 +1,821 @@
+//! Services of the streams opened by the server, and TCP forwarding.
+//!
+//! The target of a stream selects its service:
+//!
+//! - `echo`: everything received is sent back.
+//! - `tcp:HOST:PORT`: the client connects to `HOST:PORT` and the stream
+//!   carries the connection.
+//! - `listen:ADDRESS:PORT`: the client listens on `ADDRESS:PORT` and sends
+//!   back the address it is bound to, as a line. Each connection it accepts
+//!   is carried by a stream it opens to `connection:ID`, `ID` being the id of
+//!   the `listen` stream. Ending the `listen` stream stops listening.
+//!
+//! The client only connects and listens where its configuration allows it.
+//! It resolves the host names itself, so that the server reaches the hosts
+//! known to the network of the client.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+
+use crate::{ChannelOptions, ConfigError, Mux, MuxStream, PendingStream};
+
+/// Time between two checks for connections of a `listen` stream.
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+/// Target of a stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Echo,
+    Tcp {
+        host: String,
+        port: u16,
+    },
+    Listen {
+        host: String,
+        port: u16,
+    },
+    /// Connection accepted for the `listen` stream of this id.
+    Connection(u32),
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(target: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid target: {target}");
+        let (scheme, address) = target.split_once(':').unwrap_or((target, ""));
+        match scheme {
+            "echo" if address.is_empty() => Ok(Target::Echo),
+            "tcp" => {
+                let (host, port) = split_host_port(address).ok_or_else(invalid)?;
+                Ok(Target::Tcp { host, port })
+            }
+            "listen" => {
+                let (host, port) = split_host_port(address).ok_or_else(invalid)?;
+                Ok(Target::Listen { host, port })
+            }
+            "connection" => address
+                .parse()
+                .map(Target::Connection)
+                .map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Echo => write!(f, "echo"),
+            Target::Tcp { host, port } => write!(f, "tcp:{}:{port}", bracketed(host)),
+            Target::Listen { host, port } => write!(f, "listen:{}:{port}", bracketed(host)),
+            Target::Connection(id) => write!(f, "connection:{id}"),
+        }
+    }
+}
+
+/// `host`, with brackets when it is an IPv6 address.
+fn bracketed(host: &str) -> String {
+    match host.contains(':') {
+        true => format!("[{host}]"),
+        false => host.to_owned(),
+    }
+}
+
+/// Split `HOST:PORT`, where an IPv6 host is written between brackets.
+pub fn split_host_port(address: &str) -> Option<(String, u16)> {
+    let (host, port) = address.rsplit_once(':')?;
+    let host = match host.strip_prefix('[') {
+        Some(host) => host.strip_suffix(']')?,
+        None if host.contains(':') => return None,
+        None => host,
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_owned(), port.parse().ok()?))
+}
+
+/// `HOST:PORT` pattern of the addresses a client may reach, `*` matching any
+/// host or any port. The host may also be `*.DOMAIN` for the subdomains of
+/// `DOMAIN`, or a network such as `10.0.0.0/8` or `[fd00::/8]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressPattern {
+    host: HostPattern,
+    port: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostPattern {
+    Any,
+    /// Lowercase host name.
+    Name(String),
+    /// Lowercase domain, whose subdomains match.
+    Domain(String),
+    /// Addresses within the network, an address alone being a network of
+    /// its own.
+    Network {
+        address: IpAddr,
+        prefix: u32,
+    },
+}
+
+impl FromStr for HostPattern {
+    type Err = ();
+
+    fn from_str(host: &str) -> Result<Self, ()> {
+        if host == "*" {
+            return Ok(HostPattern::Any);
+        }
+        if let Some(domain) = host.strip_prefix("*.") {
+            return match domain.contains(['*', '/']) || domain.is_empty() {
+                true => Err(()),
+                false => Ok(HostPattern::Domain(domain.to_lowercase())),
+            };
+        }
+        if let Some((address, prefix)) = host.split_once('/') {
+            let address: IpAddr = address.parse().map_err(|_| ())?;
+            let prefix = prefix.parse().map_err(|_| ())?;
+            return match prefix <= max_prefix(address) {
+                true => Ok(HostPattern::Network { address, prefix }),
+                false => Err(()),
+            };
+        }
+        if let Ok(address) = host.parse() {
+            let prefix = max_prefix(address);
+            return Ok(HostPattern::Network { address, prefix });
+        }
+        match host.contains('*') {
+            true => Err(()),
+            false => Ok(HostPattern::Name(host.to_lowercase())),
+        }
+    }
+}
+
+impl HostPattern {
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Any => true,
+            HostPattern::Name(name) => name.eq_ignore_ascii_case(host),
+            HostPattern::Domain(domain) => host
+                .to_lowercase()
+                .strip_suffix(domain.as_str())
+                .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+            HostPattern::Network { address, prefix } => host
+                .parse()
+                .is_ok_and(|host| within(host, *address, *prefix)),
+        }
+    }
+}
+
+/// Length in bits of the addresses of the family of `address`.
+fn max_prefix(address: IpAddr) -> u32 {
+    match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Whether `address` is within the `network/prefix` network.
+fn within(address: IpAddr, network: IpAddr, prefix: u32) -> bool {
+    let (address, network, length) = match (address, network) {
+        (IpAddr::V4(address), IpAddr::V4(network)) => {
+            (address.to_bits().into(), network.to_bits().into(), 32)
+        }
+        (IpAddr::V6(address), IpAddr::V6(network)) => (address.to_bits(), network.to_bits(), 128),
+        _ => return false,
+    };
+    // The bits after the prefix do not matter.
+    (address ^ network)
+        .checked_shr(length - prefix)
+        .is_none_or(|difference: u128| difference == 0)
+}
+
+impl FromStr for AddressPattern {
+    type Err = String;
+
+    fn from_str(pattern: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid address pattern: {pattern}");
+        let pattern = pattern.trim();
+        let (host, port) = pattern.rsplit_once(':').ok_or_else(invalid)?;
+
+        let port = match port {
+            "*" => None,
+            port => Some(port.parse().map_err(|_| invalid())?),
+        };
+        let (host, _) = split_host_port(&format!("{host}:0")).ok_or_else(invalid)?;
+        let host = host.parse().map_err(|_| invalid())?;
+        Ok(Self { host, port })
+    }
+}
+
+impl AddressPattern {
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        self.host.matches(host) && self.port.is_none_or(|pattern| pattern == port)
+    }
+
+    /// Whether the pattern matches addresses rather than names.
+    fn is_network(&self) -> bool {
+        matches!(self.host, HostPattern::Network { .. })
+    }
+}
+
+/// Where the client may connect and listen for the server. Nothing is
+/// allowed by default.
+#[derive(Debug, Clone, Default)]
+pub struct ForwardPolicy {
+    pub connect: Vec<AddressPattern>,
+    pub listen: Vec<AddressPattern>,
+}
+
+impl ForwardPolicy {
+    /// Policy from the `connect` and `listen` options of the `handler`,
+    /// each a comma separated list of [`AddressPattern`]s.
+    pub fn from_options(handler: &str, options: &ChannelOptions) -> Result<Self, ConfigError> {
+        let mut policy = Self::default();
+        for (option, value) in options {
+            let patterns = match option.as_str() {
+                "connect" => &mut policy.connect,
+                "listen" => &mut policy.listen,
+                _ => {
+                    return Err(ConfigError::UnknownOption {
+                        handler: handler.to_owned(),
+                        option: option.clone(),
+                    });
+                }
+            };
+            for pattern in value
+                .split(',')
+                .filter(|pattern| !pattern.trim().is_empty())
+            {
+                let pattern = pattern
+                    .parse()
+                    .map_err(|_| ConfigError::InvalidValue(option.clone()))?;
+                patterns.push(pattern);
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn allows_connect(&self, host: &str, port: u16) -> bool {
+        self.connect
+            .iter()
+            .any(|pattern| pattern.matches(host, port))
+    }
+
+    /// Addresses of `host` the client may connect to: all of them when the
+    /// host itself is allowed, else those within the networks allowed on
+    /// `port`. Names are only resolved when they could be allowed, so that
+    /// the server cannot use the client to look up any name.
+    pub fn resolve_connect(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let allowed = self.allows_connect(host, port);
+        let networks: Vec<_> = self
+            .connect
+            .iter()
+            .filter(|pattern| pattern.is_network() && pattern.port.is_none_or(|p| p == port))
+            .collect();
+        if !allowed && networks.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let addresses = (host, port).to_socket_addrs()?;
+        Ok(addresses
+            .filter(|address| {
+                let ip = address.ip().to_string();
+                allowed || networks.iter().any(|pattern| pattern.matches(&ip, port))
+            })
+            .collect())
+    }
+
+    pub fn allows_listen(&self, host: &str, port: u16) -> bool {
+        self.listen
+            .iter()
+            .any(|pattern| pattern.matches(host, port))
+    }
+}
+
+/// Why a stream was refused. The reason given to the peer starts with the
+/// text of the refusal, so that it can tell them apart, see
+/// [`crate::StreamRefused`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    UnknownTarget,
+    /// The policy does not allow the target.
+    NotAllowed,
+    /// The host name of the target could not be resolved.
+    Unresolved,
+    ConnectFailed,
+    ListenFailed,
+}
+
+impl Refusal {
+    const ALL: [Refusal; 5] = [
+        Refusal::UnknownTarget,
+        Refusal::NotAllowed,
+        Refusal::Unresolved,
+        Refusal::ConnectFailed,
+        Refusal::ListenFailed,
+    ];
+
+    fn text(self) -> &'static str {
+        match self {
+            Refusal::UnknownTarget => "unknown target",
+            Refusal::NotAllowed => "not allowed",
+            Refusal::Unresolved => "cannot resolve",
+            Refusal::ConnectFailed => "cannot connect",
+            Refusal::ListenFailed => "cannot listen",
+        }
+    }
+
+    /// Reason to refuse a stream with, `details` following the text of the
+    /// refusal.
+    pub fn reason(self, details: impl fmt::Display) -> String {
+        format!("{}: {details}", self.text())
+    }
+
+    /// Refusal a reason was made of, `None` for other reasons.
+    pub fn of(reason: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|refusal| {
+            reason
+                .strip_prefix(refusal.text())
+                .is_some_and(|details| details.starts_with(':'))
+        })
+    }
+}
+
+/// Serve the streams opened by the server until the channel closes.
+pub(crate) fn serve_streams(mux: Mux, policy: Arc<ForwardPolicy>) {
+    while let Ok(pending) = mux.accept() {
+        let target = pending.target().to_owned();
+        if let Err(err) = serve_stream(&mux, pending, &policy) {
+            debug!("failed to answer the stream to {target}: {err}");
+        }
+    }
+}
+
+/// Accept the streams to a known and allowed target, each served by its own
+/// thread.
+fn serve_stream(mux: &Mux, pending: PendingStream, policy: &Arc<ForwardPolicy>) -> io::Result<()> {
+    let target = match pending.target().parse() {
+        Ok(target) => target,
+        Err(_) => {
+            let reason = Refusal::UnknownTarget.reason(pending.target());
+            return pending.refuse(&reason);
+        }
+    };
+
+    match target {
+        Target::Echo => {
+            let stream = pending.accept()?;
+            thread::spawn(move || echo(stream));
+            Ok(())
+        }
+        Target::Tcp { host, port } => {
+            // Resolving and connecting take time, the acceptor does not wait.
+            let policy = policy.clone();
+            thread::spawn(move || {
+                if let Err(err) = connect(pending, &policy, &host, port) {
+                    debug!("forward to {host}:{port}: {err}");
+                }
+            });
+            Ok(())
+        }
+        Target::Listen { host, port } if policy.allows_listen(&host, port) => {
+            let mux = mux.clone();
+            thread::spawn(move || {
+                if let Err(err) = listen(&mux, pending, &host, port) {
+                    debug!("listen on {host}:{port}: {err}");
+                }
+            });
+            Ok(())
+        }
+        target => pending.refuse(&Refusal::NotAllowed.reason(target)),
+    }
+}
+
+/// Send back everything received on `stream`, until the server ends it.
+fn echo(stream: MuxStream) {
+    let (mut reader, mut writer) = (&stream, &stream);
+    let ret = io::copy(&mut reader, &mut writer).and_then(|_| stream.shutdown_write());
+    if let Err(err) = ret {
+        debug!("echo stream {}: {err}", stream.id());
+    }
+}
+
+fn connect(
+    pending: PendingStream,
+    policy: &ForwardPolicy,
+    host: &str,
+    port: u16,
+) -> io::Result<()> {
+    let addresses = match policy.resolve_connect(host, port) {
+        Ok(addresses) => addresses,
+        Err(err) => return pending.refuse(&Refusal::Unresolved.reason(format!("{host}: {err}"))),
+    };
+    if addresses.is_empty() {
+        let reason = Refusal::NotAllowed.reason(pending.target());
+        return pending.refuse(&reason);
+    }
+
+    let socket = match TcpStream::connect(&addresses[..]) {
+        Ok(socket) => socket,
+        Err(err) => {
+            let reason = Refusal::ConnectFailed.reason(format!("{host}:{port}: {err}"));
+            return pending.refuse(&reason);
+        }
+    };
+    info!("forwarding stream to {host}:{port}");
+    pipe(pending.accept()?, socket)
+}
+
+fn listen(mux: &Mux, pending: PendingStream, host: &str, port: u16) -> io::Result<()> {
+    let listener = match TcpListener::bind((host, port)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            let reason = Refusal::ListenFailed.reason(format!("{host}:{port}: {err}"));
+            return pending.refuse(&reason);
+        }
+    };
+    let address = listener.local_addr()?;
+    let control = Arc::new(pending.accept()?);
+    (&*control).write_all(format!("{address}\n").as_bytes())?;
+    info!("forwarding connections to {address}");
+
+    // The server ends the stream to stop listening.
+    let stopped = Arc::new(AtomicBool::new(false));
+    thread::spawn({
+        let (control, stopped) = (control.clone(), stopped.clone());
+        move || {
+            let _ = io::copy(&mut &*control, &mut io::sink());
+            stopped.store(true, Ordering::Relaxed);
+        }
+    });
+
+    listener.set_nonblocking(true)?;
+    let target = Target::Connection(control.id()).to_string();
+    while !stopped.load(Ordering::Relaxed) {
+        let (socket, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        socket.set_nonblocking(false)?;
+
+        let (mux, target) = (mux.clone(), target.clone());
+        thread::spawn(move || match mux.open(&target) {
+            Ok(stream) => {
+                if let Err(err) = pipe(stream, socket) {
+                    debug!("connection of {peer}: {err}");
+                }
+            }
+            Err(err) => warn!("cannot forward the connection of {peer}: {err}"),
+        });
+    }
+    info!("stopped forwarding connections to {address}");
+    Ok(())
+}
+
+/// Copy between `stream` and `socket` both ways, until both are done.
+pub fn pipe(stream: MuxStream, socket: TcpStream) -> io::Result<()> {
+    thread::scope(|scope| {
+        let upload = scope.spawn(|| {
+            let ret = io::copy(&mut &socket, &mut &stream).and_then(|_| stream.shutdown_write());
+            if ret.is_err() {
+                // Stop the download too.
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+            ret
+        });
+
+        let download = io::copy(&mut &stream, &mut &socket);
+        match &download {
+            Ok(_) => {
+                let _ = socket.shutdown(Shutdown::Write);
+            }
+            // Stop the upload too.
+            Err(_) => {
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+        }
+
+        let upload = upload.join().expect("upload thread panicked");
+        download.and(upload).map(|_| ())
+    })
+}
+
+/// Read a line sent on `stream`, without its end.
+pub fn read_line(stream: &MuxStream) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    loop {
+        match (&*stream).read(&mut byte)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            _ if byte[0] == b'\n' => break,
+            _ => line.push(byte[0]),
+        }
+    }
+    String::from_utf8(line).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StreamRefused;
+    use crate::{
+        ChannelHandle, ChannelHandler, Hello, INITIAL_WINDOW, Message, MessageHandler, MessageType,
+        handler::tests::Recorder,
+        test_util::{message_mux as server_mux, tcp_echo},
+    };
+    use std::net::Ipv4Addr;
+
+    fn policy(connect: &str, listen: &str) -> ForwardPolicy {
+        let options = ChannelOptions::from([
+            ("connect".to_owned(), connect.to_owned()),
+            ("listen".to_owned(), listen.to_owned()),
+        ]);
+        ForwardPolicy::from_options("message", &options).unwrap()
+    }
+
+    fn refusal(err: &io::Error) -> Option<Refusal> {
+        let refused = err.get_ref()?.downcast_ref::<StreamRefused>()?;
+        Refusal::of(&refused.reason)
+    }
+
+    #[test]
+    fn refusals() {
+        for refusal in Refusal::ALL {
+            let reason = refusal.reason("tcp:db:5432");
+            assert_eq!(Refusal::of(&reason), Some(refusal), "{reason}");
+        }
+        assert_eq!(Refusal::of("refused"), None);
+        assert_eq!(Refusal::of("not allowedx: a"), None);
+    }
+
+    #[test]
+    fn targets() {
+        let targets = [
+            ("echo", Target::Echo),
+            (
+                "tcp:localhost:22",
+                Target::Tcp {
+                    host: "localhost".to_owned(),
+                    port: 22,
+                },
+            ),
+            (
+                "listen:[::1]:8080",
+                Target::Listen {
+                    host: "::1".to_owned(),
+                    port: 8080,
+                },
+            ),
+            ("connection:3", Target::Connection(3)),
+        ];
+        for (text, target) in targets {
+            assert_eq!(text.parse::<Target>().unwrap(), target);
+            assert_eq!(target.to_string(), text);
+        }
+
+        for invalid in ["", "echo:x", "tcp:host", "tcp::22", "tcp:::1:22", "udp:a:1"] {
+            assert!(invalid.parse::<Target>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn address_patterns() {
+        let pattern: AddressPattern = "LocalHost:22".parse().unwrap();
+        assert!(pattern.matches("localhost", 22));
+        assert!(!pattern.matches("localhost", 23));
+        assert!(!pattern.matches("127.0.0.1", 22));
+
+        let any_port: AddressPattern = "[::1]:*".parse().unwrap();
+        assert!(any_port.matches("::1", 1));
+        let any_host: AddressPattern = "*:443".parse().unwrap();
+        assert!(any_host.matches("example.com", 443));
+        assert!(!any_host.matches("example.com", 80));
+
+        let domain: AddressPattern = "*.Example.com:*".parse().unwrap();
+        assert!(domain.matches("www.example.COM", 80));
+        assert!(domain.matches("a.b.example.com", 80));
+        assert!(!domain.matches("example.com", 80));
+        assert!(!domain.matches("badexample.com", 80));
+
+        let network: AddressPattern = "10.0.0.0/8:*".parse().unwrap();
+        assert!(network.matches("10.1.2.3", 22));
+        assert!(!network.matches("11.0.0.1", 22));
+        assert!(!network.matches("10.example.com", 22));
+        let address: AddressPattern = "127.0.0.1:*".parse().unwrap();
+        assert!(address.matches("127.0.0.1", 22));
+        assert!(!address.matches("127.0.0.2", 22));
+        let v6: AddressPattern = "[fd00::/8]:443".parse().unwrap();
+        assert!(v6.matches("fd12::1", 443));
+        assert!(!v6.matches("fe80::1", 443));
+        assert!(!v6.matches("10.0.0.1", 443));
+        let everything: AddressPattern = "0.0.0.0/0:*".parse().unwrap();
+        assert!(everything.matches("192.0.2.1", 1));
+
+        for invalid in [
+            "",
+            "localhost",
+            "host:port",
+            "::1:22",
+            "10.0.0.0/33:*",
+            "[::1/129]:*",
+            "host/8:*",
+            "*.:1",
+            "a*b:1",
+        ] {
+            assert!(invalid.parse::<AddressPattern>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn policies() {
+        let policy = policy(" 127.0.0.1:*, db:5432", "");
+        assert!(policy.allows_connect("127.0.0.1", 8080));
+        assert!(policy.allows_connect("db", 5432));
+        assert!(!policy.allows_connect("db", 22));
+        assert!(!policy.allows_listen("127.0.0.1", 8080));
+        assert!(!ForwardPolicy::default().allows_connect("127.0.0.1", 8080));
+
+        // Names are not resolved when no network could allow them, on any
+        // port or on this one.
+        let names = ForwardPolicy {
+            connect: vec![
+                "db:5432".parse().unwrap(),
+                "10.0.0.0/8:443".parse().unwrap(),
+            ],
+            ..ForwardPolicy::default()
+        };
+        assert!(
+            names
+                .resolve_connect("host.invalid", 22)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(names.resolve_connect("host.invalid", 443).is_err());
+
+        let options = ChannelOptions::from([("connect".to_owned(), "nope".to_owned())]);
+        let err = ForwardPolicy::from_options("message", &options).unwrap_err();
+        assert_eq!(err.to_string(), "invalid value for connect");
+        let options = ChannelOptions::from([("other".to_owned(), "x".to_owned())]);
+        assert!(matches!(
+            ForwardPolicy::from_options("message", &options),
+            Err(ConfigError::UnknownOption { .. })
+        ));
+    }
+
+    #[test]
+    fn tcp_forward() {
+        let destination = tcp_echo();
+        let mux = server_mux(policy("127.0.0.1:*", ""));
+
+        let target = Target::Tcp {
+            host: "127.0.0.1".to_owned(),
+            port: destination.port(),
+        };
+        let mut stream = mux.open(&target.to_string()).unwrap();
+        stream.write_all(b"through the channel").unwrap();
+        stream.shutdown_write().unwrap();
+
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "through the channel");
+    }
+
+    #[test]
+    fn resolved_destinations() {
+        let policy = policy("127.0.0.0/8:*", "");
+        assert_eq!(
+            policy.resolve_connect("localhost", 80).unwrap(),
+            [SocketAddr::from((Ipv4Addr::LOCALHOST, 80))]
+        );
+        assert!(policy.resolve_connect("::1", 80).unwrap().is_empty());
+
+        // The client resolves the name, the server only knows the target.
+        let destination = tcp_echo();
+        let mux = server_mux(policy);
+        let mut stream = mux
+            .open(&format!("tcp:localhost:{}", destination.port()))
+            .unwrap();
+        stream.write_all(b"resolved").unwrap();
+        stream.shutdown_write().unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "resolved");
+    }
+
+    #[test]
+    fn forbidden_targets() {
+        let mux = server_mux(policy("127.0.0.1:22", ""));
+
+        let err = mux.open("tcp:127.0.0.1:23").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(err.to_string().ends_with("not allowed: tcp:127.0.0.1:23"));
+        assert_eq!(refusal(&err), Some(Refusal::NotAllowed));
+        assert!(mux.open("listen:127.0.0.1:0").is_err());
+    }
+
+    #[test]
+    fn unreachable_destination() {
+        // Bound then closed, so that nothing listens there.
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mux = server_mux(policy("127.0.0.1:*", ""));
+
+        let err = mux.open(&format!("tcp:127.0.0.1:{port}")).unwrap_err();
+        assert!(err.to_string().contains("cannot connect: 127.0.0.1"));
+        assert_eq!(refusal(&err), Some(Refusal::ConnectFailed));
+    }
+
+    /// Listener whose backlog is full: connecting to it blocks until the
+    /// first connection is accepted.
+    #[cfg(target_os = "linux")]
+    fn full_listener() -> (TcpListener, TcpStream) {
+        use std::os::fd::AsRawFd;
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        // SAFETY: listening again on the socket only changes its backlog.
+        assert_eq!(unsafe { libc::listen(listener.as_raw_fd(), 0) }, 0);
+        let queued = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (listener, queued)
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn channel_closed_during_connect() {
+        let (listener, _queued) = full_listener();
+        let recorder = Arc::new(Recorder::default());
+        let channel = ChannelHandle::new(recorder.clone());
+        let mut handler = MessageHandler::new(policy("127.0.0.1:*", ""));
+        let hello = Message::new(MessageType::Hello, 1, Hello::default().encode());
+        handler.on_data(&channel, &hello.encode().unwrap()).unwrap();
+
+        let mut open = INITIAL_WINDOW.to_le_bytes().to_vec();
+        let address = listener.local_addr().unwrap();
+        open.extend_from_slice(format!("tcp:{address}").as_bytes());
+        let open = Message::new(MessageType::StreamOpen, 1, open);
+        handler.on_data(&channel, &open.encode().unwrap()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        handler.on_close();
+        let written = recorder.written();
+
+        // Let the connect through, the stream is answered once it returns.
+        listener.accept().unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        // Dropped by the client, as the stream cannot be answered.
+        assert_eq!((&socket).read(&mut [0; 1]).unwrap(), 0);
+        assert_eq!(recorder.written(), written);
+    }
+
+    #[test]
+    fn reverse_forward() {
+        let mux = server_mux(policy("", "127.0.0.1:*"));
+
+        let control = mux.open("listen:127.0.0.1:0").unwrap();
+        let address = read_line(&control).unwrap();
+        let mut client = TcpStream::connect(&address).unwrap();
+        client.write_all(b"from the client machine").unwrap();
+
+        let pending = mux.accept().unwrap();
+        assert_eq!(
+            pending.target(),
+            Target::Connection(control.id()).to_string()
+        );
+        let mut stream = pending.accept().unwrap();
+        let mut received = [0; 23];
+        stream.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"from the client machine");
+
+        stream.write_all(b"reply").unwrap();
+        stream.shutdown_write().unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "reply");
+        assert_eq!(stream.read(&mut received).unwrap(), 0);
+
+        // Ending the control stream stops listening.
+        drop(control);
+        thread::sleep(4 * ACCEPT_POLL);
+        assert!(TcpStream::connect(&address).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use std::iter::FusedIterator;
+
+use crate::{CHANNEL_CHUNK_LENGTH, ChannelFlags, ChannelPduHeader, Error, PDU_HEADER_LENGTH};
+
+/// Split a message into the chunks sent on a virtual channel, the write side
+/// counterpart of [`Reassembler`](crate::Reassembler).
+///
+/// Every chunk but the last one carries `CHANNEL_CHUNK_LENGTH` bytes and all
+/// headers hold the total length of the message. An empty message still
+/// produces a single `ONLY` chunk.
+#[derive(Debug, Clone)]
+pub struct Fragmenter<'a> {
+    data: &'a [u8],
+    length: u32,
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Fragmenter<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, Error> {
+        let length = u32::try_from(data.len()).map_err(|_| Error::MessageTooLarge(data.len()))?;
+        Ok(Self {
+            data,
+            length,
+            offset: 0,
+            done: false,
+        })
+    }
+
+    /// Serialize every chunk of the message, headers included, into one buffer.
+    pub fn encode(self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(self.data.len() + self.len() * PDU_HEADER_LENGTH);
+        for (header, payload) in self {
+            frame.extend_from_slice(&header.encode());
+            frame.extend_from_slice(payload);
+        }
+        frame
+    }
+}
+
+impl<'a> Iterator for Fragmenter<'a> {
+    type Item = (ChannelPduHeader, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut flags = match self.offset {
+            0 => ChannelFlags::FIRST,
+            _ => ChannelFlags::MIDDLE,
+        };
+        let end = self.data.len().min(self.offset + CHANNEL_CHUNK_LENGTH);
+        if end == self.data.len() {
+            flags |= ChannelFlags::LAST;
+            self.done = true;
+        }
+
+        let payload = &self.data[self.offset..end];
+        self.offset = end;
+        Some((ChannelPduHeader::new(self.length, flags), payload))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let count = match self.done {
+            true => 0,
+            false => (self.data.len() - self.offset)
+                .div_ceil(CHANNEL_CHUNK_LENGTH)
+                .max(1),
+        };
+        (count, Some(count))
+    }
+}
+
+impl ExactSizeIterator for Fragmenter<'_> {}
+
+impl FusedIterator for Fragmenter<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Reassembler, encode_chunk};
+    use proptest::prelude::*;
+
+    fn flags(data: &[u8]) -> Vec<ChannelFlags> {
+        Fragmenter::new(data)
+            .unwrap()
+            .map(|(header, _)| header.flags)
+            .collect()
+    }
+
+    #[test]
+    fn chunk_flags() {
+        assert_eq!(flags(b""), [ChannelFlags::ONLY]);
+        assert_eq!(flags(&[0; CHANNEL_CHUNK_LENGTH]), [ChannelFlags::ONLY]);
+        assert_eq!(
+            flags(&[0; CHANNEL_CHUNK_LENGTH + 1]),
+            [ChannelFlags::FIRST, ChannelFlags::LAST]
+        );
+        assert_eq!(
+            flags(&[0; CHANNEL_CHUNK_LENGTH * 3]),
+            [
+                ChannelFlags::FIRST,
+                ChannelFlags::MIDDLE,
+                ChannelFlags::LAST
+            ]
+        );
+    }
+
+    #[test]
+    fn encode_layout() {
+        let data = vec![0xAA; CHANNEL_CHUNK_LENGTH + 1];
+        let frame = Fragmenter::new(&data).unwrap().encode();
+
+        assert_eq!(frame.len(), data.len() + 2 * PDU_HEADER_LENGTH);
+        let last = ChannelPduHeader::decode(&frame[PDU_HEADER_LENGTH + CHANNEL_CHUNK_LENGTH..]);
+        assert_eq!(
+            last.unwrap(),
+            ChannelPduHeader::new(data.len() as u32, ChannelFlags::LAST)
+        );
+    }
+
+    /// Sizes within a few bytes of a multiple of `CHANNEL_CHUNK_LENGTH`.
+    fn around_chunk_boundaries() -> impl Strategy<Value = usize> {
+        (0..4usize, -8..=8isize).prop_map(|(chunks, delta)| {
+            (chunks * CHANNEL_CHUNK_LENGTH).saturating_add_signed(delta)
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip_around_boundaries(
+            data in around_chunk_boundaries().prop_flat_map(|len| prop::collection::vec(any::<u8>(), len))
+        ) {
+            let mut reassembler = Reassembler::new();
+            let fragmenter = Fragmenter::new(&data).unwrap();
+            let count = fragmenter.len();
+
+            let mut messages = Vec::new();
+            for (header, payload) in fragmenter {
+                prop_assert!(payload.len() <= CHANNEL_CHUNK_LENGTH);
+                prop_assert_eq!(header.length as usize, data.len());
+                messages.extend(reassembler.push(&encode_chunk(header, payload)).unwrap());
+            }
+
+            prop_assert_eq!(count, data.len().div_ceil(CHANNEL_CHUNK_LENGTH).max(1));
+            prop_assert_eq!(messages, vec![data]);
+            prop_assert!(!reassembler.is_pending());
+        }
+
+        #[test]
+        fn roundtrip_any_size(data in prop::collection::vec(any::<u8>(), 0..CHANNEL_CHUNK_LENGTH * 5)) {
+            let frame = Fragmenter::new(&data).unwrap().encode();
+
+            let mut reassembler = Reassembler::new();
+            let mut rest = &frame[..];
+            let mut message = None;
+            while !rest.is_empty() {
+                let header = ChannelPduHeader::decode(rest).unwrap();
+                let payload_length = match header.flags.is_last() {
+                    true => rest.len() - PDU_HEADER_LENGTH,
+                    false => CHANNEL_CHUNK_LENGTH,
+                };
+                let (chunk, next) = rest.split_at(PDU_HEADER_LENGTH + payload_length);
+                message = reassembler.push(chunk).unwrap();
+                rest = next;
+            }
+
+            prop_assert_eq!(message, Some(data));
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::{fmt, io, sync::Arc};
+
+/// Write side of a virtual channel, implemented by each client plugin glue.
+pub trait ChannelWriter: Send + Sync {
+    fn write(&self, data: &[u8]) -> io::Result<()>;
+}
+
+/// Cloneable handle used by a [`ChannelHandler`] to write back on its channel.
+#[derive(Clone)]
+pub struct ChannelHandle(Arc<dyn ChannelWriter>);
+
+impl ChannelHandle {
+    pub fn new(writer: impl ChannelWriter + 'static) -> Self {
+        Self(Arc::new(writer))
+    }
+
+    pub fn write(&self, data: &[u8]) -> io::Result<()> {
+        self.0.write(data)
+    }
+}
+
+impl fmt::Debug for ChannelHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChannelHandle").finish_non_exhaustive()
+    }
+}
+
+/// Client side behavior of a channel, free of any plugin API.
+///
+/// One handler is created per opened channel. The plugin glue calls
+/// `on_open` once the channel is open, `on_data` for every message
+/// received and `on_close` when the channel goes away.
+pub trait ChannelHandler: Send {
+    fn on_open(&mut self, _channel: &ChannelHandle) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn on_data(&mut self, channel: &ChannelHandle, data: &[u8]) -> io::Result<()>;
+
+    fn on_close(&mut self) {}
+}
+
+impl fmt::Debug for dyn ChannelHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ChannelHandler")
+    }
+}
+
+/// Send every received message back unchanged.
+#[derive(Debug, Default)]
+pub struct EchoHandler;
+
+impl ChannelHandler for EchoHandler {
+    fn on_data(&mut self, channel: &ChannelHandle, data: &[u8]) -> io::Result<()> {
+        channel.write(data)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Channel writer keeping track of everything written.
+    #[derive(Default)]
+    pub(crate) struct Recorder(Mutex<Vec<Vec<u8>>>);
+
+    impl Recorder {
+        pub(crate) fn written(&self) -> Vec<Vec<u8>> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl ChannelWriter for Arc<Recorder> {
+        fn write(&self, data: &[u8]) -> io::Result<()> {
+            self.0.lock().unwrap().push(data.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn echo_handler() {
+        let recorder = Arc::new(Recorder::default());
+        let channel = ChannelHandle::new(recorder.clone());
+        let mut handler = EchoHandler;
+
+        handler.on_open(&channel).unwrap();
+        handler.on_data(&channel, b"hello").unwrap();
+        handler.on_data(&channel, b"\0\x01").unwrap();
+        handler.on_close();
+
+        assert_eq!(recorder.written(), [b"hello".to_vec(), b"\0\x01".to_vec()]);
+    }
+}
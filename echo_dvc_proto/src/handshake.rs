@@ -0,0 +1,215 @@
+//! Handshake of the message layer.
+//!
+//! Once the channel is open, the server sends a `Hello` announcing its
+//! protocol version, the largest message it accepts and its features. The
+//! client answers with a `HelloAck` holding its own, or with an `Error` when
+//! it cannot talk to the server. Both sides then use the smallest of the
+//! message sizes and the features they have in common.
+
+use std::fmt;
+
+use bitflags::bitflags;
+
+use crate::{Error, MAX_MESSAGE_SIZE, PROTOCOL_VERSION};
+
+/// Length of the `Hello` and `HelloAck` payloads. Longer payloads are
+/// accepted, the trailing bytes being left for later versions.
+pub const HELLO_LENGTH: usize = 9;
+
+bitflags! {
+    /// Features of the message layer a peer supports.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Features: u32 {
+        /// Requests are answered with their payload.
+        const ECHO = 0x0000_0001;
+        /// Streams multiplexed over the channel, see [`crate::Mux`].
+        const MUX = 0x0000_0002;
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "none");
+        }
+        for (i, (name, _)) in self.iter_names().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", name.to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+/// Payload of the `Hello` and `HelloAck` messages: version (u8), largest
+/// message accepted (u32) and features (u32), little endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u8,
+    pub max_message_size: u32,
+    pub features: Features,
+}
+
+impl Default for Hello {
+    /// What this implementation of the message layer supports.
+    fn default() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            max_message_size: MAX_MESSAGE_SIZE,
+            features: Features::all(),
+        }
+    }
+}
+
+impl Hello {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HELLO_LENGTH);
+        buf.push(self.version);
+        buf.extend_from_slice(&self.max_message_size.to_le_bytes());
+        buf.extend_from_slice(&self.features.bits().to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < HELLO_LENGTH {
+            return Err(Error::Handshake(format!(
+                "hello too short ({} bytes)",
+                buf.len()
+            )));
+        }
+
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                buf[offset],
+                buf[offset + 1],
+                buf[offset + 2],
+                buf[offset + 3],
+            ])
+        };
+        Ok(Self {
+            version: buf[0],
+            max_message_size: u32_at(1),
+            // Features of later versions are not ours to use.
+            features: Features::from_bits_truncate(u32_at(5)),
+        })
+    }
+
+    /// Settings both sides can use, given the `peer` hello.
+    pub fn negotiate(&self, peer: &Hello) -> Result<Negotiated, Error> {
+        if peer.version != self.version {
+            return Err(Error::Handshake(format!(
+                "protocol version {} is not supported, expected {}",
+                peer.version, self.version
+            )));
+        }
+        if peer.max_message_size == 0 {
+            return Err(Error::Handshake(
+                "the peer accepts no message payload".to_string(),
+            ));
+        }
+
+        Ok(Negotiated {
+            version: self.version,
+            max_message_size: self.max_message_size.min(peer.max_message_size),
+            features: self.features & peer.features,
+        })
+    }
+}
+
+/// Outcome of the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u8,
+    /// Largest payload either side may send.
+    pub max_message_size: u32,
+    /// Features supported by both sides.
+    pub features: Features,
+}
+
+impl fmt::Display for Negotiated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "protocol version {}, messages up to {} bytes, features: {}",
+            self.version, self.max_message_size, self.features
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_layout() {
+        let hello = Hello {
+            version: 1,
+            max_message_size: 0x0102_0304,
+            features: Features::ECHO,
+        };
+        let encoded = hello.encode();
+
+        assert_eq!(encoded, [1, 4, 3, 2, 1, 1, 0, 0, 0]);
+        assert_eq!(Hello::decode(&encoded).unwrap(), hello);
+
+        // Trailing bytes and unknown features of later versions are ignored.
+        let mut later = encoded.clone();
+        later[8] = 0x80;
+        later.extend_from_slice(b"future");
+        assert_eq!(Hello::decode(&later).unwrap(), hello);
+
+        assert!(matches!(
+            Hello::decode(&encoded[..8]),
+            Err(Error::Handshake(_))
+        ));
+    }
+
+    #[test]
+    fn negotiation() {
+        let local = Hello::default();
+        let peer = Hello {
+            max_message_size: 4096,
+            features: Features::empty(),
+            ..local
+        };
+
+        let negotiated = local.negotiate(&peer).unwrap();
+        assert_eq!(negotiated.version, PROTOCOL_VERSION);
+        assert_eq!(negotiated.max_message_size, 4096);
+        assert_eq!(negotiated.features, Features::empty());
+        assert_eq!(
+            negotiated.to_string(),
+            "protocol version 1, messages up to 4096 bytes, features: none"
+        );
+        assert_eq!(peer.negotiate(&local).unwrap(), negotiated);
+        assert_eq!(
+            local.negotiate(&local).unwrap().features.to_string(),
+            "echo, mux"
+        );
+    }
+
+    #[test]
+    fn incompatible_peers() {
+        let local = Hello::default();
+
+        let newer = Hello {
+            version: PROTOCOL_VERSION + 1,
+            ..local
+        };
+        let err = local.negotiate(&newer).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "protocol version {} is not supported, expected {PROTOCOL_VERSION}",
+                PROTOCOL_VERSION + 1
+            )
+        );
+
+        let mute = Hello {
+            max_message_size: 0,
+            ..local
+        };
+        assert!(matches!(local.negotiate(&mute), Err(Error::Handshake(_))));
+    }
+}
@@ -0,0 +1,264 @@
+//! Platform independent helpers for the RDP virtual channel wire format and
+//! the channel logic shared by the client plugins.
+
+mod config;
+mod forward;
+mod fragmenter;
+mod handler;
+mod handshake;
+mod listener;
+mod message;
+mod mux;
+mod pdu;
+mod reassembler;
+#[cfg(any(test, feature = "simulator"))]
+pub mod simulator;
+mod stream;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+mod transport;
+
+use std::{fmt, io};
+
+pub use config::{ChannelOptions, ConfigError, PluginConfig};
+pub use forward::{
+    AddressPattern, ForwardPolicy, Refusal, Target, pipe, read_line, split_host_port,
+};
+pub use fragmenter::Fragmenter;
+pub use handler::{ChannelHandle, ChannelHandler, ChannelWriter, EchoHandler};
+pub use handshake::{Features, HELLO_LENGTH, Hello, Negotiated};
+pub use listener::{ChannelListener, HandlerFactory, ListenerConfig, OpenChannel};
+pub use message::{
+    MAX_MESSAGE_SIZE, MESSAGE_HEADER_LENGTH, Message, MessageDecoder, MessageHandler,
+    MessageTransport, MessageType, PROTOCOL_VERSION,
+};
+pub use mux::{INITIAL_WINDOW, Mux, MuxStream, OPEN_TIMEOUT, PendingStream, Side, StreamRefused};
+pub use pdu::{
+    CHANNEL_CHUNK_LENGTH, ChannelFlags, ChannelPduHeader, PACKET_MAX_LENGTH, PDU_HEADER_LENGTH,
+    decode_chunk, encode_chunk,
+};
+pub use reassembler::Reassembler;
+pub use stream::{ByteStream, StreamTransport};
+pub use transport::{DvcReceiver, DvcSender, DvcTransport, Loopback, Timeouts};
+
+/// Name of the echo DVC, shared by the client plugins and the server.
+pub const DVC_NAME: &str = "ECHOCHN";
+
+/// Errors of the crate, and of the plugins and server built on it.
+///
+/// Transports report [`io::Error`]s, which convert to and from this type
+/// without losing anything: converting one classifies it as a timeout, a
+/// closed channel or a framing error, which is what callers react to.
+#[derive(Debug)]
+pub enum Error {
+    /// Chunk too short to hold a CHANNEL_PDU_HEADER.
+    NotAPduHeader(usize),
+    UnsupportedFlags(u32),
+    /// Chunk does not fit where it arrived in the FIRST/MIDDLE/LAST sequence.
+    OutOfSequence(u32),
+    /// A chunk announced a total length different from the `FIRST` one.
+    LengthChanged {
+        declared: u32,
+        found: u32,
+    },
+    /// More bytes were received than the declared total length.
+    Overrun {
+        declared: u32,
+        received: usize,
+    },
+    LengthMismatch {
+        declared: u32,
+        received: usize,
+    },
+    /// Message longer than the u32 total length of a CHANNEL_PDU_HEADER, or
+    /// than the limit of the message layer.
+    MessageTooLarge(usize),
+    /// Message of a version of the message layer this one does not speak.
+    UnsupportedVersion(u8),
+    UnknownMessageType(u8),
+    /// The peers of the message layer could not agree on how to talk, or
+    /// one of them refused the other.
+    Handshake(String),
+    /// A read or write did not complete within the timeout of the channel.
+    Timeout(io::Error),
+    /// The channel was closed, by the peer or locally.
+    ChannelClosed(io::Error),
+    /// Any other I/O error.
+    Io(io::Error),
+    /// Registry key or value of the Windows plugin which could not be used.
+    Registry {
+        context: String,
+        source: io::Error,
+    },
+    Config(ConfigError),
+}
+
+impl Error {
+    /// Whether the channel cannot carry messages anymore.
+    pub fn is_closed(&self) -> bool {
+        matches!(self, Error::ChannelClosed(_))
+    }
+
+    /// Framing errors: the bytes received are not valid channel chunks or
+    /// messages.
+    pub fn is_framing(&self) -> bool {
+        matches!(
+            self,
+            Error::NotAPduHeader(_)
+                | Error::UnsupportedFlags(_)
+                | Error::OutOfSequence(_)
+                | Error::LengthChanged { .. }
+                | Error::Overrun { .. }
+                | Error::LengthMismatch { .. }
+                | Error::UnsupportedVersion(_)
+                | Error::UnknownMessageType(_)
+        )
+    }
+
+    /// Kind of the [`io::Error`] this error converts to.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::Timeout(_) => io::ErrorKind::TimedOut,
+            Error::ChannelClosed(err) | Error::Io(err) => err.kind(),
+            Error::Registry { source, .. } => source.kind(),
+            Error::MessageTooLarge(_) | Error::Config(_) => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotAPduHeader(length) => write!(f, "not a PDU header (length = {length})"),
+            Error::UnsupportedFlags(flags) => write!(f, "unsupported PDU flags: 0x{flags:x}"),
+            Error::OutOfSequence(flags) => write!(f, "unexpected chunk with flags 0x{flags:x}"),
+            Error::LengthChanged { declared, found } => write!(
+                f,
+                "inconsistent pdu_length: first chunk = {declared} - current chunk = {found}"
+            ),
+            Error::Overrun { declared, received } => write!(
+                f,
+                "message overrun: pdu_length = {declared} - read = {received}"
+            ),
+            Error::LengthMismatch { declared, received } => write!(
+                f,
+                "inconsistent length: pdu_length = {declared} - read = {received}"
+            ),
+            Error::MessageTooLarge(length) => write!(f, "message too large ({length} bytes)"),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported message layer version: {version}")
+            }
+            Error::UnknownMessageType(kind) => write!(f, "unknown message type: {kind}"),
+            Error::Handshake(reason) => write!(f, "{reason}"),
+            Error::Timeout(err) | Error::ChannelClosed(err) | Error::Io(err) => write!(f, "{err}"),
+            Error::Registry { context, source } => write!(f, "{context}: {source}"),
+            Error::Config(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Timeout(err) | Error::ChannelClosed(err) | Error::Io(err) => Some(err),
+            Error::Registry { source, .. } => Some(source),
+            Error::Config(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Timeout(err) | Error::ChannelClosed(err) | Error::Io(err) => err,
+            err => io::Error::new(err.kind(), err),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        // An `Error` converted to `io::Error` before.
+        if err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            let inner = err.into_inner().and_then(|inner| inner.downcast().ok());
+            return *inner.expect("inner error checked above");
+        }
+
+        match err.kind() {
+            io::ErrorKind::TimedOut => Error::Timeout(err),
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted => Error::ChannelClosed(err),
+            _ => Error::Io(err),
+        }
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(err: ConfigError) -> Self {
+        Error::Config(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn io_error_classification() {
+        let timeout = io::Error::new(io::ErrorKind::TimedOut, "read timed out");
+        assert!(matches!(Error::from(timeout), Error::Timeout(_)));
+
+        let eof = io::Error::new(io::ErrorKind::UnexpectedEof, "peer closed the channel");
+        assert!(Error::from(eof).is_closed());
+
+        let other = Error::from(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(matches!(other, Error::Io(_)));
+        assert_eq!(other.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn io_error_roundtrip() {
+        let err = io::Error::from(Error::LengthMismatch {
+            declared: 4,
+            received: 3,
+        });
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = Error::from(err);
+        assert!(err.is_framing());
+        assert!(matches!(
+            err,
+            Error::LengthMismatch {
+                declared: 4,
+                received: 3
+            }
+        ));
+
+        let err = io::Error::from(Error::from(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "read timed out",
+        )));
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "read timed out");
+    }
+
+    #[test]
+    fn source_chain() {
+        let err = Error::Registry {
+            context: "failed to set name".to_owned(),
+            source: io::Error::from(io::ErrorKind::PermissionDenied),
+        };
+        assert!(err.to_string().starts_with("failed to set name: "));
+        assert_eq!(
+            err.source().unwrap().to_string(),
+            io::Error::from(io::ErrorKind::PermissionDenied).to_string()
+        );
+        assert!(Error::NotAPduHeader(2).source().is_none());
+    }
+}
@@ -0,0 +1,354 @@
+use std::{
+    fmt, io,
+    sync::{
+        Arc, Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use log::debug;
+
+use crate::{
+    ChannelHandle, ChannelHandler, ChannelOptions, ChannelWriter, ConfigError, DVC_NAME, DvcSender,
+    DvcTransport, EchoHandler, Error, ForwardPolicy, MessageHandler,
+};
+
+/// Build the handler of each channel opened on a listener.
+pub type HandlerFactory = Arc<dyn Fn() -> Box<dyn ChannelHandler> + Send + Sync>;
+
+type NewHandler = fn() -> Box<dyn ChannelHandler>;
+
+/// Listeners registered by the client plugins unless configured otherwise.
+const DEFAULT_LISTENERS: &[(&str, NewHandler)] = &[(DVC_NAME, || Box::new(EchoHandler))];
+
+type BuildHandler = fn(&ChannelOptions) -> Result<HandlerFactory, ConfigError>;
+
+/// Handlers selectable by name from the plugin configuration.
+const HANDLERS: &[(&str, BuildHandler)] = &[("echo", echo_handler), ("message", message_handler)];
+
+/// Handler used when the configuration of a channel does not name one.
+const DEFAULT_HANDLER: &str = "echo";
+
+/// Refuse the options given to `handler`, which takes none.
+fn no_options(handler: &str, options: &ChannelOptions) -> Result<(), ConfigError> {
+    match options.keys().next() {
+        Some(option) => Err(ConfigError::UnknownOption {
+            handler: handler.to_owned(),
+            option: option.clone(),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn echo_handler(options: &ChannelOptions) -> Result<HandlerFactory, ConfigError> {
+    no_options("echo", options)?;
+    Ok(Arc::new(|| Box::new(EchoHandler)))
+}
+
+fn message_handler(options: &ChannelOptions) -> Result<HandlerFactory, ConfigError> {
+    let policy = ForwardPolicy::from_options("message", options)?;
+    Ok(Arc::new(move || {
+        Box::new(MessageHandler::new(policy.clone()))
+    }))
+}
+
+/// Named DVC listener and the handler serving its channels.
+#[derive(Clone)]
+pub struct ListenerConfig {
+    pub name: String,
+    pub handler: HandlerFactory,
+}
+
+impl ListenerConfig {
+    pub fn new(
+        name: impl Into<String>,
+        handler: impl Fn() -> Box<dyn ChannelHandler> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            handler: Arc::new(handler),
+        }
+    }
+
+    pub fn defaults() -> Vec<Self> {
+        DEFAULT_LISTENERS
+            .iter()
+            .map(|&(name, handler)| Self::new(name, handler))
+            .collect()
+    }
+
+    /// Build a listener from configuration values: `handler` selects the
+    /// handler by name, case insensitively, and any other value is one of its
+    /// options.
+    pub fn from_values(
+        name: impl Into<String>,
+        values: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let name = name.into();
+        if name.is_empty() || name.contains('\0') {
+            return Err(ConfigError::InvalidChannelName(name));
+        }
+
+        let mut handler = DEFAULT_HANDLER.to_owned();
+        let mut options = ChannelOptions::new();
+        for (key, value) in values {
+            if key.eq_ignore_ascii_case("handler") {
+                handler = value;
+            } else {
+                options.insert(key, value);
+            }
+        }
+
+        let Some(&(_, build)) = HANDLERS
+            .iter()
+            .find(|(kind, _)| kind.eq_ignore_ascii_case(&handler))
+        else {
+            return Err(ConfigError::UnknownHandler(handler));
+        };
+
+        Ok(Self {
+            name,
+            handler: build(&options)?,
+        })
+    }
+}
+
+impl fmt::Debug for ListenerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ListenerConfig")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Listener registered by a client plugin, tracking its open channels.
+#[derive(Debug)]
+pub struct ChannelListener {
+    config: ListenerConfig,
+    open_channels: AtomicUsize,
+}
+
+impl ChannelListener {
+    pub fn new(config: ListenerConfig) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            open_channels: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    pub fn open_channels(&self) -> usize {
+        self.open_channels.load(Ordering::Relaxed)
+    }
+
+    /// Create the handler of a newly accepted channel and call its `on_open`.
+    pub fn open(self: &Arc<Self>, channel: ChannelHandle) -> io::Result<OpenChannel> {
+        let mut channel = self.accept(channel);
+        channel.on_open()?;
+        Ok(channel)
+    }
+
+    /// Create the handler of a channel being accepted, for plugin APIs which
+    /// report the channel open later: its `on_open` is only called by
+    /// [`OpenChannel::on_open`].
+    pub fn accept(self: &Arc<Self>, channel: ChannelHandle) -> OpenChannel {
+        let count = self.open_channels.fetch_add(1, Ordering::Relaxed) + 1;
+        debug!("{}: channel accepted ({count} open)", self.name());
+
+        OpenChannel {
+            listener: self.clone(),
+            channel,
+            handler: (self.config.handler)(),
+            opened: false,
+            closed: false,
+        }
+    }
+
+    /// Serve a channel carried by `transport` the way a client plugin serves
+    /// the channels it accepts, until the peer closes it.
+    pub fn serve(self: &Arc<Self>, transport: Box<dyn DvcTransport>) -> io::Result<()> {
+        let (sender, mut receiver) = transport.split()?;
+        let writer = Arc::new(SenderWriter::new(sender));
+        let mut channel = self.open(ChannelHandle::new(writer.clone()))?;
+
+        let ret = loop {
+            let data = match receiver.recv().map_err(Error::from) {
+                Ok(data) => data,
+                Err(Error::ChannelClosed(_)) => break Ok(()),
+                Err(err) => break Err(err.into()),
+            };
+            if let Err(err) = channel.on_data(&data) {
+                break Err(err);
+            }
+        };
+
+        channel.close();
+        writer.lock().close()?;
+        ret
+    }
+}
+
+/// Sending half of a transport, as the writer of a served channel.
+pub(crate) struct SenderWriter(Mutex<Box<dyn DvcSender>>);
+
+impl SenderWriter {
+    pub(crate) fn new(sender: Box<dyn DvcSender>) -> Self {
+        Self(Mutex::new(sender))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Box<dyn DvcSender>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl ChannelWriter for Arc<SenderWriter> {
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        self.lock().send(data)
+    }
+}
+
+/// Channel accepted on a [`ChannelListener`], routing events to its handler.
+#[derive(Debug)]
+pub struct OpenChannel {
+    listener: Arc<ChannelListener>,
+    channel: ChannelHandle,
+    handler: Box<dyn ChannelHandler>,
+    /// Set once `on_open` succeeded, the handler only sees `on_close` then.
+    opened: bool,
+    closed: bool,
+}
+
+impl OpenChannel {
+    pub fn name(&self) -> &str {
+        self.listener.name()
+    }
+
+    pub fn on_open(&mut self) -> io::Result<()> {
+        self.handler.on_open(&self.channel)?;
+        self.opened = true;
+        Ok(())
+    }
+
+    pub fn on_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.handler.on_data(&self.channel, data)
+    }
+
+    pub fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        if self.opened {
+            self.handler.on_close();
+        }
+
+        let count = self.listener.open_channels.fetch_sub(1, Ordering::Relaxed) - 1;
+        debug!("{}: channel closed ({count} open)", self.name());
+    }
+}
+
+impl Drop for OpenChannel {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::tests::Recorder;
+
+    struct Reverse;
+
+    impl ChannelHandler for Reverse {
+        fn on_data(&mut self, channel: &ChannelHandle, data: &[u8]) -> io::Result<()> {
+            channel.write(&data.iter().rev().copied().collect::<Vec<_>>())
+        }
+    }
+
+    #[test]
+    fn default_listeners() {
+        let listeners = ListenerConfig::defaults();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].name, DVC_NAME);
+    }
+
+    #[test]
+    fn channels_are_routed_to_their_handler() {
+        let echo = ChannelListener::new(ListenerConfig::new("ECHO", || Box::new(EchoHandler)));
+        let reverse = ChannelListener::new(ListenerConfig::new("REVERSE", || Box::new(Reverse)));
+
+        let echo_out = Arc::new(Recorder::default());
+        let reverse_out = Arc::new(Recorder::default());
+        let mut echo_channel = echo.open(ChannelHandle::new(echo_out.clone())).unwrap();
+        let mut reverse_channel = reverse
+            .open(ChannelHandle::new(reverse_out.clone()))
+            .unwrap();
+
+        echo_channel.on_data(b"abc").unwrap();
+        reverse_channel.on_data(b"abc").unwrap();
+
+        assert_eq!(echo_out.written(), [b"abc".to_vec()]);
+        assert_eq!(reverse_out.written(), [b"cba".to_vec()]);
+    }
+
+    #[test]
+    fn open_channels_are_counted() {
+        let listener = ChannelListener::new(ListenerConfig::defaults().remove(0));
+        let recorder = Arc::new(Recorder::default());
+
+        let mut first = listener.open(ChannelHandle::new(recorder.clone())).unwrap();
+        let second = listener.open(ChannelHandle::new(recorder.clone())).unwrap();
+        assert_eq!(listener.open_channels(), 2);
+
+        first.close();
+        first.close();
+        assert_eq!(listener.open_channels(), 1);
+
+        drop(second);
+        assert_eq!(listener.open_channels(), 0);
+    }
+
+    /// Writes on open and records the events it sees.
+    struct Greeter(Arc<Mutex<Vec<&'static str>>>);
+
+    impl ChannelHandler for Greeter {
+        fn on_open(&mut self, channel: &ChannelHandle) -> io::Result<()> {
+            self.0.lock().unwrap().push("open");
+            channel.write(b"hello")
+        }
+
+        fn on_data(&mut self, _channel: &ChannelHandle, _data: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn on_close(&mut self) {
+            self.0.lock().unwrap().push("close");
+        }
+    }
+
+    #[test]
+    fn accepted_channels_open_later() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let listener = ChannelListener::new(ListenerConfig::new("GREET", {
+            let events = events.clone();
+            move || Box::new(Greeter(events.clone()))
+        }));
+        let recorder = Arc::new(Recorder::default());
+
+        let mut channel = listener.accept(ChannelHandle::new(recorder.clone()));
+        assert_eq!(listener.open_channels(), 1);
+        assert!(recorder.written().is_empty());
+        channel.on_open().unwrap();
+        assert_eq!(recorder.written(), [b"hello".to_vec()]);
+        channel.close();
+
+        // Closed before being opened: the handler sees neither.
+        drop(listener.accept(ChannelHandle::new(recorder.clone())));
+        assert_eq!(listener.open_channels(), 0);
+        assert_eq!(*events.lock().unwrap(), ["open", "close"]);
+    }
+}
@@ -0,0 +1,729 @@
+//! Optional message layer carried over the channel.
+//!
+//! Each message starts with a header holding the protocol version (u8), the
+//! message type (u8), a request id (u32) and the payload length (u32), all
+//! little endian. Responses carry the id of their request, so several
+//! requests can be in flight at once. Being length prefixed, messages do not
+//! rely on the transport to keep their boundaries.
+//!
+//! The server starts with a handshake, see [`crate::Hello`], after which
+//! both sides hold to the negotiated message size. When both support it, the
+//! channel then carries streams, see [`crate::Mux`].
+
+use std::{fmt, io, sync::Arc, thread};
+
+use log::{debug, info, warn};
+
+use crate::{
+    ChannelHandle, ChannelHandler, DvcReceiver, DvcSender, DvcTransport, Error, Features,
+    ForwardPolicy, Hello, Mux, Negotiated, Side, Timeouts, forward::serve_streams,
+    listener::SenderWriter,
+};
+
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const MESSAGE_HEADER_LENGTH: usize = 10;
+/// Largest payload accepted from the peer.
+pub const MAX_MESSAGE_SIZE: u32 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    /// Payload to send back in a `Response`.
+    Request = 1,
+    Response = 2,
+    /// Failed request, the payload holds the reason.
+    Error = 3,
+    /// Handshake of the server, the payload is a [`Hello`].
+    Hello = 4,
+    /// Answer of the client to the `Hello`, with its own.
+    HelloAck = 5,
+    /// Frames of the streams, the id being the one of the stream.
+    StreamOpen = 6,
+    StreamData = 7,
+    StreamClose = 8,
+    WindowUpdate = 9,
+}
+
+impl MessageType {
+    fn is_stream_frame(self) -> bool {
+        matches!(
+            self,
+            MessageType::StreamOpen
+                | MessageType::StreamData
+                | MessageType::StreamClose
+                | MessageType::WindowUpdate
+        )
+    }
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Error> {
+        match value {
+            1 => Ok(MessageType::Request),
+            2 => Ok(MessageType::Response),
+            3 => Ok(MessageType::Error),
+            4 => Ok(MessageType::Hello),
+            5 => Ok(MessageType::HelloAck),
+            6 => Ok(MessageType::StreamOpen),
+            7 => Ok(MessageType::StreamData),
+            8 => Ok(MessageType::StreamClose),
+            9 => Ok(MessageType::WindowUpdate),
+            value => Err(Error::UnknownMessageType(value)),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Message {
+    pub kind: MessageType,
+    pub id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(kind: MessageType, id: u32, payload: impl Into<Vec<u8>>) -> Self {
+        Self {
+            kind,
+            id,
+            payload: payload.into(),
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let length = u32::try_from(self.payload.len())
+            .map_err(|_| Error::MessageTooLarge(self.payload.len()))?;
+
+        let mut buf = Vec::with_capacity(MESSAGE_HEADER_LENGTH + self.payload.len());
+        buf.push(PROTOCOL_VERSION);
+        buf.push(self.kind as u8);
+        buf.extend_from_slice(&self.id.to_le_bytes());
+        buf.extend_from_slice(&length.to_le_bytes());
+        buf.extend_from_slice(&self.payload);
+        Ok(buf)
+    }
+}
+
+impl fmt::Debug for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Message")
+            .field("kind", &self.kind)
+            .field("id", &self.id)
+            .field("length", &self.payload.len())
+            .finish()
+    }
+}
+
+/// Cut the bytes received into messages, whatever their chunking.
+#[derive(Debug)]
+pub struct MessageDecoder {
+    buffer: Vec<u8>,
+    max_size: u32,
+}
+
+impl Default for MessageDecoder {
+    fn default() -> Self {
+        Self::new(MAX_MESSAGE_SIZE)
+    }
+}
+
+impl MessageDecoder {
+    /// Decoder refusing payloads longer than `max_size`.
+    pub fn new(max_size: u32) -> Self {
+        Self {
+            buffer: Vec::new(),
+            max_size,
+        }
+    }
+
+    pub fn set_max_size(&mut self, max_size: u32) {
+        self.max_size = max_size;
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Next complete message, `None` until all of it has been pushed. On
+    /// error, the bytes pushed so far are dropped.
+    pub fn next_message(&mut self) -> Result<Option<Message>, Error> {
+        let Some(header) = self.buffer.get(..MESSAGE_HEADER_LENGTH) else {
+            return Ok(None);
+        };
+
+        let header = parse_header(header, self.max_size).inspect_err(|_| self.buffer.clear())?;
+        let end = MESSAGE_HEADER_LENGTH + header.length as usize;
+        if self.buffer.len() < end {
+            return Ok(None);
+        }
+
+        let payload = self.buffer[MESSAGE_HEADER_LENGTH..end].to_vec();
+        self.buffer.drain(..end);
+        Ok(Some(Message::new(header.kind, header.id, payload)))
+    }
+}
+
+struct Header {
+    kind: MessageType,
+    id: u32,
+    length: u32,
+}
+
+fn parse_header(buf: &[u8], max_size: u32) -> Result<Header, Error> {
+    let u32_at = |offset: usize| {
+        u32::from_le_bytes([
+            buf[offset],
+            buf[offset + 1],
+            buf[offset + 2],
+            buf[offset + 3],
+        ])
+    };
+
+    // The handshake is read whatever the version, so that peers of different
+    // versions can still tell each other why they cannot talk.
+    let kind = MessageType::try_from(buf[1]);
+    let handshake = matches!(
+        kind,
+        Ok(MessageType::Hello | MessageType::HelloAck | MessageType::Error)
+    );
+    if buf[0] != PROTOCOL_VERSION && !handshake {
+        return Err(Error::UnsupportedVersion(buf[0]));
+    }
+    let kind = kind?;
+    let length = u32_at(6);
+    if length > max_size {
+        return Err(Error::MessageTooLarge(length as usize));
+    }
+
+    Ok(Header {
+        kind,
+        id: u32_at(2),
+        length,
+    })
+}
+
+/// Next message of the peer, receiving as much as needed.
+fn recv_message(
+    decoder: &mut MessageDecoder,
+    mut recv: impl FnMut() -> io::Result<Vec<u8>>,
+) -> io::Result<Message> {
+    loop {
+        if let Some(message) = decoder.next_message()? {
+            debug!("received {message:?}");
+            return Ok(message);
+        }
+        decoder.push(&recv()?);
+    }
+}
+
+/// Payload of the next response, an `Error` message being reported as such.
+fn recv_response(
+    decoder: &mut MessageDecoder,
+    mut recv: impl FnMut() -> io::Result<Vec<u8>>,
+) -> io::Result<Vec<u8>> {
+    loop {
+        let message = recv_message(decoder, &mut recv)?;
+        match message.kind {
+            MessageType::Response => return Ok(message.payload),
+            MessageType::Error => {
+                return Err(io::Error::other(format!(
+                    "request {} failed: {}",
+                    message.id,
+                    String::from_utf8_lossy(&message.payload)
+                )));
+            }
+            _ => debug!("ignored {message:?}"),
+        }
+    }
+}
+
+/// `message` encoded, refused when its payload is longer than `max_size`.
+fn encode_within(message: &Message, max_size: u32) -> Result<Vec<u8>, Error> {
+    if message.payload.len() > max_size as usize {
+        return Err(Error::MessageTooLarge(message.payload.len()));
+    }
+    message.encode()
+}
+
+/// Ids of the requests sent on a channel.
+#[derive(Debug, Default)]
+struct RequestIds(u32);
+
+impl RequestIds {
+    fn next(&mut self) -> u32 {
+        self.0 = self.0.wrapping_add(1);
+        self.0
+    }
+}
+
+/// [`DvcTransport`] speaking the message layer to a [`MessageHandler`].
+///
+/// Through the `DvcTransport` interface, every message sent is a request
+/// and every message received is the payload of a response. Requests can
+/// also be sent without waiting with [`MessageTransport::send_request`],
+/// and their responses matched by id with [`MessageTransport::recv_message`].
+#[derive(Debug)]
+pub struct MessageTransport<T> {
+    transport: T,
+    ids: RequestIds,
+    decoder: MessageDecoder,
+    /// Largest payload sent, the negotiated one after the handshake.
+    max_size: u32,
+}
+
+impl<T: DvcTransport> MessageTransport<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            ids: RequestIds::default(),
+            decoder: MessageDecoder::default(),
+            max_size: MAX_MESSAGE_SIZE,
+        }
+    }
+
+    /// Announce `hello` to the peer and agree on the settings of the
+    /// channel. Fails with [`Error::Handshake`] when the peer refuses, or
+    /// when it does not speak the message layer.
+    pub fn handshake(&mut self, hello: &Hello) -> Result<Negotiated, Error> {
+        let id = self.ids.next();
+        self.send_message(&Message::new(MessageType::Hello, id, hello.encode()))?;
+
+        let reply = loop {
+            let message = self.recv_message()?;
+            if message.id == id {
+                break message;
+            }
+            debug!("ignored {message:?} before the handshake");
+        };
+
+        match reply.kind {
+            MessageType::HelloAck => {
+                let negotiated = hello.negotiate(&Hello::decode(&reply.payload)?)?;
+                self.max_size = negotiated.max_message_size;
+                self.decoder.set_max_size(negotiated.max_message_size);
+                Ok(negotiated)
+            }
+            MessageType::Error => Err(Error::Handshake(format!(
+                "the peer refused the handshake: {}",
+                String::from_utf8_lossy(&reply.payload)
+            ))),
+            // The echo handler sends everything back.
+            MessageType::Hello => Err(Error::Handshake(
+                "the peer sent the hello back, is it running the message handler?".to_string(),
+            )),
+            kind => Err(Error::Handshake(format!(
+                "unexpected {kind:?} in reply to the hello"
+            ))),
+        }
+    }
+
+    /// Send `payload` as a new request, returning its id.
+    pub fn send_request(&mut self, payload: &[u8]) -> io::Result<u32> {
+        let id = self.ids.next();
+        self.send_message(&Message::new(MessageType::Request, id, payload))?;
+        Ok(id)
+    }
+
+    pub fn send_message(&mut self, message: &Message) -> io::Result<()> {
+        debug!("sending {message:?}");
+        self.transport.send(&encode_within(message, self.max_size)?)
+    }
+
+    pub fn recv_message(&mut self) -> io::Result<Message> {
+        recv_message(&mut self.decoder, || self.transport.recv())
+    }
+}
+
+impl<T: DvcTransport + 'static> MessageTransport<T> {
+    /// Streams over this transport, once the handshake negotiated them. A
+    /// thread receives the frames from then on, dropping any other message.
+    pub fn into_mux(self, negotiated: &Negotiated) -> Result<Mux, Error> {
+        if !negotiated.features.contains(Features::MUX) {
+            return Err(Error::Handshake(
+                "the peer does not support streams".to_string(),
+            ));
+        }
+
+        let MessageTransport {
+            transport,
+            mut decoder,
+            ..
+        } = self;
+        let (sender, mut receiver) = Box::new(transport).split()?;
+        let channel = ChannelHandle::new(Arc::new(SenderWriter::new(sender)));
+        let mux = Mux::new(channel, Side::Server, negotiated.max_message_size);
+
+        let reader = mux.clone();
+        thread::spawn(move || {
+            let err = loop {
+                match recv_message(&mut decoder, || receiver.recv()) {
+                    Ok(message) if message.kind.is_stream_frame() => {
+                        if let Err(err) = reader.handle(message) {
+                            break err;
+                        }
+                    }
+                    Ok(message) => debug!("ignored {message:?}"),
+                    // Reads time out when the channel has a read timeout.
+                    Err(err) if err.kind() == io::ErrorKind::TimedOut => {}
+                    Err(err) => break err,
+                }
+            };
+            debug!("streams stopped: {err}");
+            reader.close();
+        });
+        Ok(mux)
+    }
+}
+
+impl<T: DvcTransport> DvcTransport for MessageTransport<T> {
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.send_request(data).map(|_| ())
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        recv_response(&mut self.decoder, || self.transport.recv())
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.transport.close()
+    }
+
+    fn set_timeouts(&mut self, timeouts: Timeouts) -> io::Result<()> {
+        self.transport.set_timeouts(timeouts)
+    }
+
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn DvcSender>, Box<dyn DvcReceiver>)> {
+        let MessageTransport {
+            transport,
+            ids,
+            decoder,
+            max_size,
+        } = *self;
+        let (sender, receiver) = Box::new(transport).split()?;
+
+        Ok((
+            Box::new(MessageSender {
+                sender,
+                ids,
+                max_size,
+            }),
+            Box::new(MessageReceiver { receiver, decoder }),
+        ))
+    }
+}
+
+struct MessageSender {
+    sender: Box<dyn DvcSender>,
+    ids: RequestIds,
+    max_size: u32,
+}
+
+impl DvcSender for MessageSender {
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        let message = Message::new(MessageType::Request, self.ids.next(), data);
+        self.sender.send(&encode_within(&message, self.max_size)?)
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.sender.close()
+    }
+}
+
+struct MessageReceiver {
+    receiver: Box<dyn DvcReceiver>,
+    decoder: MessageDecoder,
+}
+
+impl DvcReceiver for MessageReceiver {
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        recv_response(&mut self.decoder, || self.receiver.recv())
+    }
+}
+
+/// Client side of the message layer: every request is answered with a
+/// response carrying the same id and payload, and the handshake of the
+/// server with a [`Hello`] of this side. The streams opened by the server
+/// are served according to their [`crate::Target`], connecting and
+/// listening only where the policy allows it.
+#[derive(Debug, Default)]
+pub struct MessageHandler {
+    decoder: MessageDecoder,
+    hello: Hello,
+    negotiated: Option<Negotiated>,
+    /// Streams of the channel, once negotiated.
+    mux: Option<Mux>,
+    policy: Arc<ForwardPolicy>,
+}
+
+impl MessageHandler {
+    pub fn new(policy: ForwardPolicy) -> Self {
+        Self {
+            policy: Arc::new(policy),
+            ..Self::default()
+        }
+    }
+
+    /// Settings agreed on with the server, `None` before the handshake.
+    pub fn negotiated(&self) -> Option<&Negotiated> {
+        self.negotiated.as_ref()
+    }
+
+    /// Reply to the `Hello` of the server.
+    fn handshake(&mut self, channel: &ChannelHandle, id: u32, payload: &[u8]) -> Message {
+        match Hello::decode(payload).and_then(|peer| self.hello.negotiate(&peer)) {
+            Ok(negotiated) => {
+                info!("negotiated {negotiated}");
+                self.decoder.set_max_size(negotiated.max_message_size);
+                self.negotiated = Some(negotiated);
+
+                if let Some(mux) = self.mux.take() {
+                    mux.close();
+                }
+                if negotiated.features.contains(Features::MUX) {
+                    let mux = Mux::new(channel.clone(), Side::Client, negotiated.max_message_size);
+                    let (acceptor, policy) = (mux.clone(), self.policy.clone());
+                    thread::spawn(move || serve_streams(acceptor, policy));
+                    self.mux = Some(mux);
+                }
+                Message::new(MessageType::HelloAck, id, self.hello.encode())
+            }
+            Err(err) => {
+                warn!("refused the handshake: {err}");
+                Message::new(MessageType::Error, id, err.to_string())
+            }
+        }
+    }
+}
+
+impl ChannelHandler for MessageHandler {
+    fn on_data(&mut self, channel: &ChannelHandle, data: &[u8]) -> io::Result<()> {
+        self.decoder.push(data);
+        while let Some(message) = self.decoder.next_message()? {
+            match message.kind {
+                MessageType::Request => {
+                    let response = Message::new(MessageType::Response, message.id, message.payload);
+                    channel.write(&response.encode()?)?;
+                }
+                MessageType::Hello => {
+                    let reply = self.handshake(channel, message.id, &message.payload);
+                    channel.write(&reply.encode()?)?;
+                }
+                kind if kind.is_stream_frame() => match &self.mux {
+                    Some(mux) => mux.handle(message)?,
+                    None => debug!("ignored {message:?}, streams were not negotiated"),
+                },
+                _ => debug!("ignored {message:?}"),
+            }
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self) {
+        if let Some(mux) = self.mux.take() {
+            mux.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ChannelListener, EchoHandler, Features, ListenerConfig, Loopback, handler::tests::Recorder,
+    };
+    use std::{sync::Arc, thread};
+
+    fn encoded(kind: MessageType, id: u32, payload: &[u8]) -> Vec<u8> {
+        Message::new(kind, id, payload).encode().unwrap()
+    }
+
+    /// Client plugin stand-in running a [`MessageHandler`].
+    fn message_peer() -> Loopback {
+        let (local, remote) = Loopback::pair();
+        let listener = ChannelListener::new(ListenerConfig::new("ECHOCHN", || {
+            Box::new(MessageHandler::default())
+        }));
+        thread::spawn(move || listener.serve(Box::new(remote)));
+        local
+    }
+
+    #[test]
+    fn message_layout() {
+        assert_eq!(
+            encoded(MessageType::Response, 0x0102_0304, b"hi"),
+            [1, 2, 4, 3, 2, 1, 2, 0, 0, 0, b'h', b'i']
+        );
+    }
+
+    #[test]
+    fn decoder_reassembles_and_splits() {
+        let mut stream = encoded(MessageType::Request, 1, b"first");
+        stream.extend(encoded(MessageType::Request, 2, b""));
+        stream.extend(encoded(MessageType::Error, 3, b"third"));
+
+        let mut decoder = MessageDecoder::default();
+        let mut messages = Vec::new();
+        for byte in &stream[..stream.len() - 1] {
+            decoder.push(&[*byte]);
+            while let Some(message) = decoder.next_message().unwrap() {
+                messages.push(message);
+            }
+        }
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0], Message::new(MessageType::Request, 1, b"first"));
+        assert_eq!(messages[1].payload, b"");
+
+        decoder.push(&stream[stream.len() - 1..]);
+        let last = decoder.next_message().unwrap().unwrap();
+        assert_eq!(last, Message::new(MessageType::Error, 3, b"third"));
+        assert!(decoder.next_message().unwrap().is_none());
+    }
+
+    #[test]
+    fn decoder_errors() {
+        let decode = |data: &[u8], max_size| {
+            let mut decoder = MessageDecoder::new(max_size);
+            decoder.push(data);
+            decoder.next_message()
+        };
+
+        let mut message = encoded(MessageType::Request, 1, b"abc");
+        assert!(matches!(
+            decode(&message, 2),
+            Err(Error::MessageTooLarge(3))
+        ));
+
+        message[1] = 42;
+        assert!(matches!(
+            decode(&message, 16),
+            Err(Error::UnknownMessageType(42))
+        ));
+
+        message[0] = 2;
+        assert!(matches!(
+            decode(&message, 16),
+            Err(Error::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn echo_through_the_handler() {
+        let mut transport = MessageTransport::new(message_peer());
+
+        transport.send(b"hello").unwrap();
+        assert_eq!(transport.recv().unwrap(), b"hello");
+        transport.send(b"").unwrap();
+        assert_eq!(transport.recv().unwrap(), b"");
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let mut transport = MessageTransport::new(message_peer());
+
+        let ids: Vec<u32> = (0..10u8)
+            .map(|i| transport.send_request(&[i]).unwrap())
+            .collect();
+        for (i, id) in ids.into_iter().enumerate() {
+            let response = transport.recv_message().unwrap();
+            assert_eq!(response.kind, MessageType::Response);
+            assert_eq!((response.id, response.payload), (id, vec![i as u8]));
+        }
+    }
+
+    #[test]
+    fn error_responses() {
+        let (local, mut remote) = Loopback::pair();
+        let mut transport = MessageTransport::new(local);
+
+        transport.send(b"x").unwrap();
+        let request = remote.recv().unwrap();
+        let mut decoder = MessageDecoder::default();
+        decoder.push(&request);
+        let id = decoder.next_message().unwrap().unwrap().id;
+
+        remote
+            .send(&encoded(MessageType::Error, id, b"not today"))
+            .unwrap();
+        let err = transport.recv().unwrap_err();
+        assert_eq!(err.to_string(), format!("request {id} failed: not today"));
+    }
+
+    #[test]
+    fn split_message_transport() {
+        let transport: Box<dyn DvcTransport> = Box::new(MessageTransport::new(message_peer()));
+        let (mut sender, mut receiver) = transport.split().unwrap();
+
+        sender.send(b"one").unwrap();
+        sender.send(b"two").unwrap();
+        assert_eq!(receiver.recv().unwrap(), b"one");
+        assert_eq!(receiver.recv().unwrap(), b"two");
+    }
+
+    #[test]
+    fn handshake_with_the_handler() {
+        let mut transport = MessageTransport::new(message_peer());
+        let hello = Hello {
+            max_message_size: 16,
+            ..Hello::default()
+        };
+
+        let negotiated = transport.handshake(&hello).unwrap();
+        assert_eq!(negotiated.max_message_size, 16);
+        assert_eq!(negotiated.features, Features::all());
+
+        transport.send(&[7; 16]).unwrap();
+        assert_eq!(transport.recv().unwrap(), [7; 16]);
+        let err = transport.send(&[7; 17]).unwrap_err();
+        assert!(matches!(Error::from(err), Error::MessageTooLarge(17)));
+    }
+
+    #[test]
+    fn handler_refuses_other_versions() {
+        let mut handler = MessageHandler::default();
+        let recorder = Arc::new(Recorder::default());
+        let channel = ChannelHandle::new(recorder.clone());
+        let newer = Hello {
+            version: PROTOCOL_VERSION + 1,
+            ..Hello::default()
+        };
+        let mut hello = encoded(MessageType::Hello, 1, &newer.encode());
+        hello[0] = PROTOCOL_VERSION + 1;
+
+        handler.on_data(&channel, &hello).unwrap();
+        let mut decoder = MessageDecoder::default();
+        decoder.push(&recorder.written().concat());
+        let reply = decoder.next_message().unwrap().unwrap();
+        assert_eq!((reply.kind, reply.id), (MessageType::Error, 1));
+        assert!(handler.negotiated().is_none());
+    }
+
+    #[test]
+    fn refused_handshake() {
+        let (local, mut remote) = Loopback::pair();
+        let peer = thread::spawn(move || {
+            let mut decoder = MessageDecoder::default();
+            decoder.push(&remote.recv().unwrap());
+            let hello = decoder.next_message().unwrap().unwrap();
+            let refusal = encoded(MessageType::Error, hello.id, b"go away");
+            remote.send(&refusal).unwrap();
+            remote
+        });
+
+        let mut transport = MessageTransport::new(local);
+        let err = transport.handshake(&Hello::default()).unwrap_err();
+        assert_eq!(err.to_string(), "the peer refused the handshake: go away");
+        drop(peer.join().unwrap());
+    }
+
+    #[test]
+    fn handshake_with_an_echo_peer() {
+        let (local, remote) = Loopback::pair();
+        let listener =
+            ChannelListener::new(ListenerConfig::new("ECHOCHN", || Box::new(EchoHandler)));
+        thread::spawn(move || listener.serve(Box::new(remote)));
+
+        let mut transport = MessageTransport::new(local);
+        let err = transport.handshake(&Hello::default()).unwrap_err();
+        assert!(err.to_string().contains("message handler"));
+    }
+}
@@ -0,0 +1,847 @@
+//! Streams multiplexed over the message layer.
+//!
+//! Each stream is identified by the id field of its messages: the server
+//! opens streams with odd ids and the client with even ones. A stream is
+//! opened with a `StreamOpen` naming its target, and accepted with a
+//! `WindowUpdate` or refused with a `StreamClose`.
+//!
+//! A side may only send the bytes the other granted with `WindowUpdate`s,
+//! so that a stream nobody reads does not hold up the others. A
+//! `StreamClose` without payload ends the data of its sender, the other
+//! direction going on until it ends too. With a payload, it aborts the
+//! stream and holds the reason.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    io::{self, Read, Write},
+    mem,
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use crate::{ChannelHandle, Message, MessageType};
+
+/// Bytes a side may send on a stream before the other grants more.
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+/// Time [`Mux::open`] waits for the peer to accept or refuse a stream.
+pub const OPEN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Side of the channel a [`Mux`] runs on, which decides the ids of the
+/// streams it opens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Server,
+    Client,
+}
+
+impl Side {
+    fn first_id(self) -> u32 {
+        match self {
+            Side::Server => 1,
+            Side::Client => 2,
+        }
+    }
+}
+
+/// Streams carried by a channel.
+///
+/// The frames received are handed to [`Mux::handle`], which never blocks,
+/// so that it can be called from [`crate::ChannelHandler::on_data`]. The
+/// mux is cheap to clone, every clone driving the same streams.
+#[derive(Clone)]
+pub struct Mux {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    channel: ChannelHandle,
+    side: Side,
+    /// Largest payload of a data frame.
+    max_frame: u32,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    streams: HashMap<u32, StreamState>,
+    /// Streams opened by the peer and not accepted yet, with their target.
+    incoming: VecDeque<(u32, String)>,
+    next_id: u32,
+    closed: bool,
+}
+
+#[derive(Debug, Default)]
+struct StreamState {
+    received: VecDeque<u8>,
+    /// Bytes the peer may still send.
+    window: u32,
+    /// Bytes read since the last window update.
+    consumed: u32,
+    /// Bytes this side may still send.
+    credit: u32,
+    accepted: bool,
+    /// The peer ended its data.
+    finished: bool,
+    /// This side ended its data.
+    shut_down: bool,
+    /// Reason the stream was aborted.
+    reset: Option<String>,
+}
+
+impl Mux {
+    /// Streams of a channel written through `channel`, with data frames of
+    /// up to `max_frame` bytes.
+    pub fn new(channel: ChannelHandle, side: Side, max_frame: u32) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                channel,
+                side,
+                max_frame,
+                state: Mutex::new(State {
+                    streams: HashMap::new(),
+                    incoming: VecDeque::new(),
+                    next_id: side.first_id(),
+                    closed: false,
+                }),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Open a stream to `target`, waiting up to [`OPEN_TIMEOUT`] for the
+    /// peer to accept it.
+    pub fn open(&self, target: &str) -> io::Result<MuxStream> {
+        self.open_timeout(target, OPEN_TIMEOUT)
+    }
+
+    /// Open a stream to `target`, failing with `TimedOut` when the peer
+    /// does not answer within `timeout`. The stream is then aborted.
+    pub fn open_timeout(&self, target: &str, timeout: Duration) -> io::Result<MuxStream> {
+        let id = {
+            let mut state = self.lock();
+            if state.closed {
+                return Err(closed());
+            }
+            let id = state.next_id;
+            state.next_id = id.wrapping_add(2);
+            state.streams.insert(
+                id,
+                StreamState {
+                    window: INITIAL_WINDOW,
+                    ..StreamState::default()
+                },
+            );
+            id
+        };
+
+        let mut payload = INITIAL_WINDOW.to_le_bytes().to_vec();
+        payload.extend_from_slice(target.as_bytes());
+        // Dropping the stream forgets it, whatever the outcome.
+        let stream = MuxStream {
+            mux: self.clone(),
+            id,
+        };
+        self.send(MessageType::StreamOpen, id, payload)?;
+
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        loop {
+            if state.closed {
+                return Err(closed());
+            }
+            let opened = &state.streams[&id];
+            if let Some(reason) = &opened.reset {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    StreamRefused {
+                        target: target.to_owned(),
+                        reason: reason.clone(),
+                    },
+                ));
+            }
+            if opened.accepted {
+                debug!("stream {id} opened to {target}");
+                return Ok(stream);
+            }
+            let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("stream to {target} not answered within {timeout:?}"),
+                ));
+            };
+            state = self.wait_timeout(state, left);
+        }
+    }
+
+    /// Next stream opened by the peer, to accept or refuse.
+    pub fn accept(&self) -> io::Result<PendingStream> {
+        let mut state = self.lock();
+        loop {
+            if state.closed {
+                return Err(closed());
+            }
+            while let Some((id, target)) = state.incoming.pop_front() {
+                // Aborted by the peer before it was accepted.
+                if state.streams[&id].reset.is_some() {
+                    state.streams.remove(&id);
+                    continue;
+                }
+                return Ok(PendingStream {
+                    mux: self.clone(),
+                    id,
+                    target,
+                    answered: false,
+                });
+            }
+            state = self.wait(state);
+        }
+    }
+
+    /// Take a stream frame received from the peer. Other messages are
+    /// ignored.
+    pub fn handle(&self, message: Message) -> io::Result<()> {
+        let Message { kind, id, payload } = message;
+        let mut state = self.lock();
+
+        if kind == MessageType::StreamOpen {
+            let mut target = None;
+            if let Some((window, name)) = payload.split_first_chunk::<4>()
+                && let Ok(name) = std::str::from_utf8(name)
+            {
+                target = Some((u32::from_le_bytes(*window), name.to_owned()));
+            }
+            let reason = match target {
+                None => "invalid stream open",
+                Some(_) if id == 0 || id % 2 == self.shared.side.first_id() % 2 => {
+                    "invalid stream id"
+                }
+                Some(_) if state.streams.contains_key(&id) => "stream id in use",
+                Some((window, target)) => {
+                    state.streams.insert(
+                        id,
+                        StreamState {
+                            window: INITIAL_WINDOW,
+                            credit: window,
+                            ..StreamState::default()
+                        },
+                    );
+                    state.incoming.push_back((id, target));
+                    self.shared.changed.notify_all();
+                    return Ok(());
+                }
+            };
+            drop(state);
+            return self.send(MessageType::StreamClose, id, reason.into());
+        }
+
+        let Some(stream) = state.streams.get_mut(&id) else {
+            debug!("ignored {kind:?} of unknown stream {id}");
+            return Ok(());
+        };
+        if stream.reset.is_some() {
+            return Ok(());
+        }
+
+        let reason = match kind {
+            MessageType::StreamData if payload.len() > stream.window as usize => {
+                "flow control window exceeded"
+            }
+            MessageType::StreamData if stream.finished => "data after the end of the stream",
+            MessageType::StreamData => {
+                stream.window -= payload.len() as u32;
+                stream.received.extend(payload);
+                self.shared.changed.notify_all();
+                return Ok(());
+            }
+            MessageType::WindowUpdate => match <[u8; 4]>::try_from(payload.as_slice()) {
+                Ok(increment) => {
+                    stream.credit = stream.credit.saturating_add(u32::from_le_bytes(increment));
+                    stream.accepted = true;
+                    self.shared.changed.notify_all();
+                    return Ok(());
+                }
+                Err(_) => "invalid window update",
+            },
+            MessageType::StreamClose => {
+                match payload.is_empty() {
+                    true => stream.finished = true,
+                    false => stream.reset = Some(String::from_utf8_lossy(&payload).into_owned()),
+                }
+                self.shared.changed.notify_all();
+                return Ok(());
+            }
+            kind => {
+                debug!("ignored {kind:?} on the mux");
+                return Ok(());
+            }
+        };
+
+        // Abort the stream on frames breaking the protocol.
+        debug!("stream {id} aborted: {reason}");
+        stream.reset = Some(reason.to_owned());
+        self.shared.changed.notify_all();
+        drop(state);
+        self.send(MessageType::StreamClose, id, reason.into())
+    }
+
+    /// Stop every stream, the channel being gone. Nothing is written to the
+    /// channel afterwards.
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.shared.changed.notify_all();
+    }
+
+    fn send(&self, kind: MessageType, id: u32, payload: Vec<u8>) -> io::Result<()> {
+        // The writer of a closed channel may be gone with it.
+        if self.lock().closed {
+            return Err(closed());
+        }
+        let message = Message::new(kind, id, payload);
+        self.shared.channel.write(&message.encode()?)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.shared
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn wait<'a>(&self, state: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        self.shared
+            .changed
+            .wait(state)
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn wait_timeout<'a>(
+        &self,
+        state: MutexGuard<'a, State>,
+        timeout: Duration,
+    ) -> MutexGuard<'a, State> {
+        let (state, _) = self
+            .shared
+            .changed
+            .wait_timeout(state, timeout)
+            .unwrap_or_else(PoisonError::into_inner);
+        state
+    }
+}
+
+impl fmt::Debug for Mux {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mux")
+            .field("side", &self.shared.side)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Stream the peer refused, the error of [`Mux::open`] it is refused with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamRefused {
+    pub target: String,
+    /// Reason given by the peer.
+    pub reason: String,
+}
+
+impl fmt::Display for StreamRefused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stream to {} refused: {}", self.target, self.reason)
+    }
+}
+
+impl std::error::Error for StreamRefused {}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "channel closed")
+}
+
+/// Stream opened by the peer. Dropping it refuses it.
+#[derive(Debug)]
+pub struct PendingStream {
+    mux: Mux,
+    id: u32,
+    target: String,
+    answered: bool,
+}
+
+impl PendingStream {
+    /// What the peer wants to reach, its meaning being up to the handler.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    pub fn accept(mut self) -> io::Result<MuxStream> {
+        self.answered = true;
+        let stream = MuxStream {
+            mux: self.mux.clone(),
+            id: self.id,
+        };
+        let window = INITIAL_WINDOW.to_le_bytes().to_vec();
+        self.mux.send(MessageType::WindowUpdate, self.id, window)?;
+        Ok(stream)
+    }
+
+    pub fn refuse(mut self, reason: &str) -> io::Result<()> {
+        self.answered = true;
+        self.mux.lock().streams.remove(&self.id);
+        let reason = match reason {
+            "" => "refused",
+            reason => reason,
+        };
+        self.mux
+            .send(MessageType::StreamClose, self.id, reason.into())
+    }
+}
+
+impl Drop for PendingStream {
+    fn drop(&mut self) {
+        if !self.answered {
+            self.answered = true;
+            self.mux.lock().streams.remove(&self.id);
+            let _ = self
+                .mux
+                .send(MessageType::StreamClose, self.id, b"refused".to_vec());
+        }
+    }
+}
+
+/// Stream of a [`Mux`], read and written like a socket.
+///
+/// `&MuxStream` is also `Read` and `Write`, so that one thread can read
+/// while another writes. Dropping the stream closes it, aborting it if the
+/// peer has not ended its data yet.
+pub struct MuxStream {
+    mux: Mux,
+    id: u32,
+}
+
+impl MuxStream {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// End the data of this side. The peer can still send.
+    pub fn shutdown_write(&self) -> io::Result<()> {
+        {
+            let mut state = self.mux.lock();
+            let stream = state
+                .streams
+                .get_mut(&self.id)
+                .expect("streams are forgotten on drop");
+            if stream.shut_down || stream.reset.is_some() {
+                return Ok(());
+            }
+            stream.shut_down = true;
+        }
+        self.mux.send(MessageType::StreamClose, self.id, Vec::new())
+    }
+}
+
+impl Read for &MuxStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut state = self.mux.lock();
+        loop {
+            if state.closed {
+                return Err(closed());
+            }
+            let stream = state
+                .streams
+                .get_mut(&self.id)
+                .expect("streams are forgotten on drop");
+            if let Some(reason) = &stream.reset {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    format!("stream aborted: {reason}"),
+                ));
+            }
+
+            if !stream.received.is_empty() {
+                let length = stream.received.len().min(buf.len());
+                for (byte, received) in buf.iter_mut().zip(stream.received.drain(..length)) {
+                    *byte = received;
+                }
+
+                // Grant the peer what was read once it is worth a frame.
+                stream.consumed += length as u32;
+                let mut update = None;
+                if stream.consumed >= INITIAL_WINDOW / 2 && !stream.finished {
+                    let increment = mem::take(&mut stream.consumed);
+                    stream.window += increment;
+                    update = Some(increment);
+                }
+                drop(state);
+
+                if let Some(increment) = update {
+                    let increment = increment.to_le_bytes().to_vec();
+                    self.mux
+                        .send(MessageType::WindowUpdate, self.id, increment)?;
+                }
+                return Ok(length);
+            }
+            if stream.finished {
+                return Ok(0);
+            }
+            state = self.mux.wait(state);
+        }
+    }
+}
+
+impl Write for &MuxStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut state = self.mux.lock();
+        let length = loop {
+            if state.closed {
+                return Err(closed());
+            }
+            let stream = state
+                .streams
+                .get_mut(&self.id)
+                .expect("streams are forgotten on drop");
+            if let Some(reason) = &stream.reset {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    format!("stream aborted: {reason}"),
+                ));
+            }
+            if stream.shut_down {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "stream shut down",
+                ));
+            }
+            if stream.credit > 0 {
+                let length = buf
+                    .len()
+                    .min(stream.credit as usize)
+                    .min(self.mux.shared.max_frame as usize);
+                stream.credit -= length as u32;
+                break length;
+            }
+            state = self.mux.wait(state);
+        };
+        drop(state);
+
+        self.mux
+            .send(MessageType::StreamData, self.id, buf[..length].to_vec())?;
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for MuxStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for MuxStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let mut state = self.mux.lock();
+        let Some(stream) = state.streams.remove(&self.id) else {
+            return;
+        };
+        if state.closed || stream.reset.is_some() {
+            return;
+        }
+        drop(state);
+
+        let ret = match (stream.shut_down, stream.finished) {
+            (_, false) => {
+                self.mux
+                    .send(MessageType::StreamClose, self.id, b"stream closed".to_vec())
+            }
+            (false, true) => self.mux.send(MessageType::StreamClose, self.id, Vec::new()),
+            (true, true) => Ok(()),
+        };
+        if let Err(err) = ret {
+            debug!("failed to close stream {}: {err}", self.id);
+        }
+    }
+}
+
+impl fmt::Debug for MuxStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MuxStream").field("id", &self.id).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ForwardPolicy, MessageDecoder, handler::tests::Recorder, test_util};
+    use std::{thread, time::Duration};
+
+    /// Server side mux, talking to a [`crate::MessageHandler`].
+    fn server_mux() -> Mux {
+        test_util::message_mux(ForwardPolicy::default())
+    }
+
+    /// Mux whose frames are recorded instead of sent.
+    fn recorded_mux(side: Side, max_frame: u32) -> (Mux, Arc<Recorder>) {
+        let recorder = Arc::new(Recorder::default());
+        let mux = Mux::new(ChannelHandle::new(recorder.clone()), side, max_frame);
+        (mux, recorder)
+    }
+
+    fn frames(recorder: &Recorder) -> Vec<Message> {
+        let mut decoder = MessageDecoder::default();
+        decoder.push(&recorder.written().concat());
+        std::iter::from_fn(|| decoder.next_message().unwrap()).collect()
+    }
+
+    fn frame(kind: MessageType, id: u32, payload: &[u8]) -> Message {
+        Message::new(kind, id, payload)
+    }
+
+    #[test]
+    fn echo_stream() {
+        let mux = server_mux();
+        let stream = mux.open("echo").unwrap();
+
+        // Several windows worth of data, read while it is written.
+        let data: Vec<u8> = (0..3 * INITIAL_WINDOW).map(|i| i as u8).collect();
+        let received = thread::scope(|scope| {
+            scope.spawn(|| {
+                (&stream).write_all(&data).unwrap();
+                stream.shutdown_write().unwrap();
+            });
+            let mut received = Vec::new();
+            (&stream).read_to_end(&mut received).unwrap();
+            received
+        });
+
+        assert!(received == data);
+    }
+
+    #[test]
+    fn independent_streams() {
+        let mux = server_mux();
+        let mut first = mux.open("echo").unwrap();
+        let mut second = mux.open("echo").unwrap();
+        assert_ne!(first.id(), second.id());
+
+        second.write_all(b"second").unwrap();
+        first.write_all(b"first").unwrap();
+        let mut buf = [0; 6];
+        second.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"second");
+        first.read_exact(&mut buf[..5]).unwrap();
+        assert_eq!(&buf[..5], b"first");
+
+        drop(first);
+        second.shutdown_write().unwrap();
+        assert_eq!(second.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn refused_stream() {
+        let mux = server_mux();
+
+        let err = mux.open("nowhere").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(
+            err.to_string(),
+            "stream to nowhere refused: unknown target: nowhere"
+        );
+    }
+
+    #[test]
+    fn writes_wait_for_the_window() {
+        let (mux, recorder) = recorded_mux(Side::Server, 64);
+
+        let opener = thread::spawn({
+            let mux = mux.clone();
+            move || mux.open("target")
+        });
+        while frames(&recorder).is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let open = &frames(&recorder)[0];
+        assert_eq!((open.kind, open.id), (MessageType::StreamOpen, 1));
+        assert_eq!(&open.payload[4..], b"target");
+
+        mux.handle(frame(MessageType::WindowUpdate, 1, &100u32.to_le_bytes()))
+            .unwrap();
+        let stream = opener.join().unwrap().unwrap();
+
+        // Frames are cut at `max_frame`, then at the window.
+        assert_eq!((&stream).write(&[1; 250]).unwrap(), 64);
+        assert_eq!((&stream).write(&[1; 250]).unwrap(), 36);
+        let writer = thread::spawn(move || {
+            let written = (&stream).write(&[1; 250]);
+            (stream, written)
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert!(!writer.is_finished());
+
+        mux.handle(frame(MessageType::WindowUpdate, 1, &10u32.to_le_bytes()))
+            .unwrap();
+        let (_stream, written) = writer.join().unwrap();
+        assert_eq!(written.unwrap(), 10);
+
+        let sizes: Vec<usize> = frames(&recorder)[1..]
+            .iter()
+            .map(|data| data.payload.len())
+            .collect();
+        assert_eq!(sizes, [64, 36, 10]);
+    }
+
+    #[test]
+    fn reads_grant_the_window() {
+        let (mux, recorder) = recorded_mux(Side::Client, INITIAL_WINDOW);
+        let mut open = 0u32.to_le_bytes().to_vec();
+        open.extend_from_slice(b"echo");
+        mux.handle(frame(MessageType::StreamOpen, 1, &open))
+            .unwrap();
+
+        let pending = mux.accept().unwrap();
+        assert_eq!(pending.target(), "echo");
+        let mut stream = pending.accept().unwrap();
+
+        let half = vec![0; INITIAL_WINDOW as usize / 2];
+        mux.handle(frame(MessageType::StreamData, 1, &half))
+            .unwrap();
+        let mut buf = vec![0; half.len()];
+        stream.read_exact(&mut buf).unwrap();
+
+        let window = INITIAL_WINDOW.to_le_bytes();
+        let update = (INITIAL_WINDOW / 2).to_le_bytes();
+        assert_eq!(
+            frames(&recorder),
+            [
+                frame(MessageType::WindowUpdate, 1, &window),
+                frame(MessageType::WindowUpdate, 1, &update),
+            ]
+        );
+    }
+
+    #[test]
+    fn window_overrun_aborts_the_stream() {
+        let (mux, recorder) = recorded_mux(Side::Client, INITIAL_WINDOW);
+        let mut open = 16u32.to_le_bytes().to_vec();
+        open.extend_from_slice(b"echo");
+        mux.handle(frame(MessageType::StreamOpen, 3, &open))
+            .unwrap();
+        let mut stream = mux.accept().unwrap().accept().unwrap();
+
+        let data = vec![0; INITIAL_WINDOW as usize + 1];
+        mux.handle(frame(MessageType::StreamData, 3, &data))
+            .unwrap();
+
+        let err = stream.read(&mut [0; 8]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        let abort = frames(&recorder).pop().unwrap();
+        assert_eq!(
+            abort,
+            frame(MessageType::StreamClose, 3, b"flow control window exceeded")
+        );
+    }
+
+    #[test]
+    fn invalid_opens_are_refused() {
+        let (mux, recorder) = recorded_mux(Side::Server, INITIAL_WINDOW);
+
+        mux.handle(frame(MessageType::StreamOpen, 2, b"ab"))
+            .unwrap();
+        mux.handle(frame(MessageType::StreamOpen, 5, &[0; 4]))
+            .unwrap();
+        assert_eq!(
+            frames(&recorder),
+            [
+                frame(MessageType::StreamClose, 2, b"invalid stream open"),
+                frame(MessageType::StreamClose, 5, b"invalid stream id"),
+            ]
+        );
+    }
+
+    #[test]
+    fn closed_channel() {
+        let (mux, _recorder) = recorded_mux(Side::Client, INITIAL_WINDOW);
+        let mut open = INITIAL_WINDOW.to_le_bytes().to_vec();
+        open.extend_from_slice(b"echo");
+        mux.handle(frame(MessageType::StreamOpen, 1, &open))
+            .unwrap();
+        let stream = mux.accept().unwrap().accept().unwrap();
+
+        let reader = thread::spawn(move || (&stream).read(&mut [0; 8]));
+        thread::sleep(Duration::from_millis(10));
+        mux.close();
+
+        let err = reader.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        assert!(mux.open("echo").is_err());
+        assert!(mux.accept().is_err());
+    }
+
+    #[test]
+    fn unanswered_open() {
+        let (mux, recorder) = recorded_mux(Side::Server, INITIAL_WINDOW);
+
+        let err = mux
+            .open_timeout("target", Duration::from_millis(20))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "stream to target not answered within 20ms");
+
+        // The stream is aborted, a late answer is ignored.
+        let frames = frames(&recorder);
+        assert_eq!(frames[0].kind, MessageType::StreamOpen);
+        assert_eq!(
+            frames[1],
+            frame(MessageType::StreamClose, 1, b"stream closed")
+        );
+        mux.handle(frame(MessageType::WindowUpdate, 1, &[0; 4]))
+            .unwrap();
+    }
+
+    #[test]
+    fn nothing_is_sent_once_closed() {
+        let (mux, recorder) = recorded_mux(Side::Client, INITIAL_WINDOW);
+        let mut open = INITIAL_WINDOW.to_le_bytes().to_vec();
+        open.extend_from_slice(b"echo");
+        mux.handle(frame(MessageType::StreamOpen, 1, &open))
+            .unwrap();
+        mux.handle(frame(MessageType::StreamOpen, 3, &open))
+            .unwrap();
+        let (first, second) = (mux.accept().unwrap(), mux.accept().unwrap());
+        let stream = first.accept().unwrap();
+        let written = recorder.written().len();
+
+        mux.close();
+        let err = second.accept().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        assert!(stream.shutdown_write().is_err());
+        // Invalid frames are not answered either.
+        assert!(
+            mux.handle(frame(MessageType::StreamOpen, 2, b"ab"))
+                .is_err()
+        );
+        drop(stream);
+        assert_eq!(recorder.written().len(), written);
+    }
+}
@@ -0,0 +1,138 @@
+use bitflags::bitflags;
+
+use crate::Error;
+
+/// Size of a serialized CHANNEL_PDU_HEADER.
+pub const PDU_HEADER_LENGTH: usize = 0x8;
+/// Maximum payload carried by a single chunk (CHANNEL_CHUNK_LENGTH).
+pub const CHANNEL_CHUNK_LENGTH: usize = 1600;
+/// Maximum size of a chunk as read from the channel: header + payload.
+pub const PACKET_MAX_LENGTH: usize = CHANNEL_CHUNK_LENGTH + PDU_HEADER_LENGTH;
+
+bitflags! {
+    /// `flags` field of a CHANNEL_PDU_HEADER ([MS-RDPBCGR] 2.2.6.1.1).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct ChannelFlags: u32 {
+        const FIRST = 0x0000_0001;
+        const LAST = 0x0000_0002;
+        const SHOW_PROTOCOL = 0x0000_0010;
+        const SUSPEND = 0x0000_0020;
+        const RESUME = 0x0000_0040;
+        const SHADOW_PERSISTENT = 0x0000_0080;
+        const PACKET_COMPRESSED = 0x0020_0000;
+        const PACKET_AT_FRONT = 0x0040_0000;
+        const PACKET_FLUSHED = 0x0080_0000;
+        const COMPRESSION_TYPE_MASK = 0x000F_0000;
+    }
+}
+
+impl ChannelFlags {
+    /// A chunk that is neither the first nor the last one (0x0).
+    pub const MIDDLE: Self = Self::empty();
+    /// A message that fits in a single chunk.
+    pub const ONLY: Self = Self::FIRST.union(Self::LAST);
+
+    /// Bits describing the position of a chunk in its message.
+    pub const POSITION_MASK: Self = Self::ONLY;
+
+    pub fn is_first(self) -> bool {
+        self.contains(Self::FIRST)
+    }
+
+    pub fn is_last(self) -> bool {
+        self.contains(Self::LAST)
+    }
+}
+
+/// CHANNEL_PDU_HEADER prefixing every chunk read from a virtual channel.
+///
+/// `length` is the total length of the reassembled message, not the length
+/// of the chunk it is attached to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelPduHeader {
+    pub length: u32,
+    pub flags: ChannelFlags,
+}
+
+impl ChannelPduHeader {
+    pub fn new(length: u32, flags: ChannelFlags) -> Self {
+        Self { length, flags }
+    }
+
+    /// Parse the header at the start of `buf`. Trailing bytes are ignored.
+    pub fn decode(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < PDU_HEADER_LENGTH {
+            return Err(Error::NotAPduHeader(buf.len()));
+        }
+
+        let mut length = [0u8; 4];
+        length.copy_from_slice(&buf[..4]);
+
+        let mut flags = [0u8; 4];
+        flags.copy_from_slice(&buf[4..PDU_HEADER_LENGTH]);
+
+        Ok(Self {
+            length: u32::from_le_bytes(length),
+            flags: ChannelFlags::from_bits_retain(u32::from_le_bytes(flags)),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; PDU_HEADER_LENGTH] {
+        let mut buf = [0u8; PDU_HEADER_LENGTH];
+        buf[..4].copy_from_slice(&self.length.to_le_bytes());
+        buf[4..].copy_from_slice(&self.flags.bits().to_le_bytes());
+        buf
+    }
+}
+
+/// Split a raw chunk into its header and payload.
+pub fn decode_chunk(chunk: &[u8]) -> Result<(ChannelPduHeader, &[u8]), Error> {
+    let header = ChannelPduHeader::decode(chunk)?;
+    Ok((header, &chunk[PDU_HEADER_LENGTH..]))
+}
+
+/// Serialize a header followed by `payload` into a single chunk.
+pub fn encode_chunk(header: ChannelPduHeader, payload: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(PDU_HEADER_LENGTH + payload.len());
+    chunk.extend_from_slice(&header.encode());
+    chunk.extend_from_slice(payload);
+    chunk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_roundtrip() {
+        let header = ChannelPduHeader::new(0x1234, ChannelFlags::FIRST);
+        let raw = header.encode();
+        assert_eq!(raw, [0x34, 0x12, 0, 0, 0x01, 0, 0, 0]);
+        assert_eq!(ChannelPduHeader::decode(&raw).unwrap(), header);
+    }
+
+    #[test]
+    fn decode_keeps_unknown_bits() {
+        let raw = [0, 0, 0, 0, 0x03, 0, 0, 0x80];
+        let header = ChannelPduHeader::decode(&raw).unwrap();
+        assert_eq!(header.flags.bits(), 0x8000_0003);
+        assert!(header.flags.is_first() && header.flags.is_last());
+    }
+
+    #[test]
+    fn decode_short_buffer() {
+        assert!(matches!(
+            ChannelPduHeader::decode(&[0u8; 7]),
+            Err(Error::NotAPduHeader(7))
+        ));
+    }
+
+    #[test]
+    fn chunk_roundtrip() {
+        let header = ChannelPduHeader::new(5, ChannelFlags::ONLY);
+        let chunk = encode_chunk(header, b"hello");
+        let (decoded, payload) = decode_chunk(&chunk).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(payload, b"hello");
+    }
+}
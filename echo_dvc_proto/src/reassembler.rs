@@ -0,0 +1,229 @@
+use log::debug;
+
+use crate::{ChannelFlags, Error, pdu::decode_chunk};
+
+/// Upper bound on the capacity reserved up front from a declared length, so
+/// that a bogus header cannot trigger a huge allocation on its own.
+const MAX_PREALLOCATION: usize = 1 << 20;
+
+/// Rebuild messages from the chunks read on a virtual channel.
+///
+/// Each chunk is expected to start with a CHANNEL_PDU_HEADER. Chunks are fed
+/// one at a time through [`Reassembler::push`] which yields the message once
+/// the chunk flagged `LAST` has been received.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    buffer: Vec<u8>,
+    /// Total length announced by the `FIRST` chunk of the pending message.
+    declared: Option<u32>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed a chunk, header included, exactly as it was read.
+    ///
+    /// `chunk` must only contain the bytes actually read. On error, the
+    /// pending message is dropped and the reassembler is ready for a new one.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.push_inner(chunk).inspect_err(|_| self.reset())
+    }
+
+    /// Drop any partially reassembled message.
+    pub fn reset(&mut self) {
+        self.buffer = Vec::new();
+        self.declared = None;
+    }
+
+    /// Whether a message has been started but not completed yet.
+    pub fn is_pending(&self) -> bool {
+        self.declared.is_some()
+    }
+
+    fn push_inner(&mut self, chunk: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let (header, payload) = decode_chunk(chunk)?;
+
+        if !ChannelFlags::POSITION_MASK.contains(header.flags) {
+            return Err(Error::UnsupportedFlags(header.flags.bits()));
+        }
+
+        match header.flags {
+            ChannelFlags::ONLY => debug!("CHANNEL_FLAG_ONLY: one packet to read"),
+            ChannelFlags::LAST => debug!("CHANNEL_FLAG_LAST: last packet"),
+            ChannelFlags::FIRST => debug!("CHANNEL_FLAG_FIRST: first packet"),
+            _ => debug!("CHANNEL_FLAG_MIDDLE: continuing..."),
+        }
+
+        let declared = match (self.declared, header.flags.is_first()) {
+            (None, true) => {
+                let capacity = (header.length as usize).min(MAX_PREALLOCATION);
+                self.buffer = Vec::with_capacity(capacity);
+                self.declared = Some(header.length);
+                header.length
+            }
+            (Some(declared), false) if declared == header.length => declared,
+            (Some(declared), false) => {
+                return Err(Error::LengthChanged {
+                    declared,
+                    found: header.length,
+                });
+            }
+            _ => return Err(Error::OutOfSequence(header.flags.bits())),
+        };
+
+        let received = self.buffer.len() + payload.len();
+        if received > declared as usize {
+            return Err(Error::Overrun { declared, received });
+        }
+
+        self.buffer.extend_from_slice(payload);
+
+        if !header.flags.is_last() {
+            return Ok(None);
+        }
+
+        if received != declared as usize {
+            return Err(Error::LengthMismatch { declared, received });
+        }
+
+        self.declared = None;
+        Ok(Some(std::mem::take(&mut self.buffer)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChannelPduHeader, encode_chunk};
+
+    fn chunk(length: u32, flags: ChannelFlags, payload: &[u8]) -> Vec<u8> {
+        encode_chunk(ChannelPduHeader::new(length, flags), payload)
+    }
+
+    #[test]
+    fn single_chunk() {
+        let mut reassembler = Reassembler::new();
+        let only = chunk(3, ChannelFlags::ONLY, b"abc");
+        assert_eq!(reassembler.push(&only).unwrap(), Some(b"abc".to_vec()));
+        assert!(!reassembler.is_pending());
+    }
+
+    #[test]
+    fn multiple_chunks() {
+        let mut reassembler = Reassembler::new();
+        let first = chunk(6, ChannelFlags::FIRST, b"ab");
+        let middle = chunk(6, ChannelFlags::MIDDLE, b"cd");
+        let last = chunk(6, ChannelFlags::LAST, b"ef");
+
+        assert_eq!(reassembler.push(&first).unwrap(), None);
+        assert!(reassembler.is_pending());
+        assert_eq!(reassembler.push(&middle).unwrap(), None);
+        assert_eq!(reassembler.push(&last).unwrap(), Some(b"abcdef".to_vec()));
+    }
+
+    #[test]
+    fn short_chunks_keep_nul_bytes() {
+        let mut reassembler = Reassembler::new();
+        let first = chunk(4, ChannelFlags::FIRST, b"\0a");
+        let last = chunk(4, ChannelFlags::LAST, b"b\0");
+
+        assert_eq!(reassembler.push(&first).unwrap(), None);
+        assert_eq!(reassembler.push(&last).unwrap(), Some(b"\0ab\0".to_vec()));
+    }
+
+    #[test]
+    fn empty_message() {
+        let mut reassembler = Reassembler::new();
+        let only = chunk(0, ChannelFlags::ONLY, b"");
+        assert_eq!(reassembler.push(&only).unwrap(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn rejects_unsupported_flags() {
+        let mut reassembler = Reassembler::new();
+        let only = chunk(1, ChannelFlags::ONLY | ChannelFlags::SUSPEND, b"a");
+        assert!(matches!(
+            reassembler.push(&only),
+            Err(Error::UnsupportedFlags(0x23))
+        ));
+    }
+
+    #[test]
+    fn rejects_length_mismatch() {
+        let mut reassembler = Reassembler::new();
+        let only = chunk(4, ChannelFlags::ONLY, b"abc");
+        assert!(matches!(
+            reassembler.push(&only),
+            Err(Error::LengthMismatch {
+                declared: 4,
+                received: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_changing_length() {
+        let mut reassembler = Reassembler::new();
+        reassembler
+            .push(&chunk(4, ChannelFlags::FIRST, b"ab"))
+            .unwrap();
+        assert!(matches!(
+            reassembler.push(&chunk(5, ChannelFlags::LAST, b"cde")),
+            Err(Error::LengthChanged {
+                declared: 4,
+                found: 5
+            })
+        ));
+        assert!(!reassembler.is_pending());
+    }
+
+    #[test]
+    fn rejects_overrun() {
+        let mut reassembler = Reassembler::new();
+        reassembler
+            .push(&chunk(3, ChannelFlags::FIRST, b"ab"))
+            .unwrap();
+        assert!(matches!(
+            reassembler.push(&chunk(3, ChannelFlags::MIDDLE, b"cd")),
+            Err(Error::Overrun {
+                declared: 3,
+                received: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_out_of_sequence() {
+        let mut reassembler = Reassembler::new();
+        assert!(matches!(
+            reassembler.push(&chunk(2, ChannelFlags::LAST, b"ab")),
+            Err(Error::OutOfSequence(0x2))
+        ));
+
+        reassembler
+            .push(&chunk(4, ChannelFlags::FIRST, b"ab"))
+            .unwrap();
+        assert!(matches!(
+            reassembler.push(&chunk(4, ChannelFlags::FIRST, b"ab")),
+            Err(Error::OutOfSequence(0x1))
+        ));
+    }
+
+    #[test]
+    fn recovers_after_error() {
+        let mut reassembler = Reassembler::new();
+        reassembler
+            .push(&chunk(3, ChannelFlags::FIRST, b"ab"))
+            .unwrap();
+        assert!(
+            reassembler
+                .push(&chunk(3, ChannelFlags::LAST, b"cd"))
+                .is_err()
+        );
+
+        let only = chunk(2, ChannelFlags::ONLY, b"ok");
+        assert_eq!(reassembler.push(&only).unwrap(), Some(b"ok".to_vec()));
+    }
+}
@@ -0,0 +1,363 @@
+//! In-process stand-in for the channel manager of an RDP client.
+//!
+//! It drives the handler layer the way the client drives a plugin: listeners
+//! are created at initialization, channels are accepted, fed with messages
+//! and closed, and everything a handler writes back is queued per channel.
+//!
+//! Only [`ChannelListener`] and the handlers are exercised: the COM and
+//! FreeRDP glue, with their vtables and callback objects, is left out. The
+//! FreeRDP plugin tests its glue against a channel manager double of its own.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt, io,
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use crate::{ChannelHandle, ChannelListener, ChannelWriter, ListenerConfig, OpenChannel};
+
+/// Identifier of a simulated channel, as returned by `GetChannelId`.
+pub type ChannelId = u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Initialized,
+    Connected,
+    Disconnected,
+    Terminated,
+}
+
+/// Simulated `IWTSVirtualChannelManager`.
+#[derive(Debug, Default)]
+pub struct SimChannelManager {
+    listeners: Vec<Arc<ChannelListener>>,
+    channels: BTreeMap<ChannelId, SimChannel>,
+    next_id: ChannelId,
+    state: Option<State>,
+}
+
+#[derive(Debug)]
+struct SimChannel {
+    channel: OpenChannel,
+    writer: Arc<SimWriter>,
+}
+
+/// Write side of a simulated channel.
+#[derive(Debug, Default)]
+struct SimWriter {
+    written: Mutex<VecDeque<Vec<u8>>>,
+    broken: AtomicBool,
+}
+
+impl ChannelWriter for Arc<SimWriter> {
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        if self.broken.load(Ordering::Relaxed) {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "simulated write failure",
+            ));
+        }
+
+        self.written
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push_back(data.to_vec());
+        Ok(())
+    }
+}
+
+impl SimChannelManager {
+    /// Plugin `Initialize`: one `CreateListener` per entry of the table.
+    pub fn initialize(listeners: Vec<ListenerConfig>) -> Result<Self, SimError> {
+        let mut manager = Self::default();
+        for config in listeners {
+            manager.create_listener(config)?;
+        }
+        manager.state = Some(State::Initialized);
+        Ok(manager)
+    }
+
+    pub fn create_listener(&mut self, config: ListenerConfig) -> Result<(), SimError> {
+        if self.listener(&config.name).is_some() {
+            return Err(SimError::DuplicateListener(config.name));
+        }
+        self.listeners.push(ChannelListener::new(config));
+        Ok(())
+    }
+
+    /// Plugin `Connected`, also sent again when the client reconnects.
+    pub fn connected(&mut self) -> Result<(), SimError> {
+        self.transition(&[State::Initialized, State::Disconnected], State::Connected)
+    }
+
+    /// Plugin `Disconnected`. Like the client, close every channel first.
+    pub fn disconnected(&mut self) -> Result<(), SimError> {
+        self.transition(&[State::Connected], State::Disconnected)?;
+        self.close_all();
+        Ok(())
+    }
+
+    /// Plugin `Terminated`. Channels still open are closed.
+    pub fn terminated(&mut self) {
+        self.close_all();
+        self.state = Some(State::Terminated);
+    }
+
+    /// Open a channel on the listener called `name`, as a server calling
+    /// `WTSVirtualChannelOpenEx` triggers `OnNewChannelConnection`.
+    pub fn open(&mut self, name: &str) -> Result<ChannelId, SimError> {
+        self.expect_connected()?;
+
+        let listener = self
+            .listener(name)
+            .ok_or_else(|| SimError::UnknownListener(name.to_owned()))?
+            .clone();
+
+        let writer = Arc::new(SimWriter::default());
+        let channel = listener
+            .open(ChannelHandle::new(writer.clone()))
+            .map_err(SimError::Rejected)?;
+
+        self.next_id += 1;
+        self.channels
+            .insert(self.next_id, SimChannel { channel, writer });
+        Ok(self.next_id)
+    }
+
+    /// Deliver a message to the channel, as `OnDataReceived`.
+    pub fn send(&mut self, id: ChannelId, data: &[u8]) -> Result<(), SimError> {
+        self.expect_connected()?;
+        self.channel_mut(id)?
+            .channel
+            .on_data(data)
+            .map_err(SimError::Handler)
+    }
+
+    /// Next message written by the handler on the channel.
+    pub fn recv(&self, id: ChannelId) -> Result<Option<Vec<u8>>, SimError> {
+        let channel = self.channels.get(&id).ok_or(SimError::UnknownChannel(id))?;
+        Ok(channel
+            .writer
+            .written
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop_front())
+    }
+
+    /// Make every following `Write` on the channel fail.
+    pub fn break_channel(&mut self, id: ChannelId) -> Result<(), SimError> {
+        self.channel_mut(id)?
+            .writer
+            .broken
+            .store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Close the channel from the server side, as `OnClose`.
+    pub fn close(&mut self, id: ChannelId) -> Result<(), SimError> {
+        let mut channel = self
+            .channels
+            .remove(&id)
+            .ok_or(SimError::UnknownChannel(id))?;
+        channel.channel.close();
+        Ok(())
+    }
+
+    /// Number of channels open on the listener called `name`.
+    pub fn open_channels(&self, name: &str) -> usize {
+        self.listener(name)
+            .map_or(0, |listener| listener.open_channels())
+    }
+
+    fn listener(&self, name: &str) -> Option<&Arc<ChannelListener>> {
+        self.listeners
+            .iter()
+            .find(|listener| listener.name() == name)
+    }
+
+    fn channel_mut(&mut self, id: ChannelId) -> Result<&mut SimChannel, SimError> {
+        self.channels
+            .get_mut(&id)
+            .ok_or(SimError::UnknownChannel(id))
+    }
+
+    fn close_all(&mut self) {
+        for (_, mut channel) in std::mem::take(&mut self.channels) {
+            channel.channel.close();
+        }
+    }
+
+    fn expect_connected(&self) -> Result<(), SimError> {
+        match self.state {
+            Some(State::Connected) => Ok(()),
+            _ => Err(SimError::NotConnected),
+        }
+    }
+
+    fn transition(&mut self, from: &[State], to: State) -> Result<(), SimError> {
+        if !self.state.is_some_and(|state| from.contains(&state)) {
+            return Err(SimError::NotConnected);
+        }
+        self.state = Some(to);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum SimError {
+    DuplicateListener(String),
+    UnknownListener(String),
+    UnknownChannel(ChannelId),
+    /// Lifecycle call made in the wrong state, or channel use outside of a
+    /// connection.
+    NotConnected,
+    /// The handler refused the channel in `on_open`.
+    Rejected(io::Error),
+    Handler(io::Error),
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimError::DuplicateListener(name) => write!(f, "listener {name} already exists"),
+            SimError::UnknownListener(name) => write!(f, "no listener for channel {name}"),
+            SimError::UnknownChannel(id) => write!(f, "no open channel with id {id}"),
+            SimError::NotConnected => write!(f, "client is not connected"),
+            SimError::Rejected(err) => write!(f, "channel rejected: {err}"),
+            SimError::Handler(err) => write!(f, "handler error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for SimError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SimError::Rejected(err) | SimError::Handler(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChannelHandler, DVC_NAME, EchoHandler};
+
+    struct Refuse;
+
+    impl ChannelHandler for Refuse {
+        fn on_open(&mut self, _channel: &ChannelHandle) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "refused"))
+        }
+
+        fn on_data(&mut self, _channel: &ChannelHandle, _data: &[u8]) -> io::Result<()> {
+            unreachable!()
+        }
+    }
+
+    fn connected() -> SimChannelManager {
+        let mut manager = SimChannelManager::initialize(vec![
+            ListenerConfig::new(DVC_NAME, || Box::new(EchoHandler)),
+            ListenerConfig::new("REFUSE", || Box::new(Refuse)),
+        ])
+        .unwrap();
+        manager.connected().unwrap();
+        manager
+    }
+
+    #[test]
+    fn echo_conversation() {
+        let mut manager = connected();
+        let id = manager.open(DVC_NAME).unwrap();
+        assert_eq!(manager.open_channels(DVC_NAME), 1);
+
+        for message in [&b"hello"[..], b"", &[0xAA; 5000]] {
+            manager.send(id, message).unwrap();
+            assert_eq!(manager.recv(id).unwrap().as_deref(), Some(message));
+        }
+        assert_eq!(manager.recv(id).unwrap(), None);
+
+        manager.close(id).unwrap();
+        assert_eq!(manager.open_channels(DVC_NAME), 0);
+        assert!(matches!(
+            manager.send(id, b"late"),
+            Err(SimError::UnknownChannel(_))
+        ));
+    }
+
+    #[test]
+    fn channels_are_independent() {
+        let mut manager = connected();
+        let first = manager.open(DVC_NAME).unwrap();
+        let second = manager.open(DVC_NAME).unwrap();
+
+        manager.send(first, b"one").unwrap();
+        manager.send(second, b"two").unwrap();
+
+        assert_eq!(manager.recv(second).unwrap(), Some(b"two".to_vec()));
+        assert_eq!(manager.recv(first).unwrap(), Some(b"one".to_vec()));
+    }
+
+    #[test]
+    fn open_errors() {
+        let mut manager = connected();
+
+        assert!(matches!(
+            manager.open("MISSING"),
+            Err(SimError::UnknownListener(_))
+        ));
+        assert!(matches!(manager.open("REFUSE"), Err(SimError::Rejected(_))));
+        assert_eq!(manager.open_channels("REFUSE"), 0);
+
+        assert!(matches!(
+            SimChannelManager::initialize(vec![
+                ListenerConfig::new("A", || Box::new(EchoHandler)),
+                ListenerConfig::new("A", || Box::new(EchoHandler)),
+            ]),
+            Err(SimError::DuplicateListener(_))
+        ));
+    }
+
+    #[test]
+    fn write_failure_is_reported() {
+        let mut manager = connected();
+        let id = manager.open(DVC_NAME).unwrap();
+
+        manager.break_channel(id).unwrap();
+        let err = manager.send(id, b"hello").unwrap_err();
+        assert!(
+            matches!(err, SimError::Handler(ref err) if err.kind() == io::ErrorKind::BrokenPipe)
+        );
+    }
+
+    #[test]
+    fn lifecycle() {
+        let mut manager = SimChannelManager::initialize(ListenerConfig::defaults()).unwrap();
+        assert!(matches!(
+            manager.open(DVC_NAME),
+            Err(SimError::NotConnected)
+        ));
+
+        manager.connected().unwrap();
+        let id = manager.open(DVC_NAME).unwrap();
+        manager.open(DVC_NAME).unwrap();
+        assert_eq!(manager.open_channels(DVC_NAME), 2);
+
+        manager.disconnected().unwrap();
+        assert_eq!(manager.open_channels(DVC_NAME), 0);
+        assert!(matches!(
+            manager.send(id, b"hello"),
+            Err(SimError::NotConnected)
+        ));
+        assert!(matches!(
+            manager.disconnected(),
+            Err(SimError::NotConnected)
+        ));
+
+        manager.terminated();
+        assert!(matches!(manager.connected(), Err(SimError::NotConnected)));
+    }
+}
@@ -0,0 +1,527 @@
+use std::{
+    io::{self, Cursor, Read, Write},
+    mem,
+    net::{Shutdown, TcpStream},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    CHANNEL_CHUNK_LENGTH, ChannelPduHeader, DvcReceiver, DvcSender, DvcTransport, Fragmenter,
+    PDU_HEADER_LENGTH, Reassembler, Timeouts,
+};
+
+/// Byte stream that can carry a [`StreamTransport`].
+pub trait ByteStream: Read + Write + Send + 'static {
+    /// Second handle on the same stream, for [`DvcTransport::split`]. Not
+    /// supported by default.
+    fn try_clone(&self) -> io::Result<Self>
+    where
+        Self: Sized,
+    {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "stream cannot be cloned",
+        ))
+    }
+
+    /// Close both directions, for every handle on the stream.
+    fn shutdown(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Bound the time a single read may block, for every handle on the
+    /// stream. A read which times out fails with [`io::ErrorKind::WouldBlock`]
+    /// or [`io::ErrorKind::TimedOut`]. Not supported by default.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let _ = timeout;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "stream has no timeouts",
+        ))
+    }
+
+    /// Same as [`ByteStream::set_read_timeout`], for writes.
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let _ = timeout;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "stream has no timeouts",
+        ))
+    }
+}
+
+impl ByteStream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl ByteStream for std::os::unix::net::UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        std::os::unix::net::UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        std::os::unix::net::UnixStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+/// In-memory streams never block, their timeouts are ignored.
+impl ByteStream for Cursor<Vec<u8>> {
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Carry virtual channel chunks over a byte stream such as a TCP socket.
+///
+/// Messages are written as a sequence of CHANNEL_PDU_HEADER prefixed chunks,
+/// exactly as they would be read from a DVC file handle. Every chunk but the
+/// last one of a message carries `CHANNEL_CHUNK_LENGTH` bytes, which lets the
+/// reader know how much payload follows each header.
+///
+/// A write which times out after part of a message was written closes the
+/// transport, since the peer could not make sense of what follows.
+#[derive(Debug)]
+pub struct StreamTransport<S> {
+    stream: Option<S>,
+    reader: ChunkReader,
+    write_timeout: Option<Duration>,
+}
+
+/// Read side state of a [`StreamTransport`]. The chunk being read is kept
+/// when a read times out, so that the next `recv` resumes it.
+#[derive(Debug, Default)]
+struct ChunkReader {
+    reassembler: Reassembler,
+    /// Payload bytes still expected for the message being read.
+    remaining: usize,
+    /// Chunk being read: its header, then the whole chunk once the header
+    /// is decoded.
+    chunk: Vec<u8>,
+    /// Bytes of `chunk` already read.
+    filled: usize,
+    header_decoded: bool,
+    timeout: Option<Duration>,
+}
+
+impl<S: ByteStream> StreamTransport<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: Some(stream),
+            reader: ChunkReader::default(),
+            write_timeout: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> Option<&S> {
+        self.stream.as_ref()
+    }
+
+    fn stream(&mut self) -> io::Result<&mut S> {
+        stream(&mut self.stream)
+    }
+}
+
+fn stream<S>(stream: &mut Option<S>) -> io::Result<&mut S> {
+    stream
+        .as_mut()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "channel closed"))
+}
+
+/// End of an operation bounded by `timeout`.
+fn deadline(timeout: Option<Duration>) -> Option<Instant> {
+    timeout.and_then(|timeout| Instant::now().checked_add(timeout))
+}
+
+/// Time left before `deadline`, which must not be over.
+fn time_left(deadline: Instant, operation: &str) -> io::Result<Duration> {
+    match deadline.saturating_duration_since(Instant::now()) {
+        Duration::ZERO => Err(timed_out(operation)),
+        left => Ok(left),
+    }
+}
+
+fn timed_out(operation: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, format!("{operation} timed out"))
+}
+
+/// Blocking sockets report an expired timeout as `WouldBlock` on Unix.
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+impl ChunkReader {
+    fn recv(&mut self, stream: &mut impl ByteStream) -> io::Result<Vec<u8>> {
+        let deadline = deadline(self.timeout);
+        loop {
+            let chunk = self.read_chunk(stream, deadline)?;
+            if let Some(message) = self.reassembler.push(&chunk)? {
+                return Ok(message);
+            }
+        }
+    }
+
+    fn read_chunk(
+        &mut self,
+        stream: &mut impl ByteStream,
+        deadline: Option<Instant>,
+    ) -> io::Result<Vec<u8>> {
+        if !self.header_decoded {
+            self.chunk.resize(PDU_HEADER_LENGTH, 0);
+            self.fill(stream, deadline)?;
+
+            // Start over with the next bytes rather than decode this header
+            // again.
+            let header = ChannelPduHeader::decode(&self.chunk).inspect_err(|_| self.filled = 0)?;
+            if header.flags.is_first() {
+                self.remaining = header.length as usize;
+            }
+
+            let payload_length = self.remaining.min(CHANNEL_CHUNK_LENGTH);
+            self.remaining -= payload_length;
+
+            self.chunk.resize(PDU_HEADER_LENGTH + payload_length, 0);
+            self.header_decoded = true;
+        }
+        self.fill(stream, deadline)?;
+
+        self.header_decoded = false;
+        self.filled = 0;
+        Ok(mem::take(&mut self.chunk))
+    }
+
+    /// Read the rest of `chunk`. What was read is kept when this fails.
+    fn fill(&mut self, stream: &mut impl ByteStream, deadline: Option<Instant>) -> io::Result<()> {
+        while self.filled < self.chunk.len() {
+            if let Some(deadline) = deadline {
+                stream.set_read_timeout(Some(time_left(deadline, "read")?))?;
+            }
+
+            match stream.read(&mut self.chunk[self.filled..]) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ));
+                }
+                Ok(read) => self.filled += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) if is_timeout(&err) => return Err(timed_out("read")),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write `data` on `slot`, closing it if the write times out halfway.
+fn send<S: ByteStream>(
+    slot: &mut Option<S>,
+    data: &[u8],
+    timeout: Option<Duration>,
+) -> io::Result<()> {
+    let frame = Fragmenter::new(data)?.encode();
+    let stream = stream(slot)?;
+    let deadline = deadline(timeout);
+
+    let mut written = 0;
+    while written < frame.len() {
+        let ret = match deadline {
+            Some(deadline) => time_left(deadline, "write")
+                .and_then(|left| stream.set_write_timeout(Some(left)))
+                .and_then(|_| stream.write(&frame[written..])),
+            None => stream.write(&frame[written..]),
+        };
+
+        match ret {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(count) => written += count,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) if is_timeout(&err) => {
+                if written > 0 {
+                    let _ = stream.shutdown();
+                    *slot = None;
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "write timed out in the middle of a message, channel closed",
+                    ));
+                }
+                return Err(timed_out("write"));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    stream.flush()
+}
+
+/// Apply `timeouts` to `stream`, checking that it supports them.
+fn set_timeouts(stream: &impl ByteStream, timeouts: Timeouts) -> io::Result<()> {
+    stream.set_read_timeout(timeouts.read)?;
+    stream.set_write_timeout(timeouts.write)
+}
+
+impl<S: ByteStream> DvcTransport for StreamTransport<S> {
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        send(&mut self.stream, data, self.write_timeout)
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        let stream = stream(&mut self.stream)?;
+        self.reader.recv(stream)
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        if let Some(mut stream) = self.stream.take() {
+            stream.flush()?;
+        }
+        Ok(())
+    }
+
+    fn set_timeouts(&mut self, timeouts: Timeouts) -> io::Result<()> {
+        set_timeouts(self.stream()?, timeouts)?;
+        self.reader.timeout = timeouts.read;
+        self.write_timeout = timeouts.write;
+        Ok(())
+    }
+
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn DvcSender>, Box<dyn DvcReceiver>)> {
+        let StreamTransport {
+            mut stream,
+            reader,
+            write_timeout,
+        } = *self;
+        let read_half = self::stream(&mut stream)?.try_clone()?;
+
+        Ok((
+            Box::new(StreamSender {
+                stream,
+                timeout: write_timeout,
+            }),
+            Box::new(StreamReceiver {
+                stream: read_half,
+                reader,
+            }),
+        ))
+    }
+}
+
+struct StreamSender<S> {
+    stream: Option<S>,
+    timeout: Option<Duration>,
+}
+
+impl<S: ByteStream> DvcSender for StreamSender<S> {
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        send(&mut self.stream, data, self.timeout)
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        if let Some(mut stream) = self.stream.take() {
+            stream.flush()?;
+            stream.shutdown()?;
+        }
+        Ok(())
+    }
+}
+
+struct StreamReceiver<S> {
+    stream: S,
+    reader: ChunkReader,
+}
+
+impl<S: ByteStream> DvcReceiver for StreamReceiver<S> {
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        self.reader.recv(&mut self.stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChannelFlags;
+    use proptest::prelude::*;
+
+    fn roundtrip(data: &[u8]) -> Vec<u8> {
+        let mut writer = StreamTransport::new(Cursor::new(Vec::new()));
+        writer.send(data).unwrap();
+        let written = writer.get_ref().unwrap().get_ref().clone();
+
+        let mut reader = StreamTransport::new(Cursor::new(written));
+        reader.recv().unwrap()
+    }
+
+    #[test]
+    fn single_chunk() {
+        assert_eq!(roundtrip(b"hello"), b"hello");
+        assert_eq!(roundtrip(b""), b"");
+    }
+
+    #[test]
+    fn multiple_chunks() {
+        let data: Vec<u8> = (0..CHANNEL_CHUNK_LENGTH * 2 + 10)
+            .map(|i| i as u8)
+            .collect();
+        assert_eq!(roundtrip(&data), data);
+    }
+
+    #[test]
+    fn chunk_layout() {
+        let data = vec![0xAA; CHANNEL_CHUNK_LENGTH + 1];
+        let mut writer = StreamTransport::new(Cursor::new(Vec::new()));
+        writer.send(&data).unwrap();
+        let written = writer.get_ref().unwrap().get_ref();
+
+        let first = ChannelPduHeader::decode(written).unwrap();
+        assert_eq!(first.flags, ChannelFlags::FIRST);
+        assert_eq!(first.length as usize, data.len());
+
+        let last = ChannelPduHeader::decode(&written[PDU_HEADER_LENGTH + CHANNEL_CHUNK_LENGTH..]);
+        assert_eq!(last.unwrap().flags, ChannelFlags::LAST);
+        assert_eq!(written.len(), data.len() + 2 * PDU_HEADER_LENGTH);
+    }
+
+    #[test]
+    fn truncated_stream() {
+        let mut writer = StreamTransport::new(Cursor::new(Vec::new()));
+        writer.send(b"hello").unwrap();
+        let mut written = writer.get_ref().unwrap().get_ref().clone();
+        written.pop();
+
+        let mut reader = StreamTransport::new(Cursor::new(written));
+        assert_eq!(
+            reader.recv().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip_messages(
+            messages in prop::collection::vec(
+                prop::collection::vec(any::<u8>(), 0..CHANNEL_CHUNK_LENGTH * 3),
+                1..4,
+            )
+        ) {
+            let mut writer = StreamTransport::new(Cursor::new(Vec::new()));
+            for message in &messages {
+                writer.send(message).unwrap();
+            }
+            let written = writer.get_ref().unwrap().get_ref().clone();
+
+            let mut reader = StreamTransport::new(Cursor::new(written));
+            for message in &messages {
+                prop_assert_eq!(&reader.recv().unwrap(), message);
+            }
+        }
+    }
+
+    #[test]
+    fn split_over_tcp() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut peer = StreamTransport::new(listener.accept().unwrap().0);
+
+        let transport: Box<dyn DvcTransport> = Box::new(StreamTransport::new(client));
+        let (mut sender, mut receiver) = transport.split().unwrap();
+        let reader = std::thread::spawn(move || (receiver.recv(), receiver.recv()));
+
+        sender.send(b"ping").unwrap();
+        assert_eq!(peer.recv().unwrap(), b"ping");
+        peer.send(b"pong").unwrap();
+
+        sender.close().unwrap();
+        let (first, second) = reader.join().unwrap();
+        assert_eq!(first.unwrap(), b"pong");
+        assert_eq!(second.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn in_memory_stream_cannot_be_split() {
+        let transport: Box<dyn DvcTransport> =
+            Box::new(StreamTransport::new(Cursor::new(Vec::new())));
+        let err = transport.split().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (client, listener.accept().unwrap().0)
+    }
+
+    const SHORT: Option<Duration> = Some(Duration::from_millis(50));
+
+    #[test]
+    fn read_timeout_resumes_the_message() {
+        let (client, mut peer) = tcp_pair();
+        let mut transport = StreamTransport::new(client);
+        transport
+            .set_timeouts(Timeouts {
+                read: SHORT,
+                write: None,
+            })
+            .unwrap();
+
+        let err = transport.recv().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // Time out in the middle of a header, then of a payload.
+        let data = vec![0x5A; CHANNEL_CHUNK_LENGTH + 10];
+        let frame = Fragmenter::new(&data).unwrap().encode();
+        for part in [&frame[..3], &frame[3..PDU_HEADER_LENGTH + 100]] {
+            peer.write_all(part).unwrap();
+            assert_eq!(
+                transport.recv().unwrap_err().kind(),
+                io::ErrorKind::TimedOut
+            );
+        }
+        peer.write_all(&frame[PDU_HEADER_LENGTH + 100..]).unwrap();
+        assert_eq!(transport.recv().unwrap(), data);
+    }
+
+    #[test]
+    fn write_timeout() {
+        let (client, _peer) = tcp_pair();
+        let mut transport = StreamTransport::new(client);
+        transport
+            .set_timeouts(Timeouts {
+                read: None,
+                write: SHORT,
+            })
+            .unwrap();
+
+        // Nothing is read on the other end, so the socket buffers fill up.
+        let data = vec![0; 1 << 20];
+        let err = (0..64).find_map(|_| transport.send(&data).err()).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,52 @@
+//! Fixtures shared by the tests, here and in the server.
+//!
+//! They panic on failure, as tests do.
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, TcpListener},
+    thread,
+};
+
+use crate::{
+    ChannelListener, DvcTransport, ForwardPolicy, Hello, ListenerConfig, Loopback, MessageHandler,
+    MessageTransport, Mux,
+};
+
+/// Loopback whose peer answers every message with `reply(message)`.
+pub fn echo_peer(reply: fn(Vec<u8>) -> Vec<u8>) -> Loopback {
+    let (local, mut remote) = Loopback::pair();
+    thread::spawn(move || {
+        while let Ok(data) = remote.recv() {
+            if remote.send(&reply(data)).is_err() {
+                break;
+            }
+        }
+    });
+    local
+}
+
+/// Server side mux, talking over a loopback to a [`MessageHandler`]
+/// following `policy`.
+pub fn message_mux(policy: ForwardPolicy) -> Mux {
+    let (local, remote) = Loopback::pair();
+    let listener = ChannelListener::new(ListenerConfig::new("ECHOCHN", move || {
+        Box::new(MessageHandler::new(policy.clone()))
+    }));
+    thread::spawn(move || listener.serve(Box::new(remote)));
+
+    let mut transport = MessageTransport::new(local);
+    let negotiated = transport.handshake(&Hello::default()).unwrap();
+    transport.into_mux(&negotiated).unwrap()
+}
+
+/// TCP server on the loopback sending back what it receives, once.
+pub fn tcp_echo() -> SocketAddr {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (socket, _) = listener.accept().unwrap();
+        io::copy(&mut &socket, &mut &socket).unwrap();
+    });
+    address
+}
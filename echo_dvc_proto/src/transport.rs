@@ -0,0 +1,258 @@
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+/// Longest time a `send` or `recv` may block, `None` waiting forever.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+}
+
+/// Message oriented access to an opened virtual channel.
+///
+/// Implementations deliver whole messages: chunking and reassembly, if any,
+/// happen below this trait.
+pub trait DvcTransport {
+    fn send(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Block until a full message has been received.
+    fn recv(&mut self) -> io::Result<Vec<u8>>;
+
+    fn close(&mut self) -> io::Result<()>;
+
+    /// Bound the time `send` and `recv` wait for the peer. An operation
+    /// which does not complete in time is cancelled and fails with
+    /// [`io::ErrorKind::TimedOut`]. A `recv` can be retried: the part of the
+    /// message already received is kept. Not supported by default.
+    ///
+    /// The halves of [`DvcTransport::split`] keep the timeouts set before.
+    fn set_timeouts(&mut self, timeouts: Timeouts) -> io::Result<()> {
+        let _ = timeouts;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "this transport has no timeouts",
+        ))
+    }
+
+    /// Split into a sending and a receiving half, so that messages can be
+    /// received on a thread while others are sent. Closing the sending half
+    /// closes the channel, but a `recv` pending on the other half may only
+    /// return once the peer notices it.
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn DvcSender>, Box<dyn DvcReceiver>)> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "this transport cannot be split",
+        ))
+    }
+}
+
+/// Sending half of a split [`DvcTransport`].
+pub trait DvcSender: Send {
+    fn send(&mut self, data: &[u8]) -> io::Result<()>;
+
+    fn close(&mut self) -> io::Result<()>;
+}
+
+/// Receiving half of a split [`DvcTransport`].
+pub trait DvcReceiver: Send {
+    /// Block until a full message has been received.
+    fn recv(&mut self) -> io::Result<Vec<u8>>;
+}
+
+impl<T: DvcTransport + ?Sized> DvcTransport for Box<T> {
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        (**self).send(data)
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        (**self).recv()
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        (**self).close()
+    }
+
+    fn set_timeouts(&mut self, timeouts: Timeouts) -> io::Result<()> {
+        (**self).set_timeouts(timeouts)
+    }
+
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn DvcSender>, Box<dyn DvcReceiver>)> {
+        (*self).split()
+    }
+}
+
+/// In-memory transport: messages sent on one end are received on the other.
+///
+/// Sending never blocks, so only the read timeout applies.
+#[derive(Debug)]
+pub struct Loopback {
+    tx: Option<Sender<Vec<u8>>>,
+    rx: Receiver<Vec<u8>>,
+    read_timeout: Option<Duration>,
+}
+
+impl Loopback {
+    /// Create two connected ends.
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, b_rx) = mpsc::channel();
+        let (b_tx, a_rx) = mpsc::channel();
+
+        (
+            Self {
+                tx: Some(a_tx),
+                rx: a_rx,
+                read_timeout: None,
+            },
+            Self {
+                tx: Some(b_tx),
+                rx: b_rx,
+                read_timeout: None,
+            },
+        )
+    }
+}
+
+impl DvcTransport for Loopback {
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        let tx = self
+            .tx
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "channel closed"))?;
+
+        tx.send(data.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "peer closed the channel"))
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        receive(&self.rx, self.read_timeout)
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.tx = None;
+        Ok(())
+    }
+
+    fn set_timeouts(&mut self, timeouts: Timeouts) -> io::Result<()> {
+        self.read_timeout = timeouts.read;
+        Ok(())
+    }
+
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn DvcSender>, Box<dyn DvcReceiver>)> {
+        let Loopback {
+            tx,
+            rx,
+            read_timeout,
+        } = *self;
+        Ok((
+            Box::new(LoopbackSender(Loopback {
+                tx,
+                rx: mpsc::channel().1,
+                read_timeout: None,
+            })),
+            Box::new(LoopbackReceiver { rx, read_timeout }),
+        ))
+    }
+}
+
+fn receive(rx: &Receiver<Vec<u8>>, timeout: Option<Duration>) -> io::Result<Vec<u8>> {
+    let closed = || io::Error::new(io::ErrorKind::UnexpectedEof, "peer closed the channel");
+    match timeout {
+        None => rx.recv().map_err(|_| closed()),
+        Some(timeout) => rx.recv_timeout(timeout).map_err(|err| match err {
+            RecvTimeoutError::Timeout => io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no message received within {timeout:?}"),
+            ),
+            RecvTimeoutError::Disconnected => closed(),
+        }),
+    }
+}
+
+struct LoopbackSender(Loopback);
+
+impl DvcSender for LoopbackSender {
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.send(data)
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.0.close()
+    }
+}
+
+struct LoopbackReceiver {
+    rx: Receiver<Vec<u8>>,
+    read_timeout: Option<Duration>,
+}
+
+impl DvcReceiver for LoopbackReceiver {
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        receive(&self.rx, self.read_timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loopback_exchange() {
+        let (mut a, mut b) = Loopback::pair();
+
+        a.send(b"ping").unwrap();
+        assert_eq!(b.recv().unwrap(), b"ping");
+
+        b.send(b"pong").unwrap();
+        assert_eq!(a.recv().unwrap(), b"pong");
+    }
+
+    #[test]
+    fn loopback_close() {
+        let (mut a, mut b) = Loopback::pair();
+
+        a.send(b"last").unwrap();
+        a.close().unwrap();
+
+        assert_eq!(b.recv().unwrap(), b"last");
+        assert_eq!(b.recv().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(a.send(b"").unwrap_err().kind(), io::ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn loopback_split() {
+        let (a, mut b) = Loopback::pair();
+        let (mut sender, mut receiver) = (Box::new(a) as Box<dyn DvcTransport>).split().unwrap();
+
+        let reader = std::thread::spawn(move || receiver.recv());
+        b.send(b"ping").unwrap();
+        assert_eq!(reader.join().unwrap().unwrap(), b"ping");
+
+        sender.send(b"pong").unwrap();
+        assert_eq!(b.recv().unwrap(), b"pong");
+        sender.close().unwrap();
+        assert_eq!(b.recv().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn loopback_timeout() {
+        let (mut a, mut b) = Loopback::pair();
+        a.set_timeouts(Timeouts {
+            read: Some(Duration::from_millis(10)),
+            write: None,
+        })
+        .unwrap();
+
+        assert_eq!(a.recv().unwrap_err().kind(), io::ErrorKind::TimedOut);
+        b.send(b"late").unwrap();
+        assert_eq!(a.recv().unwrap(), b"late");
+
+        let (_, mut receiver) = (Box::new(a) as Box<dyn DvcTransport>).split().unwrap();
+        assert_eq!(receiver.recv().unwrap_err().kind(), io::ErrorKind::TimedOut);
+        drop(b);
+        assert_eq!(
+            receiver.recv().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
@@ -0,0 +1,297 @@
+//! Round-trip latency and throughput measurement over any transport.
+
+use std::{
+    fmt::Write,
+    io,
+    time::{Duration, Instant},
+};
+
+use echo_dvc_proto::{CHANNEL_CHUNK_LENGTH, DvcTransport};
+use serde_json::{Value, json};
+
+/// Message sizes used when none are given: from a single small chunk to
+/// messages split across several chunks.
+pub const DEFAULT_SIZES: &[usize] = &[
+    64,
+    CHANNEL_CHUNK_LENGTH,
+    CHANNEL_CHUNK_LENGTH + 1,
+    16 * 1024,
+];
+
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    /// Messages sent for each size.
+    pub count: usize,
+    pub sizes: Vec<usize>,
+    /// Messages sent for each size before measuring.
+    pub warmup: usize,
+}
+
+/// Round-trip time distribution.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencyStats {
+    pub min: Duration,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencyStats {
+    /// Statistics of `samples`, `None` when there is none.
+    pub fn from_samples(samples: &[Duration]) -> Option<Self> {
+        let mut sorted = samples.to_vec();
+        sorted.sort_unstable();
+
+        let total: Duration = sorted.iter().sum();
+        Some(Self {
+            min: *sorted.first()?,
+            mean: total / u32::try_from(sorted.len()).ok()?,
+            p50: percentile(&sorted, 50.0),
+            p90: percentile(&sorted, 90.0),
+            p99: percentile(&sorted, 99.0),
+            max: *sorted.last()?,
+        })
+    }
+}
+
+/// Nearest-rank percentile of a sorted, non empty slice.
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Results for one message size.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SizeReport {
+    pub size: usize,
+    pub count: usize,
+    pub elapsed: Duration,
+    pub latency: LatencyStats,
+}
+
+impl SizeReport {
+    /// Payload bytes echoed per second, counting each message once.
+    pub fn throughput(&self) -> f64 {
+        (self.size * self.count) as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn messages_per_second(&self) -> f64 {
+        self.count as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Send `config.count` messages of each size one after the other, waiting
+/// for each echo. A reply that differs from the message is an error.
+pub fn run_bench(
+    transport: &mut dyn DvcTransport,
+    config: &BenchConfig,
+) -> io::Result<Vec<SizeReport>> {
+    if config.count == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "message count must be at least 1",
+        ));
+    }
+
+    let mut reports = Vec::with_capacity(config.sizes.len());
+    for &size in &config.sizes {
+        let message: Vec<u8> = (0..size).map(|i| i as u8).collect();
+
+        for _ in 0..config.warmup {
+            round_trip(transport, &message)?;
+        }
+
+        let mut samples = Vec::with_capacity(config.count);
+        let start = Instant::now();
+        for _ in 0..config.count {
+            samples.push(round_trip(transport, &message)?);
+        }
+        let elapsed = start.elapsed();
+
+        reports.push(SizeReport {
+            size,
+            count: config.count,
+            elapsed,
+            latency: LatencyStats::from_samples(&samples).expect("count is not zero"),
+        });
+    }
+
+    Ok(reports)
+}
+
+fn round_trip(transport: &mut dyn DvcTransport, message: &[u8]) -> io::Result<Duration> {
+    let start = Instant::now();
+    transport.send(message)?;
+    let reply = transport.recv()?;
+    let rtt = start.elapsed();
+
+    if reply != message {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "echo mismatch: sent {} bytes, received {} bytes",
+                message.len(),
+                reply.len()
+            ),
+        ));
+    }
+    Ok(rtt)
+}
+
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1e6
+}
+
+pub fn render_table(reports: &[SizeReport]) -> String {
+    let mut table = format!(
+        "{:>8} {:>6} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>12} {:>10}\n",
+        "size",
+        "count",
+        "min(us)",
+        "mean(us)",
+        "p50(us)",
+        "p90(us)",
+        "p99(us)",
+        "max(us)",
+        "KiB/s",
+        "msg/s"
+    );
+    for report in reports {
+        let latency = &report.latency;
+        let _ = writeln!(
+            table,
+            "{:>8} {:>6} {:>10.1} {:>10.1} {:>10.1} {:>10.1} {:>10.1} {:>10.1} {:>12.1} {:>10.1}",
+            report.size,
+            report.count,
+            micros(latency.min),
+            micros(latency.mean),
+            micros(latency.p50),
+            micros(latency.p90),
+            micros(latency.p99),
+            micros(latency.max),
+            report.throughput() / 1024.0,
+            report.messages_per_second(),
+        );
+    }
+    table
+}
+
+pub fn render_json(reports: &[SizeReport]) -> Value {
+    let results: Vec<_> = reports
+        .iter()
+        .map(|report| {
+            let latency = &report.latency;
+            json!({
+                "size": report.size,
+                "count": report.count,
+                "elapsed_us": micros(report.elapsed),
+                "latency_us": {
+                    "min": micros(latency.min),
+                    "mean": micros(latency.mean),
+                    "p50": micros(latency.p50),
+                    "p90": micros(latency.p90),
+                    "p99": micros(latency.p99),
+                    "max": micros(latency.max),
+                },
+                "bytes_per_second": report.throughput(),
+                "messages_per_second": report.messages_per_second(),
+            })
+        })
+        .collect();
+
+    json!({ "results": results })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use echo_dvc_proto::test_util::echo_peer;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn latency_stats() {
+        let samples: Vec<_> = (1..=100).rev().map(ms).collect();
+        let stats = LatencyStats::from_samples(&samples).unwrap();
+
+        assert_eq!(
+            stats,
+            LatencyStats {
+                min: ms(1),
+                mean: Duration::from_micros(50_500),
+                p50: ms(50),
+                p90: ms(90),
+                p99: ms(99),
+                max: ms(100),
+            }
+        );
+    }
+
+    #[test]
+    fn latency_stats_edge_cases() {
+        assert_eq!(LatencyStats::from_samples(&[]), None);
+
+        let single = LatencyStats::from_samples(&[ms(7)]).unwrap();
+        assert_eq!(
+            (single.min, single.p50, single.p99, single.max),
+            (ms(7), ms(7), ms(7), ms(7))
+        );
+    }
+
+    #[test]
+    fn throughput() {
+        let report = SizeReport {
+            size: 1000,
+            count: 50,
+            elapsed: ms(500),
+            latency: LatencyStats::from_samples(&[ms(10)]).unwrap(),
+        };
+        assert_eq!(report.throughput(), 100_000.0);
+        assert_eq!(report.messages_per_second(), 100.0);
+    }
+
+    #[test]
+    fn bench_over_loopback() {
+        let mut transport = echo_peer(|data| data);
+        let config = BenchConfig {
+            count: 20,
+            sizes: DEFAULT_SIZES.to_vec(),
+            warmup: 2,
+        };
+
+        let reports = run_bench(&mut transport, &config).unwrap();
+
+        assert_eq!(reports.len(), DEFAULT_SIZES.len());
+        for (report, &size) in reports.iter().zip(DEFAULT_SIZES) {
+            assert_eq!((report.size, report.count), (size, 20));
+            assert!(report.latency.min <= report.latency.p50);
+            assert!(report.latency.p99 <= report.latency.max);
+            assert!(report.latency.max <= report.elapsed);
+        }
+
+        let table = render_table(&reports);
+        assert_eq!(table.lines().count(), reports.len() + 1);
+        let json = render_json(&reports);
+        assert_eq!(json["results"][2]["size"], CHANNEL_CHUNK_LENGTH + 1);
+    }
+
+    #[test]
+    fn bench_detects_corrupted_echo() {
+        let mut transport = echo_peer(|mut data| {
+            data.pop();
+            data
+        });
+        let config = BenchConfig {
+            count: 1,
+            sizes: vec![10],
+            warmup: 0,
+        };
+
+        let err = run_bench(&mut transport, &config).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,113 @@
+//! Stand-in for the client plugin: serves the channels carried over the TCP
+//! transport of `echo_dvc_server` with one of the plugin handlers, the echo
+//! one by default.
+
+use std::{
+    io,
+    net::{TcpListener, TcpStream},
+    process::exit,
+    sync::Arc,
+    thread,
+};
+
+use clap::Parser;
+use echo_dvc_proto::{ChannelListener, DVC_NAME, ListenerConfig, StreamTransport};
+use log::{error, info};
+use simplelog::Config;
+
+const LISTEN_ADDRESS_DEFAULT: &str = "127.0.0.1:3390";
+
+#[derive(Parser)]
+#[command(name = "echo_dvc_listener")]
+struct Cli {
+    #[arg(short, long, help = "enable debug logs")]
+    verbose: bool,
+    #[arg(
+        long,
+        default_value = "echo",
+        help = "handler serving the channels, as named in the plugin configuration"
+    )]
+    handler: String,
+    #[arg(
+        short,
+        long = "option",
+        value_name = "KEY=VALUE",
+        value_parser = parse_option,
+        help = "option of the handler, e.g. connect=127.0.0.1:*, can be repeated"
+    )]
+    options: Vec<(String, String)>,
+    #[arg(default_value = LISTEN_ADDRESS_DEFAULT, help = "address to listen on")]
+    address: String,
+}
+
+fn parse_option(arg: &str) -> Result<(String, String), String> {
+    arg.split_once('=')
+        .map(|(key, value)| (key.trim().to_string(), value.to_string()))
+        .ok_or_else(|| format!("invalid option: {arg}, expected KEY=VALUE"))
+}
+
+fn init_logs(verbose: bool) {
+    let level = if verbose {
+        log::LevelFilter::Debug
+    } else {
+        log::LevelFilter::Info
+    };
+
+    let _ = simplelog::TermLogger::init(
+        level,
+        Config::default(),
+        simplelog::TerminalMode::Mixed,
+        simplelog::ColorChoice::Auto,
+    );
+}
+
+fn main() {
+    let opts = Cli::parse();
+    init_logs(opts.verbose);
+
+    let config = [("handler".to_string(), opts.handler)]
+        .into_iter()
+        .chain(opts.options);
+    let channels = match ListenerConfig::from_values(DVC_NAME, config) {
+        Ok(config) => ChannelListener::new(config),
+        Err(err) => {
+            error!("invalid handler: {err}");
+            exit(1);
+        }
+    };
+
+    let listener = match TcpListener::bind(&opts.address) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("failed to listen on {}: {err}", opts.address);
+            exit(1);
+        }
+    };
+
+    info!("listening on {}", opts.address);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let channels = Arc::clone(&channels);
+                thread::spawn(move || {
+                    let peer = stream
+                        .peer_addr()
+                        .map_or_else(|_| "unknown peer".to_string(), |addr| addr.to_string());
+                    match serve(&channels, stream) {
+                        Ok(_) => info!("{peer} disconnected"),
+                        Err(err) => error!("{peer}: {err}"),
+                    }
+                });
+            }
+            Err(err) => error!("failed to accept connection: {err}"),
+        }
+    }
+}
+
+fn serve(channels: &Arc<ChannelListener>, stream: TcpStream) -> io::Result<()> {
+    info!("new connection from {}", stream.peer_addr()?);
+    stream.set_nodelay(true)?;
+
+    channels.serve(Box::new(StreamTransport::new(stream)))
+}
@@ -0,0 +1,182 @@
+//! Commands of the prompt and of scripts.
+//!
+//! Every command is declared once in [`COMMANDS`], from which lines are
+//! parsed, the help is generated and command names are completed.
+
+use std::fmt::Write;
+
+use log::debug;
+
+use crate::payload::Payload;
+
+/// Command read from the prompt or from a script.
+#[derive(Debug, PartialEq)]
+pub enum Command<'a> {
+    Empty,
+    Help,
+    Quit,
+    /// Wait for the next message, with an optional timeout.
+    Recv(&'a str),
+    Write(Payload<'a>),
+    /// Write, then check that the reply is the same payload.
+    Verify(Payload<'a>),
+    Invalid(&'a str),
+}
+
+pub struct CommandSpec {
+    /// Name of the command, then its aliases.
+    pub names: &'static [&'static str],
+    /// Placeholder of the argument, empty when there is none. `PATH`
+    /// arguments are completed as file names.
+    pub arg: &'static str,
+    pub help: &'static str,
+    build: for<'a> fn(&'a str) -> Command<'a>,
+}
+
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        names: &["write", "put"],
+        arg: "TEXT",
+        help: "write TEXT to the DVC",
+        build: |arg| Command::Write(Payload::Text(arg)),
+    },
+    CommandSpec {
+        names: &["writehex"],
+        arg: "HEX",
+        help: "write bytes given in hexadecimal, e.g. DEADBEEF",
+        build: |arg| Command::Write(Payload::Hex(arg)),
+    },
+    CommandSpec {
+        names: &["writeb64"],
+        arg: "BASE64",
+        help: "write bytes given in base64, e.g. 3q2+7w==",
+        build: |arg| Command::Write(Payload::Base64(arg)),
+    },
+    CommandSpec {
+        names: &["sendfile"],
+        arg: "PATH",
+        help: "write the content of a file",
+        build: |arg| Command::Write(Payload::File(arg.trim())),
+    },
+    CommandSpec {
+        names: &["verify", "check"],
+        arg: "TEXT",
+        help: "write TEXT and check that it is echoed back",
+        build: |arg| Command::Verify(Payload::Text(arg)),
+    },
+    CommandSpec {
+        names: &["recv", "read"],
+        arg: "[TIMEOUT]",
+        help: "show the next message received, waiting up to TIMEOUT (e.g. 500ms, 2s)",
+        build: |arg| Command::Recv(arg.trim()),
+    },
+    CommandSpec {
+        names: &["help", "?"],
+        arg: "",
+        help: "show this help",
+        build: |_| Command::Help,
+    },
+    CommandSpec {
+        names: &["quit", "exit"],
+        arg: "",
+        help: "leave this interface",
+        build: |_| Command::Quit,
+    },
+];
+
+impl<'a> Command<'a> {
+    pub fn parse(line: &'a str) -> Self {
+        let line = line.trim();
+        let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+
+        debug!("command: {command}");
+        debug!("arg: {arg}");
+
+        if command.is_empty() {
+            return Self::Empty;
+        }
+
+        COMMANDS
+            .iter()
+            .find(|spec| {
+                spec.names
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(command))
+            })
+            .map_or(Self::Invalid(command), |spec| (spec.build)(arg))
+    }
+}
+
+/// Help listing every command of [`COMMANDS`].
+pub fn help() -> String {
+    let usages: Vec<String> = COMMANDS
+        .iter()
+        .map(|spec| match spec.arg {
+            "" => spec.names.join(", "),
+            arg => format!("{} {arg}", spec.names.join(", ")),
+        })
+        .collect();
+    let width = usages.iter().map(String::len).max().unwrap_or_default();
+
+    let mut help = String::from("Commands:\n");
+    for (usage, spec) in usages.iter().zip(COMMANDS) {
+        let _ = writeln!(help, "  {usage:width$}  {}", spec.help);
+    }
+    help
+}
+
+/// Names of the commands starting with `prefix`, aliases included.
+pub fn complete(prefix: &str) -> Vec<&'static str> {
+    COMMANDS
+        .iter()
+        .flat_map(|spec| spec.names)
+        .copied()
+        .filter(|name| {
+            name.len() >= prefix.len() && name[..prefix.len()].eq_ignore_ascii_case(prefix)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Command::parse("  \n"), Command::Empty);
+        assert_eq!(
+            Command::parse("WRITE hello world\n"),
+            Command::Write(Payload::Text("hello world"))
+        );
+        assert_eq!(Command::parse("put x"), Command::Write(Payload::Text("x")));
+        assert_eq!(
+            Command::parse("sendfile  /tmp/a b "),
+            Command::Write(Payload::File("/tmp/a b"))
+        );
+        assert_eq!(
+            Command::parse("Check ok"),
+            Command::Verify(Payload::Text("ok"))
+        );
+        assert_eq!(Command::parse("recv 2s"), Command::Recv("2s"));
+        assert_eq!(Command::parse("?"), Command::Help);
+        assert_eq!(Command::parse("exit"), Command::Quit);
+        assert_eq!(Command::parse("nope arg"), Command::Invalid("nope"));
+    }
+
+    #[test]
+    fn help_lists_every_command() {
+        let help = help();
+
+        assert_eq!(help.lines().count(), COMMANDS.len() + 1);
+        assert!(help.contains("\n  write, put TEXT       write TEXT to the DVC\n"));
+        assert!(help.contains("\n  quit, exit            leave this interface\n"));
+    }
+
+    #[test]
+    fn completion() {
+        assert_eq!(complete("wri"), ["write", "writehex", "writeb64"]);
+        assert_eq!(complete("E"), ["exit"]);
+        assert_eq!(complete("nope"), Vec::<&str>::new());
+        assert_eq!(complete("").len(), 13);
+    }
+}
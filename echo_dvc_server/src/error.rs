@@ -0,0 +1,141 @@
+//! Errors of the channel operations and exit status of the server.
+
+use std::{fmt, io};
+
+use echo_dvc_proto::Error;
+
+/// Channel operation which failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+/// Failed channel operation.
+///
+/// Carried by the [`io::Error`]s of the prompt and of the scripts, so that
+/// the cause of a failure can still be told apart once it is reported.
+#[derive(Debug)]
+pub struct ChannelError {
+    pub operation: Operation,
+    pub source: Error,
+}
+
+impl ChannelError {
+    /// `err` of `operation`, as an [`io::Error`] of the same kind.
+    pub fn wrap(operation: Operation, err: io::Error) -> io::Error {
+        let source = Error::from(err);
+        io::Error::new(source.kind(), ChannelError { operation, source })
+    }
+}
+
+impl fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.operation {
+            Operation::Read => write!(f, "error reading from channel: {}", self.source),
+            Operation::Write => write!(f, "error writing to channel: {}", self.source),
+        }
+    }
+}
+
+impl std::error::Error for ChannelError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Exit status of the server. The values are stable, scripts can rely on
+/// them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitStatus {
+    /// Every command passed.
+    Passed = 0,
+    /// Any error not listed below, e.g. a script which cannot be read.
+    Error = 1,
+    /// A reply differed from what was written, or a command was invalid.
+    Failed = 2,
+    /// The channel could not be opened.
+    OpenFailed = 3,
+    /// The channel was closed before the end.
+    ChannelClosed = 4,
+    /// The client did not reply, or did not take a write, in time.
+    Timeout = 5,
+    /// The client sent data which are not valid channel chunks or messages,
+    /// or refused the handshake.
+    Protocol = 6,
+}
+
+impl ExitStatus {
+    /// Status of a run which stopped on `err`.
+    pub fn of(err: io::Error) -> Self {
+        let err = if err
+            .get_ref()
+            .is_some_and(|inner| inner.is::<ChannelError>())
+        {
+            let inner = err
+                .into_inner()
+                .and_then(|inner| inner.downcast::<ChannelError>().ok());
+            inner.expect("inner error checked above").source
+        } else {
+            Error::from(err)
+        };
+
+        match err {
+            Error::Timeout(_) => Self::Timeout,
+            Error::ChannelClosed(_) => Self::ChannelClosed,
+            Error::Handshake(_) => Self::Protocol,
+            err if err.is_framing() => Self::Protocol,
+            _ => Self::Error,
+        }
+    }
+
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrapped_errors() {
+        let err = ChannelError::wrap(
+            Operation::Read,
+            io::Error::new(io::ErrorKind::TimedOut, "read timed out"),
+        );
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            err.to_string(),
+            "error reading from channel: read timed out"
+        );
+        assert_eq!(ExitStatus::of(err), ExitStatus::Timeout);
+
+        let framing = io::Error::from(Error::NotAPduHeader(2));
+        let err = ChannelError::wrap(Operation::Read, framing);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(ExitStatus::of(err), ExitStatus::Protocol);
+    }
+
+    #[test]
+    fn exit_statuses() {
+        let closed = io::Error::new(io::ErrorKind::BrokenPipe, "peer closed the channel");
+        assert_eq!(
+            ExitStatus::of(ChannelError::wrap(Operation::Write, closed)),
+            ExitStatus::ChannelClosed
+        );
+        assert_eq!(
+            ExitStatus::of(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            ExitStatus::ChannelClosed
+        );
+        assert_eq!(
+            ExitStatus::of(io::Error::from(io::ErrorKind::PermissionDenied)),
+            ExitStatus::Error
+        );
+        assert_eq!(
+            ExitStatus::of(Error::Handshake("refused".to_string()).into()),
+            ExitStatus::Protocol
+        );
+        assert_eq!(ExitStatus::Protocol.code(), 6);
+    }
+}
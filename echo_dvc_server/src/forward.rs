@@ -0,0 +1,322 @@
+//! TCP forwarding through the streams of the channel.
+//!
+//! Local forwards listen in the remote session and carry each connection to
+//! a destination reached by the client. Remote forwards do the opposite: the
+//! client listens and the server connects to the destination.
+
+use std::{
+    io,
+    net::{TcpListener, TcpStream},
+    str::FromStr,
+    thread,
+};
+
+use echo_dvc_proto::{
+    Mux, MuxStream, PendingStream, Refusal, Target, pipe, read_line, split_host_port,
+};
+use log::{debug, info, warn};
+
+/// Address the forwards listen on when none is given.
+const DEFAULT_BIND: &str = "127.0.0.1";
+
+/// `[BIND:]PORT:HOST:HOSTPORT` argument: listen on `BIND:PORT` and forward
+/// the connections to `HOST:HOSTPORT`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Forward {
+    pub bind: String,
+    pub port: u16,
+    pub host: String,
+    pub host_port: u16,
+}
+
+impl FromStr for Forward {
+    type Err = String;
+
+    fn from_str(arg: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid forward: {arg}, expected [BIND:]PORT:HOST:HOSTPORT");
+
+        let (listen, destination) = match split_colons(arg).as_slice() {
+            [port, host, host_port] => (
+                format!("{DEFAULT_BIND}:{port}"),
+                format!("{host}:{host_port}"),
+            ),
+            [bind, port, host, host_port] => {
+                (format!("{bind}:{port}"), format!("{host}:{host_port}"))
+            }
+            _ => return Err(invalid()),
+        };
+        let (bind, port) = split_host_port(&listen).ok_or_else(invalid)?;
+        let (host, host_port) = split_host_port(&destination).ok_or_else(invalid)?;
+
+        Ok(Self {
+            bind,
+            port,
+            host,
+            host_port,
+        })
+    }
+}
+
+/// Parts of `arg` between the colons which are not within brackets.
+fn split_colons(arg: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut bracketed) = (0, false);
+    for (i, c) in arg.char_indices() {
+        match c {
+            '[' => bracketed = true,
+            ']' => bracketed = false,
+            ':' if !bracketed => {
+                parts.push(&arg[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&arg[start..]);
+    parts
+}
+
+/// Forwards running over a channel.
+pub struct Forwarder {
+    mux: Mux,
+    remote: Vec<RemoteForward>,
+    /// Where each forward listens and where it leads, for display.
+    routes: Vec<String>,
+}
+
+/// Forward listening on the client.
+struct RemoteForward {
+    /// `listen` stream, the client listening as long as it is open.
+    control: MuxStream,
+    host: String,
+    port: u16,
+}
+
+impl Forwarder {
+    /// Start listening for every forward. Fails if any cannot listen.
+    pub fn start(mux: Mux, local: &[Forward], remote: &[Forward]) -> io::Result<Self> {
+        let mut forwarder = Self {
+            mux,
+            remote: Vec::new(),
+            routes: Vec::new(),
+        };
+
+        for forward in remote {
+            let target = Target::Listen {
+                host: forward.bind.clone(),
+                port: forward.port,
+            };
+            let control = forwarder.mux.open(&target.to_string())?;
+            let address = read_line(&control)?;
+
+            forwarder.routes.push(format!(
+                "client {address} -> {}:{}",
+                forward.host, forward.host_port
+            ));
+            forwarder.remote.push(RemoteForward {
+                control,
+                host: forward.host.clone(),
+                port: forward.host_port,
+            });
+        }
+
+        for forward in local {
+            let listener = TcpListener::bind((forward.bind.as_str(), forward.port))?;
+            let target = Target::Tcp {
+                host: forward.host.clone(),
+                port: forward.host_port,
+            };
+            forwarder.routes.push(format!(
+                "{} -> client {}:{}",
+                listener.local_addr()?,
+                forward.host,
+                forward.host_port
+            ));
+
+            let mux = forwarder.mux.clone();
+            thread::spawn(move || accept_local(listener, mux, target.to_string()));
+        }
+
+        Ok(forwarder)
+    }
+
+    pub fn routes(&self) -> &[String] {
+        &self.routes
+    }
+
+    /// Carry the connections of the remote forwards until the channel
+    /// closes, which is reported as an error.
+    pub fn serve(self) -> io::Result<()> {
+        loop {
+            let pending = self.mux.accept()?;
+            let destination = match pending.target().parse() {
+                Ok(Target::Connection(id)) => self
+                    .remote
+                    .iter()
+                    .find(|forward| forward.control.id() == id)
+                    .map(|forward| (forward.host.clone(), forward.port)),
+                _ => None,
+            };
+
+            let Some((host, port)) = destination else {
+                let reason = Refusal::UnknownTarget.reason(pending.target());
+                if let Err(err) = pending.refuse(&reason) {
+                    debug!("failed to refuse a stream: {err}");
+                }
+                continue;
+            };
+            thread::spawn(move || {
+                if let Err(err) = connect_remote(pending, &host, port) {
+                    debug!("forward to {host}:{port}: {err}");
+                }
+            });
+        }
+    }
+}
+
+/// Carry each connection accepted on `listener` to `target`.
+fn accept_local(listener: TcpListener, mux: Mux, target: String) {
+    for socket in listener.incoming() {
+        let socket = match socket {
+            Ok(socket) => socket,
+            Err(err) => {
+                warn!("failed to accept a connection: {err}");
+                continue;
+            }
+        };
+
+        let (mux, target) = (mux.clone(), target.clone());
+        thread::spawn(move || {
+            let peer = socket.peer_addr().map(|peer| peer.to_string());
+            let peer = peer.unwrap_or_default();
+            match mux.open(&target) {
+                Ok(stream) => {
+                    info!("{peer}: forwarded to the client {target}");
+                    if let Err(err) = pipe(stream, socket) {
+                        debug!("{peer}: {err}");
+                    }
+                }
+                Err(err) => warn!("{peer}: {err}"),
+            }
+        });
+    }
+}
+
+fn connect_remote(pending: PendingStream, host: &str, port: u16) -> io::Result<()> {
+    let socket = match TcpStream::connect((host, port)) {
+        Ok(socket) => socket,
+        Err(err) => {
+            let reason = Refusal::ConnectFailed.reason(format!("{host}:{port}: {err}"));
+            return pending.refuse(&reason);
+        }
+    };
+    info!("connection of the client forwarded to {host}:{port}");
+    pipe(pending.accept()?, socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use echo_dvc_proto::{
+        ChannelOptions, ForwardPolicy,
+        test_util::{message_mux, tcp_echo},
+    };
+    use std::{
+        io::{Read, Write},
+        net::Shutdown,
+    };
+
+    fn forward(bind: &str, port: u16, host: &str, host_port: u16) -> Forward {
+        Forward {
+            bind: bind.to_owned(),
+            port,
+            host: host.to_owned(),
+            host_port,
+        }
+    }
+
+    /// Server side mux, talking to a client allowed to reach and listen on
+    /// the loopback.
+    fn server_mux() -> Mux {
+        let options = ChannelOptions::from([
+            ("connect".to_owned(), "127.0.0.1:*".to_owned()),
+            ("listen".to_owned(), "127.0.0.1:*".to_owned()),
+        ]);
+        message_mux(ForwardPolicy::from_options("message", &options).unwrap())
+    }
+
+    fn exchange(address: &str, data: &str) -> String {
+        let mut socket = TcpStream::connect(address).unwrap();
+        socket.write_all(data.as_bytes()).unwrap();
+        socket.shutdown(Shutdown::Write).unwrap();
+        let mut received = String::new();
+        socket.read_to_string(&mut received).unwrap();
+        received
+    }
+
+    #[test]
+    fn forward_args() {
+        assert_eq!(
+            "8080:db:5432".parse(),
+            Ok(forward("127.0.0.1", 8080, "db", 5432))
+        );
+        assert_eq!(
+            "0.0.0.0:22:10.0.0.2:22".parse(),
+            Ok(forward("0.0.0.0", 22, "10.0.0.2", 22))
+        );
+        assert_eq!(
+            "[::1]:8080:[fe80::1]:80".parse(),
+            Ok(forward("::1", 8080, "fe80::1", 80))
+        );
+
+        for invalid in [
+            "",
+            "8080",
+            "8080:db",
+            "db:5432",
+            "x:8080:db:5432:1",
+            "8080:[db:1",
+        ] {
+            assert!(invalid.parse::<Forward>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn local_and_remote_forwards() {
+        let (local_destination, remote_destination) = (tcp_echo(), tcp_echo());
+        let forwarder = Forwarder::start(
+            server_mux(),
+            &[forward(
+                "127.0.0.1",
+                0,
+                "127.0.0.1",
+                local_destination.port(),
+            )],
+            &[forward(
+                "127.0.0.1",
+                0,
+                "127.0.0.1",
+                remote_destination.port(),
+            )],
+        )
+        .unwrap();
+
+        let routes = forwarder.routes().to_vec();
+        assert_eq!(routes.len(), 2);
+        // The listening address, before the arrow.
+        let address = |route: &str| {
+            let (listening, _) = route.split_once(" -> ").unwrap();
+            listening.rsplit(' ').next().unwrap().to_owned()
+        };
+        let (remote, local) = (address(&routes[0]), address(&routes[1]));
+        assert!(routes[0].starts_with("client 127.0.0.1:"));
+        assert!(routes[1].ends_with(&format!("-> client 127.0.0.1:{}", local_destination.port())));
+        thread::spawn(move || forwarder.serve());
+
+        assert_eq!(exchange(&local, "to the client side"), "to the client side");
+        assert_eq!(
+            exchange(&remote, "to the server side"),
+            "to the server side"
+        );
+    }
+}
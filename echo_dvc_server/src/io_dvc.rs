@@ -1,24 +1,310 @@
+use echo_dvc_proto::{
+    DvcReceiver, DvcSender, DvcTransport, PACKET_MAX_LENGTH, Reassembler, Timeouts,
+};
 use log::debug;
-use std::cell;
+use std::{
+    io, ptr,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
 use windows::{
     self as ws,
-    Win32::System::{
-        IO::OVERLAPPED,
-        RemoteDesktop::{CHANNEL_FLAG_FIRST, CHANNEL_FLAG_LAST, CHANNEL_FLAG_MIDDLE},
+    Win32::{
+        Foundation::{ERROR_OPERATION_ABORTED, HANDLE, WAIT_FAILED, WAIT_TIMEOUT},
+        System::{
+            IO::{CancelIoEx, GetOverlappedResult, OVERLAPPED},
+            RemoteDesktop::{
+                WTS_CHANNEL_OPTION_DYNAMIC, WTS_CURRENT_SESSION, WTSFreeMemory,
+                WTSVirtualChannelClose, WTSVirtualChannelOpenEx, WTSVirtualChannelQuery,
+            },
+            Threading::{INFINITE, WaitForSingleObject},
+        },
     },
+    core::PCSTR,
 };
 
-use crate::{PACKET_MAX_LENGTH, PDU_HEADER_LENGTH};
+/// DVC opened through the WTS API of the current remote session.
+pub struct WtsTransport {
+    writer: WtsWriter,
+    reader: WtsReader,
+}
+
+/// Channel handle shared by the reading and writing sides, closed once.
+struct WtsChannel {
+    channel: Mutex<HANDLE>,
+    filehandle: HANDLE,
+}
+
+// The handles can be used from any thread, and `channel` is only closed
+// under the lock.
+unsafe impl Send for WtsChannel {}
+unsafe impl Sync for WtsChannel {}
+
+struct WtsWriter {
+    channel: Arc<WtsChannel>,
+    overlapped: OVERLAPPED,
+    timeout: Option<Duration>,
+}
+
+struct WtsReader {
+    channel: Arc<WtsChannel>,
+    overlapped: OVERLAPPED,
+    timeout: Option<Duration>,
+    /// Kept between reads, so that a message is not lost when a read times
+    /// out halfway.
+    reassembler: Reassembler,
+}
+
+// Each OVERLAPPED is only used by the side owning it.
+unsafe impl Send for WtsWriter {}
+unsafe impl Send for WtsReader {}
+
+impl WtsTransport {
+    pub fn open(channel_name: &str) -> io::Result<Self> {
+        let channel = unsafe {
+            WTSVirtualChannelOpenEx(
+                WTS_CURRENT_SESSION,
+                PCSTR(format!("{channel_name}\0").as_ptr()),
+                WTS_CHANNEL_OPTION_DYNAMIC,
+            )
+        }?;
+
+        if channel.0.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        debug!("channel handle ok: {channel:?}");
+
+        let mut filehandleptr: *mut HANDLE = ptr::null_mut();
+        let filehandleptrptr: *mut *mut HANDLE = &raw mut filehandleptr;
+        let mut len = 0;
+
+        debug!("WTSVirtualChannelQuery");
+        let ret = unsafe {
+            WTSVirtualChannelQuery(
+                channel,
+                ws::Win32::System::RemoteDesktop::WTSVirtualFileHandle,
+                filehandleptrptr.cast(),
+                &raw mut len,
+            )
+        };
+
+        if let Err(err) = ret {
+            let _ = unsafe { WTSVirtualChannelClose(channel) };
+            return Err(err.into());
+        }
+
+        if filehandleptr.is_null() {
+            let err = io::Error::last_os_error();
+            let _ = unsafe { WTSVirtualChannelClose(channel) };
+            return Err(err);
+        }
+
+        let filehandle = unsafe { *filehandleptr };
+        unsafe { WTSFreeMemory(filehandleptr.cast()) };
+        debug!("filehandle: {filehandle:?}");
 
-pub fn write_dvc(
-    filehandle: ws::Win32::Foundation::HANDLE,
+        let read_event = match create_event() {
+            Ok(event) => event,
+            Err(err) => {
+                let _ = unsafe { WTSVirtualChannelClose(channel) };
+                return Err(err);
+            }
+        };
+        let write_event = match create_event() {
+            Ok(event) => event,
+            Err(err) => {
+                let _ = unsafe { ws::Win32::Foundation::CloseHandle(read_event) };
+                let _ = unsafe { WTSVirtualChannelClose(channel) };
+                return Err(err);
+            }
+        };
+
+        let channel = Arc::new(WtsChannel {
+            channel: Mutex::new(channel),
+            filehandle,
+        });
+        Ok(Self {
+            writer: WtsWriter {
+                channel: Arc::clone(&channel),
+                overlapped: overlapped(write_event),
+                timeout: None,
+            },
+            reader: WtsReader {
+                channel,
+                overlapped: overlapped(read_event),
+                timeout: None,
+                reassembler: Reassembler::new(),
+            },
+        })
+    }
+}
+
+fn create_event() -> io::Result<HANDLE> {
+    let event = unsafe {
+        ws::Win32::System::Threading::CreateEventA(Some(ptr::null()), false, false, PCSTR::null())
+    }?;
+
+    if event.0.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(event)
+}
+
+fn overlapped(event: HANDLE) -> OVERLAPPED {
+    OVERLAPPED {
+        Internal: 0,
+        InternalHigh: 0,
+        Anonymous: ws::Win32::System::IO::OVERLAPPED_0 {
+            Pointer: ptr::null_mut(),
+        },
+        hEvent: event,
+    }
+}
+
+impl DvcTransport for WtsTransport {
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.writer.send(data)
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        self.reader.recv()
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.writer.close()
+    }
+
+    fn set_timeouts(&mut self, timeouts: Timeouts) -> io::Result<()> {
+        self.reader.timeout = timeouts.read;
+        self.writer.timeout = timeouts.write;
+        Ok(())
+    }
+
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn DvcSender>, Box<dyn DvcReceiver>)> {
+        let WtsTransport { writer, reader } = *self;
+        Ok((Box::new(writer), Box::new(reader)))
+    }
+}
+
+impl WtsChannel {
+    /// Close the channel, which also fails the I/O pending on `filehandle`.
+    fn close(&self) -> io::Result<()> {
+        let mut channel = self.channel.lock().unwrap_or_else(PoisonError::into_inner);
+        if channel.0.is_null() {
+            return Ok(());
+        }
+
+        debug!("WTSVirtualChannelClose");
+        let ret = unsafe { WTSVirtualChannelClose(*channel) };
+        *channel = HANDLE(ptr::null_mut());
+
+        ret.map_err(io::Error::from)
+    }
+}
+
+impl Drop for WtsChannel {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+impl DvcSender for WtsWriter {
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        write_dvc(
+            self.channel.filehandle,
+            data,
+            &mut self.overlapped,
+            self.timeout,
+        )
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.channel.close()
+    }
+}
+
+impl DvcReceiver for WtsReader {
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        read_dvc(
+            self.channel.filehandle,
+            &mut self.overlapped,
+            &mut self.reassembler,
+            self.timeout,
+        )
+    }
+}
+
+impl Drop for WtsWriter {
+    fn drop(&mut self) {
+        let _ = unsafe { ws::Win32::Foundation::CloseHandle(self.overlapped.hEvent) };
+    }
+}
+
+impl Drop for WtsReader {
+    fn drop(&mut self) {
+        let _ = unsafe { ws::Win32::Foundation::CloseHandle(self.overlapped.hEvent) };
+    }
+}
+
+/// Wait up to `timeout` for the I/O pending on `overlapped`, and cancel it
+/// if it does not complete in time. Returns the number of bytes transferred.
+fn wait_overlapped(
+    filehandle: HANDLE,
+    overlapped: &OVERLAPPED,
+    timeout: Option<Duration>,
+    operation: &str,
+) -> io::Result<u32> {
+    // Rounded up, so that a timeout below a millisecond still waits.
+    let millis = timeout.map_or(INFINITE, |timeout| {
+        u32::try_from(timeout.as_nanos().div_ceil(1_000_000)).unwrap_or(INFINITE - 1)
+    });
+
+    debug!("{operation} WaitForSingleObject");
+    let ret = unsafe { WaitForSingleObject(overlapped.hEvent, millis) };
+    if ret == WAIT_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    let cancelled = ret == WAIT_TIMEOUT;
+    if cancelled {
+        debug!("{operation} CancelIoEx");
+        // Fails if the I/O completed in the meantime, which is then reported
+        // below.
+        let _ = unsafe { CancelIoEx(filehandle, Some(&raw const *overlapped)) };
+    }
+
+    // Even cancelled, the I/O must be over before the OVERLAPPED and its
+    // buffer are reused.
+    let mut transferred = 0;
+    let ret = unsafe {
+        GetOverlappedResult(
+            filehandle,
+            &raw const *overlapped,
+            &raw mut transferred,
+            true,
+        )
+    };
+
+    match ret {
+        Ok(()) => Ok(transferred),
+        Err(err) if cancelled && err.code() == ERROR_OPERATION_ABORTED.to_hresult() => Err(
+            io::Error::new(io::ErrorKind::TimedOut, format!("{operation} timed out")),
+        ),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Unlike reads, writes are not framed: the DVC handle splits the message into
+/// chunks itself and the client receives it whole.
+fn write_dvc(
+    filehandle: HANDLE,
     data: &[u8],
-    ref_overlapped: &cell::RefCell<OVERLAPPED>,
-) -> ws::core::Result<()> {
+    overlapped: &mut OVERLAPPED,
+    timeout: Option<Duration>,
+) -> io::Result<()> {
     let mut written = 0;
 
-    let mut overlapped = ref_overlapped.borrow_mut();
-
     debug!("WriteFile");
     let ret = unsafe {
         ws::Win32::Storage::FileSystem::WriteFile(
@@ -32,22 +318,9 @@ pub fn write_dvc(
     let mut real_written = written;
     if let Err(err) = ret {
         if err.code() == ws::Win32::Foundation::ERROR_IO_PENDING.to_hresult() {
-            let mut written = 0;
-            let ret = unsafe {
-                ws::Win32::System::IO::GetOverlappedResult(
-                    filehandle,
-                    &raw const *overlapped,
-                    &raw mut written,
-                    true,
-                )
-            };
-
-            real_written = match ret {
-                Ok(_) => written,
-                Err(err) => return Err(err),
-            };
+            real_written = wait_overlapped(filehandle, overlapped, timeout, "write")?;
         } else {
-            return Err(err);
+            return Err(err.into());
         }
     }
 
@@ -58,15 +331,14 @@ pub fn write_dvc(
     Ok(())
 }
 
-pub fn read_dvc(
-    filehandle: ws::Win32::Foundation::HANDLE,
-    ref_overlapped: &cell::RefCell<OVERLAPPED>,
-) -> Result<String, ws::core::Error> {
-    let mut tot_read = 0;
-    let mut specified_pdu_length = 0;
-
-    let mut read_string = String::new();
-    let mut overlapped = ref_overlapped.borrow_mut();
+/// Read chunks until `reassembler` completes a message. `timeout` bounds
+/// each read.
+fn read_dvc(
+    filehandle: HANDLE,
+    overlapped: &mut OVERLAPPED,
+    reassembler: &mut Reassembler,
+    timeout: Option<Duration>,
+) -> io::Result<Vec<u8>> {
     loop {
         debug!("ReadFile");
         let mut rbuf = [0u8; PACKET_MAX_LENGTH];
@@ -83,80 +355,15 @@ pub fn read_dvc(
         let mut real_read = read;
         if let Err(err) = ret {
             if err.code() == ws::Win32::Foundation::ERROR_IO_PENDING.to_hresult() {
-                debug!("read GetOverlappedResult");
-                let mut overlapped_read = 0;
-                let ret = unsafe {
-                    ws::Win32::System::IO::GetOverlappedResult(
-                        filehandle,
-                        &raw const *overlapped,
-                        &raw mut overlapped_read,
-                        true,
-                    )
-                };
-
-                real_read = match ret {
-                    Ok(_) => overlapped_read,
-                    Err(err) => return Err(err),
-                };
+                real_read = wait_overlapped(filehandle, overlapped, timeout, "read")?;
             } else {
-                return Err(err);
+                return Err(err.into());
             }
         }
 
-        if real_read < 8 {
-            return Err(ws::core::Error::new(
-                ws::Win32::Foundation::E_FAIL,
-                format!("not a PDU header (length = {real_read}): {rbuf:?}"),
-            ));
-        }
-
-        // Parse CHANNEL_PDU_HEADER
-        let mut pdu_length = [0u8; 4];
-        pdu_length.copy_from_slice(&rbuf[..4]);
-        let pdu_length = u32::from_le_bytes(pdu_length);
-
-        let mut pdu_flags = [0u8; 4];
-        pdu_flags.copy_from_slice(&rbuf[4..8]);
-        let pdu_flags = u32::from_le_bytes(pdu_flags);
-
-        // Extend read string
-        let rstr = String::from_utf8_lossy(&rbuf[PDU_HEADER_LENGTH..]);
-        let rstr = rstr.trim_matches(char::from(0));
-        read_string.push_str(rstr);
-        tot_read += real_read - PDU_HEADER_LENGTH as u32;
-
-        const CHANNEL_FLAG_ONLY: u32 = CHANNEL_FLAG_FIRST | CHANNEL_FLAG_LAST;
-        match pdu_flags {
-            CHANNEL_FLAG_ONLY /* 0x3 */ => {
-                debug!("CHANNEL_FLAG_ONLY: one packet to read");
-                break;
-            }
-            CHANNEL_FLAG_LAST /* 0x2 */ => {
-                debug!("CHANNEL_FLAG_ONLY: last packet");
-                specified_pdu_length = pdu_length;
-                break;
-            }
-            CHANNEL_FLAG_FIRST /* 0x1 */ => {
-                debug!("CHANNEL_FLAG_ONLY: first packet");
-            }
-            CHANNEL_FLAG_MIDDLE /* 0x0 */ => {
-                debug!("CHANNEL_FLAG_MIDDLE: continuing...");
-            }
-            _ => {
-                return Err(ws::core::Error::new(
-                    ws::Win32::Foundation::E_FAIL,
-                    format!("unsupported PDU flags: 0x{pdu_flags:x}"),
-                ));
-            }
+        let chunk = &rbuf[..real_read as usize];
+        if let Some(message) = reassembler.push(chunk)? {
+            return Ok(message);
         }
     }
-
-    if specified_pdu_length != tot_read {
-        return Err(ws::core::Error::new(
-            ws::Win32::Foundation::E_FAIL,
-            format!("inconsistent length: pdu_length = {specified_pdu_length} - read = {tot_read}"),
-        ));
-    }
-
-    Ok(read_string)
 }
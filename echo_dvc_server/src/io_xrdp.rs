@@ -0,0 +1,312 @@
+//! DVC access on xrdp hosts.
+//!
+//! On Linux, xrdp has no `wtsapi32`: session processes reach the virtual
+//! channels through its chansrv component, which listens on a per display
+//! Unix socket (`xrdpapi_<display>`). This mirrors what `libxrdpapi` does in
+//! `WTSVirtualChannelOpenEx`: connect, send the open request, then exchange
+//! raw channel data on the socket.
+
+use std::{
+    env,
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use echo_dvc_proto::{CHANNEL_CHUNK_LENGTH, DvcReceiver, DvcSender, DvcTransport, Timeouts};
+use log::debug;
+
+/// Directory used by xrdp < 0.10, which does not export `XRDP_SOCKET_PATH`.
+const XRDP_SOCKET_PATH_DEFAULT: &str = "/tmp/.xrdp";
+const WTS_CHANNEL_OPTION_DYNAMIC: u32 = 0x1;
+const OPEN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// DVC opened through xrdp chansrv.
+///
+/// chansrv forwards the channel data without any CHANNEL_PDU_HEADER, so
+/// message boundaries are not preserved: [`DvcTransport::recv`] returns the
+/// data as it arrives on the socket. For the same reason, a write which
+/// times out may have written part of the data.
+pub struct ChansrvTransport {
+    stream: Option<UnixStream>,
+}
+
+impl ChansrvTransport {
+    /// Open `channel_name` through the chansrv socket of the current session.
+    pub fn open(channel_name: &str) -> io::Result<Self> {
+        Self::open_at(&default_socket_path()?, channel_name)
+    }
+
+    /// Open `channel_name` through the chansrv socket at `path`.
+    pub fn open_at(path: &Path, channel_name: &str) -> io::Result<Self> {
+        debug!("connecting to chansrv: {}", path.display());
+        let mut stream = UnixStream::connect(path)?;
+
+        stream.set_read_timeout(Some(OPEN_TIMEOUT))?;
+        stream.write_all(&open_request(channel_name, WTS_CHANNEL_OPTION_DYNAMIC))?;
+
+        let mut status = [0u8; 4];
+        stream.read_exact(&mut status)?;
+        let status = u32::from_le_bytes(status);
+        if status != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("chansrv refused to open {channel_name} (status = {status})"),
+            ));
+        }
+        stream.set_read_timeout(None)?;
+
+        debug!("chansrv opened {channel_name}");
+        Ok(Self {
+            stream: Some(stream),
+        })
+    }
+
+    fn stream(&mut self) -> io::Result<&mut UnixStream> {
+        self.stream
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "channel closed"))
+    }
+}
+
+/// Blocking sockets report an expired timeout as `WouldBlock`.
+fn timeout_error(err: io::Error, operation: &str) -> io::Error {
+    match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+            io::Error::new(io::ErrorKind::TimedOut, format!("{operation} timed out"))
+        }
+        _ => err,
+    }
+}
+
+impl DvcTransport for ChansrvTransport {
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream()?
+            .write_all(data)
+            .map_err(|err| timeout_error(err, "write"))
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        let mut rbuf = vec![0u8; CHANNEL_CHUNK_LENGTH];
+        let read = self
+            .stream()?
+            .read(&mut rbuf)
+            .map_err(|err| timeout_error(err, "read"))?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "chansrv closed the channel",
+            ));
+        }
+
+        rbuf.truncate(read);
+        Ok(rbuf)
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        if let Some(stream) = self.stream.take() {
+            stream.shutdown(std::net::Shutdown::Both)?;
+        }
+        Ok(())
+    }
+
+    /// The timeouts are socket options, shared by both halves.
+    fn set_timeouts(&mut self, timeouts: Timeouts) -> io::Result<()> {
+        let stream = self.stream()?;
+        stream.set_read_timeout(timeouts.read)?;
+        stream.set_write_timeout(timeouts.write)
+    }
+
+    fn split(mut self: Box<Self>) -> io::Result<(Box<dyn DvcSender>, Box<dyn DvcReceiver>)> {
+        let read_half = Self {
+            stream: Some(self.stream()?.try_clone()?),
+        };
+        Ok((Box::new(Half(*self)), Box::new(Half(read_half))))
+    }
+}
+
+/// One half of a split [`ChansrvTransport`], each owning a handle on the
+/// socket.
+struct Half(ChansrvTransport);
+
+impl DvcSender for Half {
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.send(data)
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.0.close()
+    }
+}
+
+impl DvcReceiver for Half {
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        self.0.recv()
+    }
+}
+
+/// Build the request sent by `WTSVirtualChannelOpenEx`:
+/// total length, version (0), name length, name, flags, all little endian.
+fn open_request(channel_name: &str, flags: u32) -> Vec<u8> {
+    let name = channel_name.as_bytes();
+    let length = 4 + 4 + 4 + name.len() + 4;
+
+    let mut request = Vec::with_capacity(length);
+    request.extend_from_slice(&(length as u32).to_le_bytes());
+    request.extend_from_slice(&0u32.to_le_bytes());
+    request.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    request.extend_from_slice(name);
+    request.extend_from_slice(&flags.to_le_bytes());
+    request
+}
+
+fn default_socket_path() -> io::Result<PathBuf> {
+    let display = env::var("DISPLAY")
+        .ok()
+        .and_then(|display| display_number(&display))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no X11 display number"))?;
+
+    let socket_dir =
+        env::var_os("XRDP_SOCKET_PATH").unwrap_or_else(|| XRDP_SOCKET_PATH_DEFAULT.into());
+
+    Ok(Path::new(&socket_dir).join(format!("xrdpapi_{display}")))
+}
+
+/// Extract `10` from a `DISPLAY` value such as `:10.0` or `host:10`.
+fn display_number(display: &str) -> Option<u32> {
+    let (_, display) = display.rsplit_once(':')?;
+    let display = display.split('.').next()?;
+    display.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{os::unix::net::UnixListener, process, thread};
+
+    /// Stand-in chansrv: accept one channel open request, then echo.
+    fn chansrv_peer(name: &str, status: u32) -> PathBuf {
+        let path = env::temp_dir().join(format!("xrdpapi_{}_{name}", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let mut length = [0u8; 4];
+            stream.read_exact(&mut length).unwrap();
+            let mut request = vec![0u8; u32::from_le_bytes(length) as usize - 4];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(&status.to_le_bytes()).unwrap();
+
+            let mut rbuf = [0u8; 64];
+            while let Ok(read @ 1..) = stream.read(&mut rbuf) {
+                stream.write_all(&rbuf[..read]).unwrap();
+            }
+        });
+
+        path
+    }
+
+    #[test]
+    fn request_layout() {
+        assert_eq!(
+            open_request("ECHOCHN", WTS_CHANNEL_OPTION_DYNAMIC),
+            [
+                &[23, 0, 0, 0][..],
+                &[0, 0, 0, 0],
+                &[7, 0, 0, 0],
+                b"ECHOCHN",
+                &[1, 0, 0, 0],
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn display_numbers() {
+        assert_eq!(display_number(":10.0"), Some(10));
+        assert_eq!(display_number("localhost:11"), Some(11));
+        assert_eq!(display_number("wayland-0"), None);
+    }
+
+    #[test]
+    fn echo_through_chansrv() {
+        let path = chansrv_peer("echo", 0);
+        let mut transport = ChansrvTransport::open_at(&path, "ECHOCHN").unwrap();
+
+        transport.send(b"hello").unwrap();
+        assert_eq!(transport.recv().unwrap(), b"hello");
+        transport.close().unwrap();
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn large_echo_through_chansrv() {
+        let path = chansrv_peer("large", 0);
+        let mut transport = ChansrvTransport::open_at(&path, "ECHOCHN").unwrap();
+
+        // More than chansrv forwards at once, echoed in pieces as they come.
+        let sent: Vec<u8> = (0..4000).map(|i| i as u8).collect();
+        transport.send(&sent).unwrap();
+        let mut received = Vec::new();
+        while received.len() < sent.len() {
+            let data = transport.recv().unwrap();
+            assert!(data.len() <= CHANNEL_CHUNK_LENGTH);
+            received.extend_from_slice(&data);
+        }
+        assert_eq!(received, sent);
+        transport.close().unwrap();
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn split_through_chansrv() {
+        let path = chansrv_peer("split", 0);
+        let transport = ChansrvTransport::open_at(&path, "ECHOCHN").unwrap();
+        let (mut sender, mut receiver) = Box::new(transport).split().unwrap();
+
+        sender.send(b"hello").unwrap();
+        assert_eq!(receiver.recv().unwrap(), b"hello");
+        sender.close().unwrap();
+        assert_eq!(
+            receiver.recv().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn read_timeout() {
+        let path = chansrv_peer("timeout", 0);
+        let mut transport = ChansrvTransport::open_at(&path, "ECHOCHN").unwrap();
+        transport
+            .set_timeouts(Timeouts {
+                read: Some(Duration::from_millis(20)),
+                write: None,
+            })
+            .unwrap();
+
+        assert_eq!(
+            transport.recv().unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+        transport.send(b"hello").unwrap();
+        assert_eq!(transport.recv().unwrap(), b"hello");
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn refused_channel() {
+        let path = chansrv_peer("refused", 1);
+        let err = ChansrvTransport::open_at(&path, "ECHOCHN").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,47 +1,249 @@
+mod bench;
+mod commands;
+mod error;
+mod forward;
+#[cfg(windows)]
 mod io_dvc;
+#[cfg(unix)]
+mod io_xrdp;
+mod payload;
+mod repl;
+mod script;
+mod session;
+mod socks;
+mod verify;
 
 use std::{
-    cell,
-    io::{self, Write},
+    fs,
+    io::{self, IsTerminal, Read, Write},
+    net::{SocketAddr, TcpStream},
+    path::PathBuf,
     process::exit,
-    ptr,
+    str::FromStr,
+    thread,
+    time::Duration,
 };
 
-use clap::Parser;
-use io_dvc::{read_dvc, write_dvc};
-
-use log::{debug, error};
-use simplelog::Config;
-use windows::{
-    self as ws,
-    Win32::System::{
-        IO::OVERLAPPED,
-        RemoteDesktop::{
-            CHANNEL_CHUNK_LENGTH, WTS_CHANNEL_OPTION_DYNAMIC, WTS_CURRENT_SESSION,
-            WTSVirtualChannelOpenEx, WTSVirtualChannelQuery,
-        },
-    },
-    core::PCSTR,
+use bench::{BenchConfig, DEFAULT_SIZES, render_json, render_table, run_bench};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use commands::Command;
+use echo_dvc_proto::{
+    DVC_NAME, DvcTransport, Error, Hello, MessageTransport, Negotiated, StreamTransport, Timeouts,
 };
+use error::{ChannelError, ExitStatus, Operation};
+use forward::{Forward, Forwarder};
 
-const PDU_HEADER_LENGTH: usize = 0x8;
-const PACKET_MAX_LENGTH: usize = CHANNEL_CHUNK_LENGTH as usize + PDU_HEADER_LENGTH;
-const DVC_NAME_DEFAULT: &str = "ECHOCHN";
+use log::{debug, error, info};
+use payload::{OutputFormat, display};
+use repl::{LineInput, Lines, Terminal, default_history};
+use script::{ScriptResult, run_script};
+use session::{Incoming, Printer, Session, parse_duration, parse_timeout};
+use simplelog::Config;
+use socks::SocksProxy;
+use verify::{Tally, first_difference, hexdump_diff};
 
-const HELP_MSG: &str = r#"
-Usage:
-- "write XXXX" or "put XXXX" to write to the DVC
-- "quit" or "exit" to leave this interface
-"#;
 const PROMPT: &str = "echo_dvc> ";
+/// Time `verify` waits for the reply.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time the client has to answer the handshake, unless `--read-timeout` is
+/// given.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Parser)]
 #[command(name = "echo_dvc_server")]
 struct Cli {
     #[arg(short, long, help = "enable debug logs")]
     verbose: bool,
-    #[arg(default_value = DVC_NAME_DEFAULT, help = "DVC name to open")]
+    #[arg(
+        short,
+        long,
+        default_value = "wts",
+        help = "channel transport: \"wts\", \"xrdp[://socket]\" or \"tcp://host:port\""
+    )]
+    transport: TransportSpec,
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "exec",
+        help = "run the commands of FILE (\"-\" for stdin) and report results as JSON lines"
+    )]
+    script: Option<PathBuf>,
+    #[arg(
+        short,
+        long,
+        value_name = "COMMAND",
+        help = "run COMMAND and report its result as a JSON line, can be repeated"
+    )]
+    exec: Vec<String>,
+    #[arg(long, help = "check that every write is echoed back unchanged")]
+    verify: bool,
+    #[arg(
+        short,
+        long,
+        value_enum,
+        help = "display of the data received [default: both, text in scripted mode]"
+    )]
+    output: Option<OutputFormat>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "history file of the prompt [default: ~/.echo_dvc_history]"
+    )]
+    history: Option<PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Incoming::Print,
+        help = "what the prompt does with the messages received"
+    )]
+    incoming: Incoming,
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_channel_timeout,
+        help = "fail when a reply takes longer than DURATION, e.g. 500ms or 2s"
+    )]
+    read_timeout: Option<Duration>,
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_channel_timeout,
+        help = "fail when a write is blocked for longer than DURATION"
+    )]
+    write_timeout: Option<Duration>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Protocol::Raw,
+        help = "framing of the data written, which must match the handler of the client"
+    )]
+    protocol: Protocol,
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        conflicts_with_all = ["script", "exec"],
+        help = "run a SOCKS5 proxy on ADDRESS, e.g. 127.0.0.1:1080, whose connections are made by the client, with the message protocol"
+    )]
+    socks: Option<SocketAddr>,
+    #[arg(default_value = DVC_NAME, help = "DVC name to open")]
     name: String,
+    #[command(subcommand)]
+    mode: Option<Mode>,
+}
+
+#[derive(Subcommand)]
+enum Mode {
+    /// Measure round-trip latency and throughput of the channel
+    Bench(BenchArgs),
+    /// Forward TCP connections through the channel, with the message
+    /// protocol
+    Forward(ForwardArgs),
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    #[arg(
+        short = 'n',
+        long,
+        default_value_t = 100,
+        help = "messages sent for each size"
+    )]
+    count: usize,
+    #[arg(
+        short,
+        long,
+        value_delimiter = ',',
+        help = "comma separated message sizes in bytes [default: 64,1600,1601,16384]"
+    )]
+    sizes: Vec<usize>,
+    #[arg(
+        long,
+        default_value_t = 10,
+        help = "unmeasured messages sent before each size"
+    )]
+    warmup: usize,
+    #[arg(long, value_enum, default_value_t = ReportFormat::Table)]
+    format: ReportFormat,
+}
+
+#[derive(Args)]
+#[group(required = true, multiple = true)]
+struct ForwardArgs {
+    #[arg(
+        short = 'L',
+        long,
+        value_name = "[BIND:]PORT:HOST:HOSTPORT",
+        help = "listen on BIND:PORT (default bind 127.0.0.1) and forward the connections to HOST:HOSTPORT, reached by the client, can be repeated"
+    )]
+    local: Vec<Forward>,
+    #[arg(
+        short = 'R',
+        long,
+        value_name = "[BIND:]PORT:HOST:HOSTPORT",
+        help = "make the client listen on BIND:PORT and forward the connections to HOST:HOSTPORT, reached from here, can be repeated"
+    )]
+    remote: Vec<Forward>,
+}
+
+/// Framing of the data exchanged on the channel.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum Protocol {
+    /// Raw bytes, for the `echo` handler.
+    Raw,
+    /// Length prefixed requests and responses, for the `message` handler.
+    Message,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ReportFormat {
+    Table,
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TransportSpec {
+    /// DVC of the current remote session.
+    Wts,
+    /// DVC of the current xrdp session, through the chansrv socket.
+    Xrdp(Option<PathBuf>),
+    /// CHANNEL_PDU_HEADER chunks over TCP, see `echo_dvc_listener`.
+    Tcp(String),
+}
+
+impl FromStr for TransportSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once("://") {
+            None if s.eq_ignore_ascii_case("wts") => Ok(Self::Wts),
+            None if s.eq_ignore_ascii_case("xrdp") => Ok(Self::Xrdp(None)),
+            Some((scheme, path)) if scheme.eq_ignore_ascii_case("xrdp") && !path.is_empty() => {
+                Ok(Self::Xrdp(Some(path.into())))
+            }
+            Some((scheme, address))
+                if scheme.eq_ignore_ascii_case("tcp") && !address.is_empty() =>
+            {
+                Ok(Self::Tcp(address.to_string()))
+            }
+            _ => Err(format!("unsupported transport: {s}")),
+        }
+    }
+}
+
+impl TransportSpec {
+    /// Whether a `recv` returns a whole message. chansrv forwards the data
+    /// without the chunk headers, which carry the boundaries.
+    fn keeps_boundaries(&self) -> bool {
+        !matches!(self, Self::Xrdp(_))
+    }
+}
+
+/// Timeout of the channel operations, which cannot be zero.
+fn parse_channel_timeout(arg: &str) -> Result<Duration, String> {
+    match parse_duration(arg)? {
+        Duration::ZERO => Err("timeout must be greater than zero".to_string()),
+        timeout => Ok(timeout),
+    }
 }
 
 fn init_logs(verbose: bool) {
@@ -59,157 +261,866 @@ fn init_logs(verbose: bool) {
     );
 }
 
-fn main() {
-    let opts = Cli::parse();
-    init_logs(opts.verbose);
+fn open_transport(spec: &TransportSpec, channel_name: &str) -> io::Result<Box<dyn DvcTransport>> {
+    match spec {
+        TransportSpec::Wts => open_wts(channel_name),
+        TransportSpec::Xrdp(path) => open_xrdp(path.as_deref(), channel_name),
+        TransportSpec::Tcp(address) => {
+            let stream = TcpStream::connect(address)?;
+            stream.set_nodelay(true)?;
+            debug!("connected to {}", stream.peer_addr()?);
+            Ok(Box::new(StreamTransport::new(stream)))
+        }
+    }
+}
 
-    let channel_name = opts.name;
+#[cfg(windows)]
+fn open_wts(channel_name: &str) -> io::Result<Box<dyn DvcTransport>> {
+    Ok(Box::new(io_dvc::WtsTransport::open(channel_name)?))
+}
 
-    println!("opening channel: {channel_name}");
+#[cfg(not(windows))]
+fn open_wts(_channel_name: &str) -> io::Result<Box<dyn DvcTransport>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "WTS virtual channels are only available on Windows",
+    ))
+}
 
-    let ch_handle = match unsafe {
-        WTSVirtualChannelOpenEx(
-            WTS_CURRENT_SESSION,
-            PCSTR(format!("{channel_name}\0").as_ptr()),
-            WTS_CHANNEL_OPTION_DYNAMIC,
-        )
-    } {
-        Ok(handle) => handle,
-        Err(err) => {
-            error!("failed to open DVC {channel_name}: {err}");
-            error!("Are you sure the plugin is correctly loaded ?");
-            exit(1);
-        }
+#[cfg(unix)]
+fn open_xrdp(
+    path: Option<&std::path::Path>,
+    channel_name: &str,
+) -> io::Result<Box<dyn DvcTransport>> {
+    let transport = match path {
+        Some(path) => io_xrdp::ChansrvTransport::open_at(path, channel_name)?,
+        None => io_xrdp::ChansrvTransport::open(channel_name)?,
     };
+    Ok(Box::new(transport))
+}
+
+#[cfg(not(unix))]
+fn open_xrdp(
+    _path: Option<&std::path::Path>,
+    _channel_name: &str,
+) -> io::Result<Box<dyn DvcTransport>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "xrdp virtual channels are only available on Unix",
+    ))
+}
+
+/// Read the commands given by `--script` or `--exec`, if any.
+fn read_script(opts: &Cli) -> io::Result<Option<String>> {
+    match &opts.script {
+        Some(path) if path.as_os_str() == "-" => {
+            let mut script = String::new();
+            io::stdin().read_to_string(&mut script)?;
+            Ok(Some(script))
+        }
+        Some(path) => fs::read_to_string(path).map(Some),
+        None if !opts.exec.is_empty() => Ok(Some(opts.exec.join("\n"))),
+        None => Ok(None),
+    }
+}
 
-    if ch_handle.0.is_null() {
-        let err = io::Error::last_os_error();
-        error!("error channel handle is null: {err}");
-        exit(1);
+/// Line editor of the prompt, or plain lines when stdin is redirected, and
+/// the output of the messages printed as they arrive.
+fn open_input(history: Option<PathBuf>) -> io::Result<(Box<dyn LineInput>, Printer)> {
+    if io::stdin().is_terminal() {
+        let mut terminal = Terminal::new(history.or_else(default_history))?;
+        let printer = terminal.printer()?;
+        return Ok((Box::new(terminal), printer));
     }
 
-    debug!("channel handle ok: {ch_handle:?}");
+    let printer: Printer = Box::new(|text| {
+        let mut stdout = io::stdout().lock();
+        let _ = write!(stdout, "{text}");
+        let _ = stdout.flush();
+    });
+    Ok((
+        Box::new(Lines::new(io::stdin().lock(), io::stdout())),
+        printer,
+    ))
+}
 
-    let mut filehandleptr: *mut ws::Win32::Foundation::HANDLE = ptr::null_mut();
-    let filehandleptrptr: *mut *mut ws::Win32::Foundation::HANDLE = &raw mut filehandleptr;
-    let mut len = 0;
+/// Interactive prompt on `transport`.
+fn prompt(
+    transport: Box<dyn DvcTransport>,
+    history: Option<PathBuf>,
+    incoming: Incoming,
+    verify_writes: bool,
+    boundaries: bool,
+    format: OutputFormat,
+) -> io::Result<()> {
+    let (mut input, printer) = open_input(history)?;
+    let mut session = Session::start(transport, incoming, format, printer)?;
 
-    debug!("WTSVirtualChannelQuery");
-    let ret = unsafe {
-        WTSVirtualChannelQuery(
-            ch_handle,
-            ws::Win32::System::RemoteDesktop::WTSVirtualFileHandle,
-            filehandleptrptr.cast(),
-            &raw mut len,
-        )
-    };
+    let ret = run(
+        &mut session,
+        input.as_mut(),
+        io::stdout(),
+        verify_writes,
+        boundaries,
+        format,
+    );
+    let _ = session.close();
+    ret
+}
+
+/// Handshake of the message layer, under a read timeout so that a client
+/// which does not answer is reported.
+fn handshake(
+    transport: &mut MessageTransport<Box<dyn DvcTransport>>,
+    timeouts: Timeouts,
+) -> Result<Negotiated, Error> {
+    transport.set_timeouts(Timeouts {
+        read: timeouts.read.or(Some(HANDSHAKE_TIMEOUT)),
+        ..timeouts
+    })?;
+    let negotiated = transport.handshake(&Hello::default())?;
+    transport.set_timeouts(timeouts)?;
+    Ok(negotiated)
+}
+
+/// Run the `local` and `remote` forwards, and the SOCKS proxy on `socks`,
+/// until the channel closes.
+fn forward(
+    messages: MessageTransport<Box<dyn DvcTransport>>,
+    negotiated: &Negotiated,
+    local: &[Forward],
+    remote: &[Forward],
+    socks: Option<SocketAddr>,
+) -> ExitStatus {
+    let mux = messages.into_mux(negotiated).map_err(io::Error::from);
+    let ret = mux.and_then(|mux| {
+        let proxy = socks
+            .map(|address| SocksProxy::bind(address, mux.clone()))
+            .transpose()?;
+        let forwarder = Forwarder::start(mux, local, remote)?;
+
+        for route in forwarder.routes() {
+            println!("forwarding {route}");
+        }
+        if let Some(proxy) = proxy {
+            println!(
+                "socks proxy on {}, connecting from the client",
+                proxy.local_addr()?
+            );
+            thread::spawn(move || proxy.serve());
+        }
+        forwarder.serve()
+    });
 
     match ret {
-        Ok(_) => {}
+        Ok(()) => ExitStatus::Passed,
         Err(err) => {
-            error!("WTSVirtualChannelQuery failed: {err}");
-            exit(1);
+            error!("error: {err}");
+            ExitStatus::of(err)
         }
     }
+}
 
-    if filehandleptr.is_null() {
-        let err = io::Error::last_os_error();
-        error!("error file handle is null: {err}");
-        exit(1);
+fn bench(transport: &mut dyn DvcTransport, args: &BenchArgs) -> io::Result<()> {
+    let config = BenchConfig {
+        count: args.count,
+        sizes: match args.sizes.is_empty() {
+            true => DEFAULT_SIZES.to_vec(),
+            false => args.sizes.clone(),
+        },
+        warmup: args.warmup,
+    };
+
+    let reports = run_bench(transport, &config)?;
+    match args.format {
+        ReportFormat::Table => print!("{}", render_table(&reports)),
+        ReportFormat::Json => println!("{}", render_json(&reports)),
     }
+    Ok(())
+}
 
-    let filehandle = unsafe { *filehandleptr };
-    debug!("filehandle: {filehandle:?}");
+fn main() {
+    let opts = Cli::parse();
+    init_logs(opts.verbose);
 
-    let h_event = unsafe {
-        ws::Win32::System::Threading::CreateEventA(Some(ptr::null()), false, false, PCSTR::null())
+    if opts.mode.is_some() && (opts.script.is_some() || !opts.exec.is_empty()) {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--script and --exec cannot be used with a subcommand",
+            )
+            .exit();
+    }
+    if opts.socks.is_some() && matches!(opts.mode, Some(Mode::Bench(_))) {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--socks cannot be used with the bench subcommand",
+            )
+            .exit();
     }
-    .unwrap();
 
-    if h_event.0.is_null() {
-        let err = io::Error::last_os_error();
-        error!("error handle event is null: {err}");
-        exit(1);
+    // Raw replies can only be checked when each one is received whole.
+    let forwarding = matches!(opts.mode, Some(Mode::Forward(_))) || opts.socks.is_some();
+    let boundaries =
+        opts.protocol == Protocol::Message || forwarding || opts.transport.keeps_boundaries();
+    let checked = matches!(opts.mode, Some(Mode::Bench(_)))
+        || opts.script.is_some()
+        || !opts.exec.is_empty()
+        || opts.verify;
+    if !boundaries && checked {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "the bench subcommand, --script, --exec and --verify need --protocol message \
+                 with the xrdp transport, which does not keep message boundaries",
+            )
+            .exit();
     }
 
-    let anonymous = ws::Win32::System::IO::OVERLAPPED_0 {
-        Pointer: ptr::null_mut(),
+    let script = match read_script(&opts) {
+        Ok(script) => script,
+        Err(err) => {
+            error!("failed to read script: {err}");
+            exit(ExitStatus::Error.code());
+        }
     };
 
-    let read_overlapped = ws::Win32::System::IO::OVERLAPPED {
-        Internal: 0,
-        InternalHigh: 0,
-        Anonymous: anonymous,
-        hEvent: h_event,
+    let channel_name = opts.name;
+    let format = opts.output.unwrap_or(match script {
+        Some(_) => OutputFormat::Text,
+        None => OutputFormat::Both,
+    });
+
+    // Keep stdout for the results in scripted mode.
+    let interactive = script.is_none() && opts.mode.is_none() && opts.socks.is_none();
+    if interactive {
+        match &opts.transport {
+            TransportSpec::Wts | TransportSpec::Xrdp(_) => {
+                println!("opening channel: {channel_name}")
+            }
+            TransportSpec::Tcp(address) => println!("connecting to: {address}"),
+        }
+    }
+
+    let mut transport = match open_transport(&opts.transport, &channel_name) {
+        Ok(transport) => transport,
+        Err(err) => {
+            match &opts.transport {
+                TransportSpec::Wts | TransportSpec::Xrdp(_) => {
+                    error!("failed to open DVC {channel_name}: {err}");
+                    error!("Are you sure the plugin is correctly loaded ?");
+                }
+                TransportSpec::Tcp(address) => error!("failed to connect to {address}: {err}"),
+            }
+            exit(ExitStatus::OpenFailed.code());
+        }
     };
 
-    let write_overlapped = ws::Win32::System::IO::OVERLAPPED {
-        Internal: 0,
-        InternalHigh: 0,
-        Anonymous: anonymous,
-        hEvent: ws::Win32::Foundation::HANDLE(ptr::null_mut()),
+    let timeouts = Timeouts {
+        read: opts.read_timeout,
+        write: opts.write_timeout,
     };
+    if timeouts != Timeouts::default()
+        && let Err(err) = transport.set_timeouts(timeouts)
+    {
+        error!("failed to set the channel timeouts: {err}");
+        exit(ExitStatus::Error.code());
+    }
+
+    if opts.protocol == Protocol::Message || forwarding {
+        let mut messages = MessageTransport::new(transport);
+        let negotiated = match handshake(&mut messages, timeouts) {
+            Ok(negotiated) => negotiated,
+            Err(err) => {
+                error!("handshake failed: {err}");
+                exit(ExitStatus::of(err.into()).code());
+            }
+        };
+        match interactive {
+            true => println!("negotiated {negotiated}"),
+            false => info!("negotiated {negotiated}"),
+        }
 
-    let read_overlapped = cell::RefCell::new(read_overlapped);
-    let write_overlapped = cell::RefCell::new(write_overlapped);
+        if forwarding {
+            let (local, remote) = match &opts.mode {
+                Some(Mode::Forward(args)) => (&args.local[..], &args.remote[..]),
+                _ => (&[][..], &[][..]),
+            };
+            exit(forward(messages, &negotiated, local, remote, opts.socks).code());
+        }
+        transport = Box::new(messages);
+    }
+
+    let ret = match (&opts.mode, &script) {
+        (Some(Mode::Forward(_)), _) => unreachable!("forwarding exits above"),
+        (Some(Mode::Bench(args)), _) => {
+            let ret = bench(&mut transport, args);
+            let _ = transport.close();
+            ret.map(|_| ScriptResult::Passed)
+        }
+        (None, Some(script)) => {
+            let ret = run_script(
+                &mut transport,
+                script.lines(),
+                io::stdout().lock(),
+                opts.verify,
+                format,
+            );
+            let _ = transport.close();
+            ret
+        }
+        (None, None) => prompt(
+            transport,
+            opts.history,
+            opts.incoming,
+            opts.verify,
+            boundaries,
+            format,
+        )
+        .map(|_| ScriptResult::Passed),
+    };
 
-    match run(filehandle, read_overlapped, write_overlapped) {
-        Ok(_) => {}
+    let status = match ret {
+        Ok(ScriptResult::Passed) => ExitStatus::Passed,
+        Ok(ScriptResult::Failed) => ExitStatus::Failed,
         Err(e) => {
             error!("error: {e}");
-            exit(1);
+            ExitStatus::of(e)
+        }
+    };
+    exit(status.code());
+}
+
+/// Send `data` on the channel and wait for the reply.
+fn exchange(transport: &mut dyn DvcTransport, data: &[u8]) -> io::Result<Vec<u8>> {
+    transport
+        .send(data)
+        .map_err(|err| ChannelError::wrap(Operation::Write, err))?;
+
+    transport
+        .recv()
+        .map_err(|err| ChannelError::wrap(Operation::Read, err))
+}
+
+/// Print whether `received` matches `sent` and record it in `tally`.
+fn report_verification(
+    mut output: impl Write,
+    sent: &[u8],
+    received: &[u8],
+    tally: &mut Tally,
+) -> io::Result<()> {
+    let difference = first_difference(sent, received);
+    tally.record(difference.is_none());
+
+    match difference {
+        None => writeln!(output, "PASS ({} bytes)", sent.len()),
+        Some(offset) => {
+            writeln!(
+                output,
+                "FAIL: first difference at offset {offset} (sent {} bytes, received {} bytes)",
+                sent.len(),
+                received.len()
+            )?;
+            write!(output, "{}", hexdump_diff(sent, received))
         }
     }
 }
 
+/// Interactive prompt. Writes do not wait for a reply, except when checked
+/// with `verify`, or with `verify_writes` for all of them. Received data is
+/// displayed according to `format`.
+///
+/// Without `boundaries`, messages are received in arbitrary pieces which
+/// cannot be told apart from replies, so `verify` is refused.
 fn run(
-    filehandle: ws::Win32::Foundation::HANDLE,
-    read_overlapped: cell::RefCell<OVERLAPPED>,
-    write_overlapped: cell::RefCell<OVERLAPPED>,
-) -> ws::core::Result<()> {
-    println!("{HELP_MSG}");
-    let mut input = String::new();
+    session: &mut Session,
+    input: &mut dyn LineInput,
+    mut output: impl Write,
+    verify_writes: bool,
+    boundaries: bool,
+    format: OutputFormat,
+) -> io::Result<()> {
+    writeln!(output, "{}", commands::help())?;
+    let mut tally = Tally::default();
     loop {
-        print!("{PROMPT}");
-        io::stdout().flush().unwrap();
-
-        let _ = io::stdin().read_line(&mut input).unwrap();
-        let line = input.trim();
-
-        let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
-
-        let command = command.to_uppercase();
-
-        debug!("command: {command}");
-        debug!("arg: {arg}");
-
-        match command.as_str() {
-            "" => (),
-            "QUIT" | "EXIT" => break,
-            "WRITE" | "PUT" => {
-                // Send
-                write_dvc(filehandle, arg.as_bytes(), &write_overlapped).map_err(|err| {
-                    ws::core::Error::new(
-                        err.code(),
-                        format!("error writting to channel: {}", err.message()),
-                    )
-                })?;
-
-                // Receive
-                let read = read_dvc(filehandle, &read_overlapped).map_err(|err| {
-                    ws::core::Error::new(
-                        err.code(),
-                        format!("error reading from channel: {}", err.message()),
-                    )
-                })?;
-
-                println!("received: {} ({:?})", read, read.as_bytes());
+        let prompt = match session.queued() {
+            0 => PROMPT.to_string(),
+            queued => format!("echo_dvc [{queued} queued]> "),
+        };
+        let Some(line) = input.read_line(&prompt)? else {
+            break;
+        };
+
+        let (payload, verify) = match Command::parse(&line) {
+            Command::Empty => continue,
+            Command::Help => {
+                writeln!(output, "{}", commands::help())?;
+                continue;
+            }
+            Command::Quit => break,
+            Command::Recv(arg) => {
+                let timeout = match parse_timeout(arg) {
+                    Ok(timeout) => timeout,
+                    Err(err) => {
+                        writeln!(output, "{err}")?;
+                        continue;
+                    }
+                };
+                match session.recv(timeout)? {
+                    Some(read) => write!(output, "{}", display(&read, format))?,
+                    None => writeln!(output, "no message received within {timeout:?}")?,
+                }
+                continue;
+            }
+            Command::Verify(_) if !boundaries => {
+                writeln!(
+                    output,
+                    "verify needs --protocol message with the xrdp transport"
+                )?;
+                continue;
+            }
+            Command::Write(payload) => (payload, verify_writes),
+            Command::Verify(payload) => (payload, true),
+            Command::Invalid(_) => {
+                writeln!(output, "invalid command")?;
+                continue;
+            }
+        };
+        let data = match payload.bytes() {
+            Ok(data) => data,
+            Err(err) => {
+                writeln!(output, "{err}")?;
+                continue;
+            }
+        };
+
+        if !verify {
+            session.send(&data)?;
+            writeln!(output, "sent {} bytes", data.len())?;
+            continue;
+        }
+
+        match session.request(&data, REPLY_TIMEOUT)? {
+            Some(read) => {
+                write!(output, "{}", display(&read, format))?;
+                report_verification(&mut output, &data, &read, &mut tally)?;
+            }
+            None => {
+                tally.record(false);
+                writeln!(output, "FAIL: no reply within {REPLY_TIMEOUT:?}")?;
             }
-            _ => println!("invalid command"),
         }
+    }
 
-        input.clear();
+    if !tally.is_empty() {
+        writeln!(
+            output,
+            "verified: {} passed, {} failed",
+            tally.passed, tally.failed
+        )?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use echo_dvc_proto::{
+        ChannelListener, ListenerConfig, Loopback, MessageHandler, test_util::echo_peer,
+    };
+    use std::thread;
+
+    fn run_lines(transport: impl DvcTransport + 'static, script: &str) -> io::Result<String> {
+        run_with(transport, script, false, OutputFormat::Both)
+    }
+
+    /// Run the prompt with the messages queued, so that the output only
+    /// depends on the commands.
+    fn run_with(
+        transport: impl DvcTransport + 'static,
+        script: &str,
+        verify_writes: bool,
+        format: OutputFormat,
+    ) -> io::Result<String> {
+        let mut session = Session::start(
+            Box::new(transport),
+            Incoming::Queue,
+            format,
+            Box::new(|_| {}),
+        )?;
+
+        let mut output = Vec::new();
+        let mut input = Lines::new(script.as_bytes(), io::sink());
+        run(
+            &mut session,
+            &mut input,
+            &mut output,
+            verify_writes,
+            true,
+            format,
+        )?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn transport_spec() {
+        assert_eq!("wts".parse(), Ok(TransportSpec::Wts));
+        assert_eq!(
+            "tcp://127.0.0.1:3390".parse(),
+            Ok(TransportSpec::Tcp("127.0.0.1:3390".to_string()))
+        );
+        assert_eq!("xrdp".parse(), Ok(TransportSpec::Xrdp(None)));
+        assert_eq!(
+            "xrdp:///tmp/.xrdp/xrdpapi_10".parse(),
+            Ok(TransportSpec::Xrdp(Some("/tmp/.xrdp/xrdpapi_10".into())))
+        );
+        assert!("tcp://".parse::<TransportSpec>().is_err());
+        assert!("udp://127.0.0.1:3390".parse::<TransportSpec>().is_err());
+    }
+
+    #[test]
+    fn bench_subcommand() {
+        let opts =
+            Cli::try_parse_from(["echo_dvc_server", "-t", "tcp://127.0.0.1:1", "bench"]).unwrap();
+        assert_eq!(opts.name, DVC_NAME);
+        assert!(
+            matches!(opts.mode, Some(Mode::Bench(ref args)) if args.count == 100 && args.sizes.is_empty())
+        );
+
+        let opts = Cli::try_parse_from([
+            "echo_dvc_server",
+            "OTHER",
+            "bench",
+            "-n",
+            "5",
+            "--sizes",
+            "1,2000",
+            "--format",
+            "json",
+        ])
+        .unwrap();
+        assert_eq!(opts.name, "OTHER");
+        let Some(Mode::Bench(args)) = opts.mode else {
+            panic!("bench subcommand expected");
+        };
+        assert_eq!((args.count, args.sizes), (5, vec![1, 2000]));
+    }
+
+    #[test]
+    fn timeout_options() {
+        let opts = Cli::try_parse_from([
+            "echo_dvc_server",
+            "--read-timeout",
+            "500ms",
+            "--write-timeout",
+            "2",
+        ])
+        .unwrap();
+        assert_eq!(opts.read_timeout, Some(Duration::from_millis(500)));
+        assert_eq!(opts.write_timeout, Some(Duration::from_secs(2)));
+
+        assert!(Cli::try_parse_from(["echo_dvc_server", "--read-timeout", "0"]).is_err());
+        assert!(Cli::try_parse_from(["echo_dvc_server", "--write-timeout", "soon"]).is_err());
+    }
+
+    #[test]
+    fn forward_options() {
+        let opts = Cli::try_parse_from([
+            "echo_dvc_server",
+            "forward",
+            "-L",
+            "8080:db:5432",
+            "--remote",
+            "0.0.0.0:9000:localhost:80",
+            "-L",
+            "2222:10.0.0.2:22",
+        ])
+        .unwrap();
+        let Some(Mode::Forward(args)) = opts.mode else {
+            panic!("not the forward subcommand");
+        };
+        assert_eq!(args.local.len(), 2);
+        assert_eq!(args.local[1].host, "10.0.0.2");
+        assert_eq!(args.remote[0].bind, "0.0.0.0");
+
+        assert!(Cli::try_parse_from(["echo_dvc_server", "forward"]).is_err());
+        assert!(Cli::try_parse_from(["echo_dvc_server", "forward", "-L", "8080"]).is_err());
+    }
+
+    #[test]
+    fn socks_options() {
+        let opts = Cli::try_parse_from(["echo_dvc_server", "--socks", "127.0.0.1:1080"]).unwrap();
+        assert_eq!(opts.socks, Some(SocketAddr::from(([127, 0, 0, 1], 1080))));
+        assert!(opts.mode.is_none());
+
+        let opts = Cli::try_parse_from([
+            "echo_dvc_server",
+            "--socks",
+            "[::1]:1080",
+            "forward",
+            "-L",
+            "8080:db:5432",
+        ])
+        .unwrap();
+        assert!(matches!(opts.mode, Some(Mode::Forward(_))));
+
+        assert!(Cli::try_parse_from(["echo_dvc_server", "--socks", "1080"]).is_err());
+        assert!(
+            Cli::try_parse_from([
+                "echo_dvc_server",
+                "--socks",
+                "127.0.0.1:1080",
+                "-e",
+                "put a"
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn write_is_echoed() {
+        let output = run_lines(
+            echo_peer(|data| data),
+            "write hello\nrecv\nput a b\nrecv\nquit\n",
+        )
+        .unwrap();
+
+        assert!(output.contains("sent 5 bytes"));
+        assert!(output.contains("received: hello\n00000000  68 65 6c 6c 6f "));
+        assert!(output.contains("received: a b\n00000000  61 20 62 "));
+    }
+
+    #[test]
+    fn binary_payloads() {
+        let output = run_lines(
+            echo_peer(|data| data),
+            "writehex 00ff 7f\nrecv\nwriteb64 AP9/\nrecv\nwritehex 0\nwriteb64 !\nsendfile /nonexistent\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            output
+                .matches("00000000  00 ff 7f                                         |...|")
+                .count(),
+            2
+        );
+        assert!(output.contains("invalid hex payload: odd number of digits (1)"));
+        assert!(output.contains("invalid base64 payload"));
+        assert!(output.contains("cannot read /nonexistent"));
+    }
+
+    #[test]
+    fn write_over_tcp() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut remote = StreamTransport::new(stream);
+            while let Ok(data) = remote.recv() {
+                remote.send(&data).unwrap();
+            }
+        });
+
+        let transport = open_transport(&TransportSpec::Tcp(address), DVC_NAME).unwrap();
+        let big = "x".repeat(4000);
+        let output = run_lines(
+            transport,
+            &format!("write hello\nrecv\nwrite {big}\nrecv\n"),
+        )
+        .unwrap();
+
+        assert!(output.contains("received: hello"));
+        assert!(output.contains(&format!("received: {big}")));
+    }
+
+    #[test]
+    fn message_protocol() {
+        let (local, remote) = Loopback::pair();
+        let listener = ChannelListener::new(ListenerConfig::new(DVC_NAME, || {
+            Box::new(MessageHandler::default())
+        }));
+        thread::spawn(move || listener.serve(Box::new(remote)));
+
+        let output = run_lines(
+            MessageTransport::new(local),
+            "write hello\nwrite world\nrecv\nrecv\nverify again\n",
+        )
+        .unwrap();
+
+        let hello = output.find("received: hello").unwrap();
+        assert!(output[hello..].contains("received: world"));
+        assert!(output.contains("PASS (5 bytes)"));
+    }
+
+    #[test]
+    fn message_handshake() {
+        let (local, remote) = Loopback::pair();
+        let listener = ChannelListener::new(ListenerConfig::new(DVC_NAME, || {
+            Box::new(MessageHandler::default())
+        }));
+        thread::spawn(move || listener.serve(Box::new(remote)));
+
+        let transport: Box<dyn DvcTransport> = Box::new(local);
+        let mut transport = MessageTransport::new(transport);
+        let negotiated = handshake(&mut transport, Timeouts::default()).unwrap();
+        assert_eq!(negotiated.version, echo_dvc_proto::PROTOCOL_VERSION);
+
+        let output = run_lines(transport, "verify hello\n").unwrap();
+        assert!(output.contains("PASS (5 bytes)"));
+    }
+
+    #[test]
+    fn silent_client_fails_the_handshake() {
+        let (local, _remote) = Loopback::pair();
+        let transport: Box<dyn DvcTransport> = Box::new(local);
+        let mut transport = MessageTransport::new(transport);
+        let timeouts = Timeouts {
+            read: Some(Duration::from_millis(10)),
+            write: None,
+        };
+
+        let err = handshake(&mut transport, timeouts).unwrap_err();
+        assert_eq!(ExitStatus::of(err.into()), ExitStatus::Timeout);
+    }
+
+    #[test]
+    fn stops_at_end_of_input() {
+        let output = run_lines(echo_peer(|data| data), "nope\n").unwrap();
+
+        assert!(output.contains("invalid command"));
+    }
+
+    #[test]
+    fn closed_channel_is_an_error() {
+        let (transport, _) = Loopback::pair();
+        let err = run_lines(transport, "write hello\n").unwrap_err();
+
+        assert!(err.to_string().starts_with("error writing to channel"));
+    }
+
+    #[test]
+    fn verify_command() {
+        let output = run_lines(
+            echo_peer(|data| data),
+            "verify hello
+check x
+write y
+",
+        )
+        .unwrap();
+
+        assert_eq!(output.matches("PASS").count(), 2);
+        assert!(output.contains("PASS (5 bytes)"));
+        assert!(output.ends_with("verified: 2 passed, 0 failed\n"));
+    }
+
+    #[test]
+    fn verify_needs_message_boundaries() {
+        let mut session = Session::start(
+            Box::new(echo_peer(|data| data)),
+            Incoming::Queue,
+            OutputFormat::Text,
+            Box::new(|_| {}),
+        )
+        .unwrap();
+
+        let mut output = Vec::new();
+        let mut input = Lines::new(&b"verify hello\nwrite hello\n"[..], io::sink());
+        run(
+            &mut session,
+            &mut input,
+            &mut output,
+            false,
+            false,
+            OutputFormat::Text,
+        )
+        .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("verify needs --protocol message with the xrdp transport"));
+        assert!(output.ends_with("sent 5 bytes\n"));
+    }
+
+    #[test]
+    fn verify_skips_queued_messages() {
+        let (transport, mut remote) = Loopback::pair();
+        remote.send(b"unsolicited").unwrap();
+        thread::spawn(move || {
+            while let Ok(data) = remote.recv() {
+                if remote.send(&data).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut session = Session::start(
+            Box::new(transport),
+            Incoming::Queue,
+            OutputFormat::Text,
+            Box::new(|_| {}),
+        )
+        .unwrap();
+        while session.queued() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut output = Vec::new();
+        let mut input = Lines::new(&b"verify hello\nrecv\n"[..], io::sink());
+        run(
+            &mut session,
+            &mut input,
+            &mut output,
+            false,
+            true,
+            OutputFormat::Text,
+        )
+        .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("received: hello\nPASS (5 bytes)\nreceived: unsolicited\n"));
+        assert!(output.ends_with("verified: 1 passed, 0 failed\n"));
+    }
+
+    #[test]
+    fn verify_reports_the_difference() {
+        let transport = echo_peer(|mut data| {
+            data[1] = b'A';
+            data
+        });
+
+        let output = run_with(transport, "write hello\n", true, OutputFormat::Text).unwrap();
+
+        assert!(
+            output.contains("FAIL: first difference at offset 1 (sent 5 bytes, received 5 bytes)")
+        );
+        assert!(output.contains("- 00000000  68 65 6c 6c 6f"));
+        assert!(output.contains("+ 00000000  68 41 6c 6c 6f"));
+        assert!(output.ends_with("verified: 0 passed, 1 failed\n"));
+    }
+
+    #[test]
+    fn messages_are_received_in_the_background() {
+        let (transport, mut remote) = Loopback::pair();
+        remote.send(b"unsolicited").unwrap();
+
+        let peer = thread::spawn(move || {
+            // Reply nothing to the first message.
+            remote.recv().unwrap();
+            let data = remote.recv().unwrap();
+            remote.send(&data).unwrap();
+            remote
+        });
+        let output = run_with(
+            transport,
+            "write ignored\nwrite echoed\nrecv\nrecv\nrecv 10ms\n",
+            false,
+            OutputFormat::Text,
+        )
+        .unwrap();
+        drop(peer.join().unwrap());
+
+        assert!(output.contains("received: unsolicited\nreceived: echoed\n"));
+        assert!(output.contains("no message received within 10ms"));
+    }
+}
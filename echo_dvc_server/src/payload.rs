@@ -0,0 +1,201 @@
+//! Payloads of the write commands and display of the received data.
+
+use std::{fmt, fs, io};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use clap::ValueEnum;
+
+use crate::verify::hexdump;
+
+/// Data to write, as given on a command line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Payload<'a> {
+    /// Literal text.
+    Text(&'a str),
+    /// Hexadecimal digits, whitespace is ignored.
+    Hex(&'a str),
+    Base64(&'a str),
+    /// Content of a file.
+    File(&'a str),
+}
+
+impl Payload<'_> {
+    /// Name of the command writing this payload.
+    pub fn command(&self) -> &'static str {
+        match self {
+            Payload::Text(_) => "write",
+            Payload::Hex(_) => "writehex",
+            Payload::Base64(_) => "writeb64",
+            Payload::File(_) => "sendfile",
+        }
+    }
+
+    pub fn bytes(&self) -> Result<Vec<u8>, PayloadError> {
+        match self {
+            Payload::Text(text) => Ok(text.as_bytes().to_vec()),
+            Payload::Hex(digits) => decode_hex(digits),
+            Payload::Base64(encoded) => {
+                let encoded: String = encoded.split_whitespace().collect();
+                STANDARD.decode(encoded).map_err(PayloadError::Base64)
+            }
+            Payload::File(path) => {
+                fs::read(path).map_err(|err| PayloadError::File(path.to_string(), err))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PayloadError {
+    Hex(String),
+    Base64(base64::DecodeError),
+    File(String, io::Error),
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadError::Hex(reason) => write!(f, "invalid hex payload: {reason}"),
+            PayloadError::Base64(err) => write!(f, "invalid base64 payload: {err}"),
+            PayloadError::File(path, err) => write!(f, "cannot read {path}: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for PayloadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PayloadError::Hex(_) => None,
+            PayloadError::Base64(err) => Some(err),
+            PayloadError::File(_, err) => Some(err),
+        }
+    }
+}
+
+fn decode_hex(digits: &str) -> Result<Vec<u8>, PayloadError> {
+    let digits: Vec<u8> = digits
+        .bytes()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(PayloadError::Hex(format!(
+            "odd number of digits ({})",
+            digits.len()
+        )));
+    }
+
+    let nibble = |digit: u8| char::from(digit).to_digit(16);
+    digits
+        .chunks_exact(2)
+        .map(|pair| match (nibble(pair[0]), nibble(pair[1])) {
+            (Some(high), Some(low)) => Ok((high << 4 | low) as u8),
+            _ => Err(PayloadError::Hex(format!(
+                "not a byte: {:?}",
+                String::from_utf8_lossy(pair)
+            ))),
+        })
+        .collect()
+}
+
+/// How received data is displayed.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// Data as text, invalid UTF-8 being replaced.
+    Text,
+    /// Hexdump of the data.
+    Hex,
+    Both,
+}
+
+impl OutputFormat {
+    pub fn shows_text(self) -> bool {
+        matches!(self, OutputFormat::Text | OutputFormat::Both)
+    }
+
+    pub fn shows_hex(self) -> bool {
+        matches!(self, OutputFormat::Hex | OutputFormat::Both)
+    }
+}
+
+/// Received data as shown by the prompt, ending with a newline.
+pub fn display(data: &[u8], format: OutputFormat) -> String {
+    let mut display = match format.shows_text() {
+        true => format!("received: {}\n", String::from_utf8_lossy(data)),
+        false => format!("received {} bytes\n", data.len()),
+    };
+    if format.shows_hex() {
+        display.push_str(&hexdump(data));
+    }
+    display
+}
+
+/// Compact hexadecimal string, as accepted by `writehex`.
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_payload() {
+        assert_eq!(
+            Payload::Hex("DEADbeef").bytes().unwrap(),
+            [0xde, 0xad, 0xbe, 0xef]
+        );
+        assert_eq!(Payload::Hex("00 01\t0a").bytes().unwrap(), [0, 1, 10]);
+        assert!(Payload::Hex("").bytes().unwrap().is_empty());
+        assert!(matches!(
+            Payload::Hex("abc").bytes(),
+            Err(PayloadError::Hex(_))
+        ));
+        assert!(matches!(
+            Payload::Hex("zz").bytes(),
+            Err(PayloadError::Hex(_))
+        ));
+        assert!(matches!(
+            Payload::Hex("é0").bytes(),
+            Err(PayloadError::Hex(_))
+        ));
+    }
+
+    #[test]
+    fn base64_payload() {
+        assert_eq!(
+            Payload::Base64("3q2+7w==").bytes().unwrap(),
+            [0xde, 0xad, 0xbe, 0xef]
+        );
+        assert_eq!(Payload::Base64("aGVs bG8=").bytes().unwrap(), b"hello");
+        assert!(matches!(
+            Payload::Base64("a").bytes(),
+            Err(PayloadError::Base64(_))
+        ));
+    }
+
+    #[test]
+    fn file_payload() {
+        let path = std::env::temp_dir().join(format!("echo_dvc_payload_{}", std::process::id()));
+        fs::write(&path, [0, 1, 2, 255]).unwrap();
+
+        let bytes = Payload::File(path.to_str().unwrap()).bytes();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(bytes.unwrap(), [0, 1, 2, 255]);
+        assert!(matches!(
+            Payload::File("/nonexistent/payload").bytes(),
+            Err(PayloadError::File(..))
+        ));
+    }
+
+    #[test]
+    fn display_formats() {
+        assert_eq!(display(b"hi", OutputFormat::Text), "received: hi\n");
+        assert_eq!(
+            display(b"hi", OutputFormat::Hex),
+            "received 2 bytes\n00000000  68 69                                            |hi|\n"
+        );
+        assert!(display(b"hi", OutputFormat::Both).starts_with("received: hi\n00000000  68 69"));
+        assert_eq!(to_hex(&[0xde, 0xad, 0x01]), "dead01");
+    }
+}
@@ -0,0 +1,195 @@
+//! Line input of the interactive prompt.
+
+use std::{
+    io::{self, BufRead, Write},
+    path::PathBuf,
+};
+
+use log::debug;
+use rustyline::{
+    Context, Editor, ExternalPrinter, Helper,
+    completion::{Completer, FilenameCompleter, Pair},
+    config::Config,
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    history::DefaultHistory,
+    validate::Validator,
+};
+
+use crate::{
+    commands::{self, COMMANDS},
+    session::Printer,
+};
+
+/// Entries kept in the history file.
+const HISTORY_SIZE: usize = 1000;
+
+/// Source of the lines of the prompt.
+pub trait LineInput {
+    /// Next line, `None` at the end of the input.
+    fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>>;
+}
+
+/// Lines of a reader, without editing, the prompt being written to `W`. Used
+/// when the input is not a terminal.
+pub struct Lines<R, W> {
+    reader: R,
+    prompt_output: W,
+}
+
+impl<R: BufRead, W: Write> Lines<R, W> {
+    pub fn new(reader: R, prompt_output: W) -> Self {
+        Self {
+            reader,
+            prompt_output,
+        }
+    }
+}
+
+impl<R: BufRead, W: Write> LineInput for Lines<R, W> {
+    fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        write!(self.prompt_output, "{prompt}")?;
+        self.prompt_output.flush()?;
+
+        let mut line = String::new();
+        match self.reader.read_line(&mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line)),
+        }
+    }
+}
+
+/// Terminal prompt with line editing, completion of the command names and
+/// of the `sendfile` paths, and a history kept in `history`.
+pub struct Terminal {
+    editor: Editor<CommandHelper, DefaultHistory>,
+    history: Option<PathBuf>,
+}
+
+impl Terminal {
+    pub fn new(history: Option<PathBuf>) -> io::Result<Self> {
+        let config = Config::builder()
+            .max_history_size(HISTORY_SIZE)
+            .map_err(to_io_error)?
+            .history_ignore_dups(true)
+            .map_err(to_io_error)?
+            .build();
+        let mut editor = Editor::with_config(config).map_err(to_io_error)?;
+        editor.set_helper(Some(CommandHelper {
+            filenames: FilenameCompleter::new(),
+        }));
+
+        // Missing on the first run.
+        if let Some(path) = &history
+            && let Err(err) = editor.load_history(path)
+        {
+            debug!("cannot load history from {}: {err}", path.display());
+        }
+
+        Ok(Self { editor, history })
+    }
+
+    /// Output printing above the line being edited.
+    pub fn printer(&mut self) -> io::Result<Printer> {
+        let mut printer = self.editor.create_external_printer().map_err(to_io_error)?;
+        Ok(Box::new(move |text| {
+            let _ = printer.print(text);
+        }))
+    }
+}
+
+impl LineInput for Terminal {
+    fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        loop {
+            match self.editor.readline(prompt) {
+                Ok(line) => {
+                    if !line.trim().is_empty() {
+                        self.editor
+                            .add_history_entry(line.as_str())
+                            .map_err(to_io_error)?;
+                    }
+                    return Ok(Some(line));
+                }
+                // Ctrl-C drops the current line.
+                Err(ReadlineError::Interrupted) => {}
+                Err(ReadlineError::Eof) => return Ok(None),
+                Err(err) => return Err(to_io_error(err)),
+            }
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if let Some(path) = &self.history
+            && let Err(err) = self.editor.save_history(path)
+        {
+            debug!("cannot save history to {}: {err}", path.display());
+        }
+    }
+}
+
+fn to_io_error(err: ReadlineError) -> io::Error {
+    match err {
+        ReadlineError::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+/// History file used when none is given: `.echo_dvc_history` in the home
+/// directory.
+pub fn default_history() -> Option<PathBuf> {
+    std::env::home_dir().map(|home| home.join(".echo_dvc_history"))
+}
+
+struct CommandHelper {
+    filenames: FilenameCompleter,
+}
+
+impl Completer for CommandHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+        let start = before.len() - before.trim_start().len();
+
+        match before[start..].split_once(' ') {
+            None => {
+                let candidates = commands::complete(&before[start..])
+                    .into_iter()
+                    .map(|name| Pair {
+                        display: name.to_string(),
+                        replacement: format!("{name} "),
+                    })
+                    .collect();
+                Ok((start, candidates))
+            }
+            Some((command, _)) if takes_path(command) => self.filenames.complete(line, pos, ctx),
+            Some(_) => Ok((pos, Vec::new())),
+        }
+    }
+}
+
+fn takes_path(command: &str) -> bool {
+    COMMANDS
+        .iter()
+        .filter(|spec| spec.arg == "PATH")
+        .flat_map(|spec| spec.names)
+        .any(|name| name.eq_ignore_ascii_case(command))
+}
+
+impl Hinter for CommandHelper {
+    type Hint = String;
+}
+
+impl Highlighter for CommandHelper {}
+
+impl Validator for CommandHelper {}
+
+impl Helper for CommandHelper {}
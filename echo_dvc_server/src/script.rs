@@ -0,0 +1,324 @@
+//! Non-interactive mode, for automated smoke tests.
+//!
+//! Commands are the ones of the prompt. Each one is reported on its own line
+//! as a JSON object so that the output can be checked by other tools.
+
+use std::io::{self, Write};
+
+use echo_dvc_proto::DvcTransport;
+use serde_json::{Value, json};
+
+use crate::{
+    commands::Command,
+    exchange,
+    payload::{OutputFormat, to_hex},
+    verify::{Tally, first_difference, hexdump_diff},
+};
+
+/// Result of a script that ran to completion.
+#[derive(Debug, PartialEq)]
+pub enum ScriptResult {
+    Passed,
+    /// A reply differed from what was written, or a command was invalid.
+    Failed,
+}
+
+/// Run `commands` in order. Blank lines and lines starting with `#` are
+/// skipped.
+///
+/// Every write is checked against its reply, and a mismatch reports the
+/// first differing offset. `verify` commands, or all writes with
+/// `verify_writes`, also report a hexdump diff. The script ends with a
+/// summary of the checks.
+///
+/// Sent and received data are reported as text, as a hex string in a
+/// `_hex` suffixed field, or both depending on `format`.
+///
+/// Failed commands do not stop the script, but a channel error does: it is
+/// reported then returned. A reply which does not come within the read
+/// timeout of the channel is such an error, reported as a `timeout`.
+pub fn run_script<'a>(
+    transport: &mut dyn DvcTransport,
+    commands: impl IntoIterator<Item = &'a str>,
+    mut output: impl Write,
+    verify_writes: bool,
+    format: OutputFormat,
+) -> io::Result<ScriptResult> {
+    let mut tally = Tally::default();
+    let ret = run_commands(
+        transport,
+        commands,
+        &mut output,
+        verify_writes,
+        format,
+        &mut tally,
+    );
+
+    let summary = json!({
+        "summary": {
+            "passed": tally.passed,
+            "failed": tally.failed,
+        },
+    });
+    writeln!(output, "{summary}")?;
+    output.flush()?;
+
+    ret
+}
+
+fn run_commands<'a>(
+    transport: &mut dyn DvcTransport,
+    commands: impl IntoIterator<Item = &'a str>,
+    mut output: impl Write,
+    verify_writes: bool,
+    format: OutputFormat,
+    tally: &mut Tally,
+) -> io::Result<ScriptResult> {
+    let mut result = ScriptResult::Passed;
+
+    for (index, line) in commands.into_iter().enumerate() {
+        let line_number = index + 1;
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+
+        let (payload, verify) = match Command::parse(line) {
+            Command::Empty | Command::Help => continue,
+            Command::Quit => break,
+            Command::Write(payload) => (payload, verify_writes),
+            Command::Verify(payload) => (payload, true),
+            Command::Invalid(command) => {
+                result = ScriptResult::Failed;
+                let report = json!({
+                    "line": line_number,
+                    "command": command,
+                    "status": "invalid",
+                });
+                writeln!(output, "{report}")?;
+                continue;
+            }
+            // Every write already waits for its reply.
+            Command::Recv(_) => {
+                result = ScriptResult::Failed;
+                let report = json!({
+                    "line": line_number,
+                    "command": "recv",
+                    "status": "invalid",
+                    "error": "recv is only available in the prompt",
+                });
+                writeln!(output, "{report}")?;
+                continue;
+            }
+        };
+        let name = if verify { "verify" } else { payload.command() };
+
+        let data = match payload.bytes() {
+            Ok(data) => data,
+            Err(err) => {
+                result = ScriptResult::Failed;
+                let report = json!({
+                    "line": line_number,
+                    "command": name,
+                    "status": "invalid",
+                    "error": err.to_string(),
+                });
+                writeln!(output, "{report}")?;
+                continue;
+            }
+        };
+
+        let read = match exchange(transport, &data) {
+            Ok(read) => read,
+            Err(err) => {
+                let status = match err.kind() {
+                    io::ErrorKind::TimedOut => "timeout",
+                    _ => "error",
+                };
+                let report = json!({
+                    "line": line_number,
+                    "command": name,
+                    "status": status,
+                    "error": err.to_string(),
+                });
+                writeln!(output, "{report}")?;
+                return Err(err);
+            }
+        };
+
+        let mut report = json!({
+            "line": line_number,
+            "command": name,
+            "status": "ok",
+        });
+        insert_data(&mut report, "sent", &data, format);
+        insert_data(&mut report, "received", &read, format);
+
+        let difference = first_difference(&data, &read);
+        tally.record(difference.is_none());
+        if let Some(offset) = difference {
+            result = ScriptResult::Failed;
+            report["status"] = "mismatch".into();
+            report["offset"] = offset.into();
+            if verify {
+                report["diff"] = hexdump_diff(&data, &read).into();
+            }
+        }
+
+        writeln!(output, "{report}")?;
+    }
+
+    Ok(result)
+}
+
+fn insert_data(report: &mut Value, key: &str, data: &[u8], format: OutputFormat) {
+    if format.shows_text() {
+        report[key] = String::from_utf8_lossy(data).into();
+    }
+    if format.shows_hex() {
+        report[format!("{key}_hex")] = to_hex(data).into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use echo_dvc_proto::{Loopback, Timeouts, test_util::echo_peer};
+    use std::time::Duration;
+
+    fn run(
+        transport: &mut dyn DvcTransport,
+        script: &str,
+    ) -> (io::Result<ScriptResult>, Vec<Value>) {
+        run_with_format(transport, script, OutputFormat::Text)
+    }
+
+    fn run_with_format(
+        transport: &mut dyn DvcTransport,
+        script: &str,
+        format: OutputFormat,
+    ) -> (io::Result<ScriptResult>, Vec<Value>) {
+        let mut output = Vec::new();
+        let result = run_script(transport, script.lines(), &mut output, false, format);
+        let reports = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        (result, reports)
+    }
+
+    #[test]
+    fn passing_script() {
+        let mut transport = echo_peer(|data| data);
+        let (result, reports) = run(
+            &mut transport,
+            "# smoke test\nwrite hello\n\nput a b\nquit\nwrite never\n",
+        );
+
+        assert_eq!(result.unwrap(), ScriptResult::Passed);
+        assert_eq!(
+            reports,
+            [
+                json!({"line": 2, "command": "write", "status": "ok", "sent": "hello", "received": "hello"}),
+                json!({"line": 4, "command": "write", "status": "ok", "sent": "a b", "received": "a b"}),
+                json!({"summary": {"passed": 2, "failed": 0}}),
+            ]
+        );
+    }
+
+    #[test]
+    fn mismatch_and_invalid_commands_fail() {
+        let mut transport = echo_peer(|mut data| {
+            data.reverse();
+            data
+        });
+        let (result, reports) = run(&mut transport, "write abc\nwrite aba\nnope\n");
+
+        assert_eq!(result.unwrap(), ScriptResult::Failed);
+        assert_eq!(reports[0]["status"], "mismatch");
+        assert_eq!(reports[0]["received"], "cba");
+        assert_eq!(reports[0]["offset"], 0);
+        assert!(reports[0].get("diff").is_none());
+        assert_eq!(reports[1]["status"], "ok");
+        assert_eq!(
+            reports[2],
+            json!({"line": 3, "command": "nope", "status": "invalid"})
+        );
+    }
+
+    #[test]
+    fn channel_error_stops_the_script() {
+        let (mut transport, _) = Loopback::pair();
+        let (result, reports) = run(&mut transport, "write hello\nwrite again\n");
+
+        assert!(result.is_err());
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0]["status"], "error");
+        assert_eq!(reports[1], json!({"summary": {"passed": 0, "failed": 0}}));
+    }
+
+    #[test]
+    fn silent_peer_times_out() {
+        let (mut transport, _remote) = Loopback::pair();
+        transport
+            .set_timeouts(Timeouts {
+                read: Some(Duration::from_millis(10)),
+                write: None,
+            })
+            .unwrap();
+        let (result, reports) = run(&mut transport, "write hello\nwrite again\n");
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(reports[0]["status"], "timeout");
+        assert_eq!(
+            reports[0]["error"],
+            "error reading from channel: no message received within 10ms"
+        );
+        assert_eq!(reports.len(), 2);
+    }
+
+    #[test]
+    fn verify_reports_a_diff() {
+        let mut transport = echo_peer(|mut data| {
+            data.truncate(2);
+            data
+        });
+        let (result, reports) = run(&mut transport, "verify abc\ncheck ab\n");
+
+        assert_eq!(result.unwrap(), ScriptResult::Failed);
+        assert_eq!(reports[0]["command"], "verify");
+        assert_eq!(reports[0]["status"], "mismatch");
+        assert_eq!(reports[0]["offset"], 2);
+        assert_eq!(
+            reports[0]["diff"],
+            "- 00000000  61 62 63                                         |abc|\n\
+             + 00000000  61 62                                            |ab|\n"
+        );
+        assert_eq!(reports[1]["status"], "ok");
+        assert_eq!(reports[2], json!({"summary": {"passed": 1, "failed": 1}}));
+    }
+
+    #[test]
+    fn binary_payloads() {
+        let mut transport = echo_peer(|data| data);
+        let (result, reports) = run_with_format(
+            &mut transport,
+            "writehex 00ff\nwriteb64 AP8=\nwritehex 0g\n",
+            OutputFormat::Both,
+        );
+
+        assert_eq!(result.unwrap(), ScriptResult::Failed);
+        assert_eq!(
+            reports[0],
+            json!({"line": 1, "command": "writehex", "status": "ok", "sent": "\0\u{fffd}", "sent_hex": "00ff", "received": "\0\u{fffd}", "received_hex": "00ff"})
+        );
+        assert_eq!(reports[1]["command"], "writeb64");
+        assert_eq!(reports[1]["received_hex"], "00ff");
+        assert_eq!(reports[2]["status"], "invalid");
+        assert_eq!(
+            reports[2]["error"],
+            "invalid hex payload: not a byte: \"0g\""
+        );
+        assert_eq!(reports[3], json!({"summary": {"passed": 2, "failed": 0}}));
+    }
+}
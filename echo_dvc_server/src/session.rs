@@ -0,0 +1,383 @@
+//! Channel of the interactive prompt, with messages received in the
+//! background.
+//!
+//! Writes do not wait for a reply: a thread receives every incoming message
+//! as it arrives, so that the peer can send nothing back, or send messages
+//! of its own.
+
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use echo_dvc_proto::{DvcReceiver, DvcSender, DvcTransport};
+use log::debug;
+
+use crate::{
+    error::{ChannelError, Operation},
+    payload::{OutputFormat, display},
+};
+
+/// What happens to the messages nobody is waiting for.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Incoming {
+    /// Print them as they arrive.
+    Print,
+    /// Keep them until they are read with `recv`.
+    Queue,
+}
+
+/// Time `recv` waits when no timeout is given.
+pub const DEFAULT_RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Output of the messages printed as they arrive.
+pub type Printer = Box<dyn FnMut(String) + Send>;
+
+pub struct Session {
+    sender: Box<dyn DvcSender>,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    inbox: Mutex<Inbox>,
+    arrived: Condvar,
+}
+
+#[derive(Default)]
+struct Inbox {
+    messages: VecDeque<Vec<u8>>,
+    /// Messages ever queued, so that a request tells the ones which arrived
+    /// after it was sent.
+    received: u64,
+    /// Replies to timed out requests, dropped when they arrive late.
+    late: usize,
+    /// Callers blocked in `recv`, which take the messages even when they are
+    /// otherwise printed.
+    waiting: usize,
+    /// Error which stopped the reception.
+    closed: Option<io::Error>,
+    /// Set by `close`, so that the reception stops quietly.
+    closing: bool,
+}
+
+impl Session {
+    /// Split `transport` and start receiving its messages.
+    pub fn start(
+        transport: Box<dyn DvcTransport>,
+        incoming: Incoming,
+        format: OutputFormat,
+        printer: Printer,
+    ) -> io::Result<Self> {
+        let (sender, receiver) = transport.split()?;
+        let shared = Arc::new(Shared {
+            inbox: Mutex::new(Inbox::default()),
+            arrived: Condvar::new(),
+        });
+
+        let reader = Reader {
+            receiver,
+            shared: Arc::clone(&shared),
+            incoming,
+            format,
+            printer,
+        };
+        thread::spawn(move || reader.run());
+
+        Ok(Self { sender, shared })
+    }
+
+    /// Send `data` without waiting for a reply.
+    pub fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.sender
+            .send(data)
+            .map_err(|err| ChannelError::wrap(Operation::Write, err))
+    }
+
+    /// Next message, waiting up to `timeout`. `None` if none arrived in time.
+    pub fn recv(&self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        self.shared.lock().waiting += 1;
+        self.wait(timeout, None)
+    }
+
+    /// Send `data` and wait up to `timeout` for the reply: the first message
+    /// arriving after it, the ones queued before are left for `recv`. A reply
+    /// which does not come in time is dropped when it arrives.
+    pub fn request(&mut self, data: &[u8], timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        // Wait before sending so that a fast reply is not printed instead.
+        let after = {
+            let mut inbox = self.shared.lock();
+            inbox.waiting += 1;
+            inbox.received
+        };
+        if let Err(err) = self.send(data) {
+            self.shared.lock().waiting -= 1;
+            return Err(err);
+        }
+        self.wait(timeout, Some(after))
+    }
+
+    /// Messages received and not read yet.
+    pub fn queued(&self) -> usize {
+        self.shared.lock().messages.len()
+    }
+
+    pub fn close(&mut self) -> io::Result<()> {
+        self.shared.lock().closing = true;
+        self.sender.close()
+    }
+
+    /// Wait for the next message, or the first one queued as message
+    /// `after` or later.
+    fn wait(&self, timeout: Duration, after: Option<u64>) -> io::Result<Option<Vec<u8>>> {
+        let deadline = Instant::now() + timeout;
+        let mut inbox = self.shared.lock();
+
+        let ret = loop {
+            let first = inbox.received - inbox.messages.len() as u64;
+            let index = after.map_or(0, |after| after.saturating_sub(first) as usize);
+            if let Some(message) = inbox.messages.remove(index) {
+                break Ok(Some(message));
+            }
+            // The error is kept for the next calls, hand out a copy.
+            if let Some(err) = &inbox.closed {
+                let err = io::Error::new(err.kind(), err.to_string());
+                break Err(ChannelError::wrap(Operation::Read, err));
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                if after.is_some() {
+                    inbox.late += 1;
+                }
+                break Ok(None);
+            }
+            inbox = self
+                .shared
+                .arrived
+                .wait_timeout(inbox, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        };
+
+        inbox.waiting -= 1;
+        ret
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Inbox> {
+        self.inbox.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Timeout of `recv`, as parsed by [`parse_duration`]. Defaults to
+/// [`DEFAULT_RECV_TIMEOUT`].
+pub fn parse_timeout(arg: &str) -> Result<Duration, String> {
+    if arg.is_empty() {
+        return Ok(DEFAULT_RECV_TIMEOUT);
+    }
+    parse_duration(arg)
+}
+
+/// A number of seconds, or a number followed by `ms` or `s`.
+pub fn parse_duration(arg: &str) -> Result<Duration, String> {
+    let (value, unit) = match arg.strip_suffix("ms") {
+        Some(value) => (value, 1e-3),
+        None => (arg.strip_suffix('s').unwrap_or(arg), 1.0),
+    };
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(|value| Duration::try_from_secs_f64(value * unit).ok())
+        .ok_or_else(|| format!("invalid timeout: {arg}"))
+}
+
+struct Reader {
+    receiver: Box<dyn DvcReceiver>,
+    shared: Arc<Shared>,
+    incoming: Incoming,
+    format: OutputFormat,
+    printer: Printer,
+}
+
+impl Reader {
+    fn run(mut self) {
+        loop {
+            let ret = self.receiver.recv();
+
+            let mut inbox = self.shared.lock();
+            match ret {
+                // Reads time out when the channel has a read timeout, there
+                // is just nothing to receive yet.
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => {}
+                Ok(message) if inbox.late > 0 => {
+                    inbox.late -= 1;
+                    debug!("dropped a late reply of {} bytes", message.len());
+                }
+                Ok(message) if self.incoming == Incoming::Print && inbox.waiting == 0 => {
+                    drop(inbox);
+                    (self.printer)(display(&message, self.format));
+                }
+                Ok(message) => {
+                    inbox.messages.push_back(message);
+                    inbox.received += 1;
+                    self.shared.arrived.notify_all();
+                }
+                Err(err) => {
+                    debug!("reception stopped: {err}");
+                    if self.incoming == Incoming::Print && !inbox.closing {
+                        (self.printer)(format!("channel closed: {err}\n"));
+                    }
+                    inbox.closed = Some(err);
+                    self.shared.arrived.notify_all();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use echo_dvc_proto::{Loopback, Timeouts};
+    use std::sync::mpsc;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn start(incoming: Incoming) -> (Session, Loopback, mpsc::Receiver<String>) {
+        let (local, remote) = Loopback::pair();
+        let (printed_tx, printed) = mpsc::channel();
+        let printer: Printer = Box::new(move |text| {
+            let _ = printed_tx.send(text);
+        });
+
+        let session = Session::start(Box::new(local), incoming, OutputFormat::Text, printer);
+        (session.unwrap(), remote, printed)
+    }
+
+    #[test]
+    fn send_does_not_wait() {
+        let (mut session, mut remote, _printed) = start(Incoming::Queue);
+
+        session.send(b"one").unwrap();
+        session.send(b"two").unwrap();
+        assert_eq!(remote.recv().unwrap(), b"one");
+        assert_eq!(remote.recv().unwrap(), b"two");
+    }
+
+    #[test]
+    fn queued_messages() {
+        let (session, mut remote, printed) = start(Incoming::Queue);
+
+        remote.send(b"unsolicited").unwrap();
+        remote.send(b"second").unwrap();
+        assert_eq!(session.recv(TIMEOUT).unwrap().unwrap(), b"unsolicited");
+        assert_eq!(session.recv(TIMEOUT).unwrap().unwrap(), b"second");
+        assert_eq!(session.recv(Duration::from_millis(10)).unwrap(), None);
+        assert!(printed.try_recv().is_err());
+    }
+
+    #[test]
+    fn printed_messages() {
+        let (mut session, mut remote, printed) = start(Incoming::Print);
+
+        remote.send(b"unsolicited").unwrap();
+        assert_eq!(printed.recv().unwrap(), "received: unsolicited\n");
+        assert_eq!(session.queued(), 0);
+
+        // A request gets its reply even though messages are printed.
+        let peer = thread::spawn(move || {
+            let request = remote.recv().unwrap();
+            remote.send(&request).unwrap();
+            remote
+        });
+        assert_eq!(session.request(b"ping", TIMEOUT).unwrap().unwrap(), b"ping");
+        assert!(printed.try_recv().is_err());
+
+        drop(peer.join().unwrap());
+        assert!(printed.recv().unwrap().starts_with("channel closed"));
+    }
+
+    #[test]
+    fn requests_get_their_own_reply() {
+        let (mut session, mut remote, _printed) = start(Incoming::Queue);
+        remote.send(b"unsolicited").unwrap();
+        while session.queued() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let peer = thread::spawn(move || {
+            let request = remote.recv().unwrap();
+            remote.send(&request).unwrap();
+            remote
+        });
+        assert_eq!(session.request(b"ping", TIMEOUT).unwrap().unwrap(), b"ping");
+        let mut remote = peer.join().unwrap();
+        assert_eq!(session.recv(TIMEOUT).unwrap().unwrap(), b"unsolicited");
+
+        // Answered too late: the reply is dropped, not taken by the next one.
+        let short = Duration::from_millis(10);
+        assert_eq!(session.request(b"slow", short).unwrap(), None);
+        assert_eq!(remote.recv().unwrap(), b"slow");
+        remote.send(b"slow").unwrap();
+        let peer = thread::spawn(move || {
+            let request = remote.recv().unwrap();
+            remote.send(&request).unwrap();
+            remote
+        });
+        assert_eq!(session.request(b"next", TIMEOUT).unwrap().unwrap(), b"next");
+        drop(peer.join().unwrap());
+        assert_eq!(session.queued(), 0);
+    }
+
+    #[test]
+    fn timeouts() {
+        assert_eq!(parse_timeout(""), Ok(DEFAULT_RECV_TIMEOUT));
+        assert_eq!(parse_timeout("2"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_timeout("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_timeout("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_timeout("0"), Ok(Duration::ZERO));
+        assert!(parse_timeout("-1").is_err());
+        assert!(parse_timeout("soon").is_err());
+    }
+
+    #[test]
+    fn timed_out_reads_are_retried() {
+        let (mut local, mut remote) = Loopback::pair();
+        local
+            .set_timeouts(Timeouts {
+                read: Some(Duration::from_millis(1)),
+                write: None,
+            })
+            .unwrap();
+        let session = Session::start(
+            Box::new(local),
+            Incoming::Queue,
+            OutputFormat::Text,
+            Box::new(|_| {}),
+        );
+        let session = session.unwrap();
+
+        thread::sleep(Duration::from_millis(20));
+        remote.send(b"late").unwrap();
+        assert_eq!(session.recv(TIMEOUT).unwrap().unwrap(), b"late");
+    }
+
+    #[test]
+    fn closed_channel() {
+        let (session, mut remote, _printed) = start(Incoming::Queue);
+
+        remote.send(b"last").unwrap();
+        remote.close().unwrap();
+
+        assert_eq!(session.recv(TIMEOUT).unwrap().unwrap(), b"last");
+        let err = session.recv(TIMEOUT).unwrap_err();
+        assert!(err.to_string().starts_with("error reading from channel"));
+    }
+}
@@ -0,0 +1,302 @@
+//! SOCKS5 proxy whose connections are made by the client.
+//!
+//! Applications of the remote session connect through the proxy, which
+//! opens a `tcp:HOST:PORT` stream for each of them: the client resolves the
+//! host and connects, if its configuration allows the destination. Only the
+//! `CONNECT` command without authentication is supported (RFC 1928).
+
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use echo_dvc_proto::{Mux, Refusal, StreamRefused, Target, pipe};
+use log::{debug, info, warn};
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+/// Time an application has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reply to a request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reply {
+    Succeeded = 0,
+    Failure = 1,
+    NotAllowed = 2,
+    HostUnreachable = 4,
+    ConnectionRefused = 5,
+    CommandNotSupported = 7,
+    AddressNotSupported = 8,
+}
+
+impl Reply {
+    /// Reply to a stream which could not be opened, from the refusal of the
+    /// client. Failures of the channel are general failures.
+    fn of(err: &io::Error) -> Self {
+        let refusal = err
+            .get_ref()
+            .and_then(|err| err.downcast_ref::<StreamRefused>())
+            .and_then(|refused| Refusal::of(&refused.reason));
+        match refusal {
+            Some(Refusal::NotAllowed) => Reply::NotAllowed,
+            Some(Refusal::Unresolved) => Reply::HostUnreachable,
+            Some(Refusal::ConnectFailed) => Reply::ConnectionRefused,
+            _ => Reply::Failure,
+        }
+    }
+}
+
+/// SOCKS5 proxy listening in the remote session.
+pub struct SocksProxy {
+    listener: TcpListener,
+    mux: Mux,
+}
+
+impl SocksProxy {
+    pub fn bind(address: SocketAddr, mux: Mux) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        Ok(Self { listener, mux })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serve the applications connecting to the proxy, each in its own
+    /// thread.
+    pub fn serve(self) {
+        for socket in self.listener.incoming() {
+            let socket = match socket {
+                Ok(socket) => socket,
+                Err(err) => {
+                    warn!("failed to accept a connection: {err}");
+                    continue;
+                }
+            };
+
+            let mux = self.mux.clone();
+            thread::spawn(move || {
+                let peer = socket.peer_addr().map(|peer| peer.to_string());
+                let peer = peer.unwrap_or_default();
+                if let Err(err) = proxy(socket, &mux, &peer) {
+                    debug!("{peer}: {err}");
+                }
+            });
+        }
+    }
+}
+
+/// Answer the request of the application on `socket`, then carry its
+/// connection.
+fn proxy(mut socket: TcpStream, mux: &Mux, peer: &str) -> io::Result<()> {
+    socket.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    if !choose_method(&mut socket)? {
+        return Ok(());
+    }
+    let target = match read_request(&mut socket)? {
+        Ok(target) => target,
+        Err(reply) => return send_reply(&mut socket, reply),
+    };
+    socket.set_read_timeout(None)?;
+
+    let stream = match mux.open(&target.to_string()) {
+        Ok(stream) => stream,
+        Err(err) => {
+            info!("{peer}: {err}");
+            return send_reply(&mut socket, Reply::of(&err));
+        }
+    };
+    send_reply(&mut socket, Reply::Succeeded)?;
+    info!("{peer}: connected by the client to {target}");
+    pipe(stream, socket)
+}
+
+/// Agree on no authentication, returns false when the application does not
+/// offer it.
+fn choose_method(socket: &mut TcpStream) -> io::Result<bool> {
+    let mut header = [0; 2];
+    socket.read_exact(&mut header)?;
+    check_version(header[0])?;
+    let mut methods = vec![0; header[1].into()];
+    socket.read_exact(&mut methods)?;
+
+    let method = match methods.contains(&NO_AUTHENTICATION) {
+        true => NO_AUTHENTICATION,
+        false => NO_ACCEPTABLE_METHOD,
+    };
+    socket.write_all(&[VERSION, method])?;
+    Ok(method == NO_AUTHENTICATION)
+}
+
+/// Destination of a `CONNECT` request, or the reply refusing the request.
+fn read_request(socket: &mut TcpStream) -> io::Result<Result<Target, Reply>> {
+    let mut header = [0; 4];
+    socket.read_exact(&mut header)?;
+    check_version(header[0])?;
+    let [_, command, _, address_type] = header;
+
+    let host = match address_type {
+        IPV4 => {
+            let mut address = [0; 4];
+            socket.read_exact(&mut address)?;
+            Ipv4Addr::from(address).to_string()
+        }
+        IPV6 => {
+            let mut address = [0; 16];
+            socket.read_exact(&mut address)?;
+            Ipv6Addr::from(address).to_string()
+        }
+        DOMAIN => {
+            let mut length = [0];
+            socket.read_exact(&mut length)?;
+            let mut name = vec![0; length[0].into()];
+            socket.read_exact(&mut name)?;
+            match String::from_utf8(name) {
+                Ok(name) if !name.is_empty() => name,
+                _ => return Ok(Err(Reply::AddressNotSupported)),
+            }
+        }
+        _ => return Ok(Err(Reply::AddressNotSupported)),
+    };
+    let mut port = [0; 2];
+    socket.read_exact(&mut port)?;
+    let port = u16::from_be_bytes(port);
+
+    if command != CONNECT {
+        return Ok(Err(Reply::CommandNotSupported));
+    }
+    Ok(Ok(Target::Tcp { host, port }))
+}
+
+fn check_version(version: u8) -> io::Result<()> {
+    match version {
+        VERSION => Ok(()),
+        version => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported SOCKS version {version}"),
+        )),
+    }
+}
+
+/// Send `reply`. The address the client connected from is not known here,
+/// an unspecified one is given instead.
+fn send_reply(socket: &mut TcpStream, reply: Reply) -> io::Result<()> {
+    socket.write_all(&[VERSION, reply as u8, 0, IPV4, 0, 0, 0, 0, 0, 0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use echo_dvc_proto::{
+        ChannelOptions, ForwardPolicy,
+        test_util::{message_mux, tcp_echo},
+    };
+    use std::net::Shutdown;
+
+    /// Proxy of a client allowed to connect to `connect`.
+    fn socks_proxy(connect: &str) -> SocketAddr {
+        let options = ChannelOptions::from([("connect".to_owned(), connect.to_owned())]);
+        let mux = message_mux(ForwardPolicy::from_options("message", &options).unwrap());
+        let proxy = SocksProxy::bind((Ipv4Addr::LOCALHOST, 0).into(), mux).unwrap();
+        let address = proxy.local_addr().unwrap();
+        thread::spawn(move || proxy.serve());
+        address
+    }
+
+    /// Send a request for `address` (type and bytes) and `port`, returns the
+    /// socket and the reply code.
+    fn request(proxy: SocketAddr, command: u8, address: &[u8], port: u16) -> (TcpStream, u8) {
+        let mut socket = TcpStream::connect(proxy).unwrap();
+        socket
+            .write_all(&[VERSION, 2, 2, NO_AUTHENTICATION])
+            .unwrap();
+        let mut method = [0; 2];
+        socket.read_exact(&mut method).unwrap();
+        assert_eq!(method, [VERSION, NO_AUTHENTICATION]);
+
+        let mut request = vec![VERSION, command, 0];
+        request.extend_from_slice(address);
+        request.extend_from_slice(&port.to_be_bytes());
+        socket.write_all(&request).unwrap();
+        let mut reply = [0; 10];
+        socket.read_exact(&mut reply).unwrap();
+        assert_eq!(reply[..4], [VERSION, reply[1], 0, IPV4]);
+        (socket, reply[1])
+    }
+
+    fn domain(name: &str) -> Vec<u8> {
+        let mut address = vec![DOMAIN, name.len() as u8];
+        address.extend_from_slice(name.as_bytes());
+        address
+    }
+
+    #[test]
+    fn connections_through_the_proxy() {
+        let proxy = socks_proxy("127.0.0.1:*");
+
+        for address in [vec![IPV4, 127, 0, 0, 1], domain("localhost")] {
+            let destination = tcp_echo();
+            let (mut socket, reply) = request(proxy, CONNECT, &address, destination.port());
+            assert_eq!(reply, Reply::Succeeded as u8);
+
+            socket.write_all(b"through the proxy").unwrap();
+            socket.shutdown(Shutdown::Write).unwrap();
+            let mut received = String::new();
+            socket.read_to_string(&mut received).unwrap();
+            assert_eq!(received, "through the proxy");
+        }
+    }
+
+    #[test]
+    fn refused_destinations() {
+        // Bound then closed, so that nothing listens there.
+        let closed = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let proxy = socks_proxy(&format!("127.0.0.1:{closed}, 10.0.0.0/8:443"));
+
+        let refused = [
+            (vec![IPV4, 127, 0, 0, 1], closed, Reply::ConnectionRefused),
+            (vec![IPV4, 127, 0, 0, 1], 22, Reply::NotAllowed),
+            (domain("host.invalid"), 22, Reply::NotAllowed),
+            (domain("host.invalid"), 443, Reply::HostUnreachable),
+        ];
+        for (address, port, expected) in refused {
+            let (_, reply) = request(proxy, CONNECT, &address, port);
+            assert_eq!(reply, expected as u8, "{address:?}:{port}");
+        }
+    }
+
+    #[test]
+    fn unsupported_requests() {
+        let proxy = socks_proxy("*:*");
+
+        let (_, reply) = request(proxy, 2, &[IPV4, 127, 0, 0, 1], 22);
+        assert_eq!(reply, Reply::CommandNotSupported as u8);
+
+        let mut socket = TcpStream::connect(proxy).unwrap();
+        socket
+            .write_all(&[VERSION, 1, NO_AUTHENTICATION, VERSION, CONNECT, 0, 9])
+            .unwrap();
+        let mut reply = [0; 12];
+        socket.read_exact(&mut reply).unwrap();
+        assert_eq!(reply[..4], [VERSION, NO_AUTHENTICATION, VERSION, 8]);
+
+        // Authentication is not supported.
+        let mut socket = TcpStream::connect(proxy).unwrap();
+        socket.write_all(&[VERSION, 1, 2]).unwrap();
+        let mut method = [0; 2];
+        socket.read_exact(&mut method).unwrap();
+        assert_eq!(method, [VERSION, NO_ACCEPTABLE_METHOD]);
+    }
+}
@@ -0,0 +1,168 @@
+//! Byte for byte comparison of an echoed reply with what was sent.
+
+use std::fmt::Write;
+
+/// Bytes shown on each hexdump row.
+const ROW_LENGTH: usize = 16;
+/// Differing rows shown before the diff is cut short.
+const MAX_DIFF_ROWS: usize = 8;
+
+/// Offset of the first byte where `received` differs from `sent`, if any.
+///
+/// When one is a prefix of the other, this is the length of the shorter one.
+pub fn first_difference(sent: &[u8], received: &[u8]) -> Option<usize> {
+    sent.iter()
+        .zip(received)
+        .position(|(a, b)| a != b)
+        .or_else(|| (sent.len() != received.len()).then(|| sent.len().min(received.len())))
+}
+
+/// Hexdump of the rows that differ, `-` for what was sent and `+` for what
+/// was received.
+pub fn hexdump_diff(sent: &[u8], received: &[u8]) -> String {
+    let rows = sent.len().max(received.len()).div_ceil(ROW_LENGTH);
+    let mut differing = (0..rows).filter(|&row| row_of(sent, row) != row_of(received, row));
+
+    let mut diff = String::new();
+    for row in differing.by_ref().take(MAX_DIFF_ROWS) {
+        for (sign, data) in [('-', sent), ('+', received)] {
+            let _ = writeln!(
+                diff,
+                "{sign} {}",
+                hexdump_row(row * ROW_LENGTH, row_of(data, row))
+            );
+        }
+    }
+
+    let remaining = differing.count();
+    if remaining > 0 {
+        let _ = writeln!(diff, "... {remaining} more differing row(s)");
+    }
+    diff
+}
+
+/// Hexdump of `data`, one row of `ROW_LENGTH` bytes per line.
+pub fn hexdump(data: &[u8]) -> String {
+    let mut dump = String::new();
+    for (row, bytes) in data.chunks(ROW_LENGTH).enumerate() {
+        let _ = writeln!(dump, "{}", hexdump_row(row * ROW_LENGTH, bytes));
+    }
+    dump
+}
+
+/// Bytes of `data` on hexdump row `row`, empty past its end.
+fn row_of(data: &[u8], row: usize) -> &[u8] {
+    let start = (row * ROW_LENGTH).min(data.len());
+    let end = (start + ROW_LENGTH).min(data.len());
+    &data[start..end]
+}
+
+fn hexdump_row(offset: usize, row: &[u8]) -> String {
+    let mut hex = String::new();
+    for i in 0..ROW_LENGTH {
+        match row.get(i) {
+            Some(byte) => {
+                let _ = write!(hex, "{byte:02x} ");
+            }
+            None => hex.push_str("   "),
+        }
+    }
+
+    let ascii: String = row
+        .iter()
+        .map(|&byte| match byte {
+            0x20..=0x7e => byte as char,
+            _ => '.',
+        })
+        .collect();
+
+    format!("{offset:08x}  {hex} |{ascii}|")
+}
+
+/// Verification results over a session.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Tally {
+    pub passed: usize,
+    pub failed: usize,
+}
+
+impl Tally {
+    pub fn record(&mut self, passed: bool) {
+        match passed {
+            true => self.passed += 1,
+            false => self.failed += 1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.passed + self.failed == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn difference_offset() {
+        assert_eq!(first_difference(b"hello", b"hello"), None);
+        assert_eq!(first_difference(b"", b""), None);
+        assert_eq!(first_difference(b"hello", b"hallo"), Some(1));
+        assert_eq!(first_difference(b"hello", b"hell"), Some(4));
+        assert_eq!(first_difference(b"", b"x"), Some(0));
+    }
+
+    #[test]
+    fn diff_shows_differing_rows() {
+        let sent: Vec<u8> = (0..40).collect();
+        let mut received = sent.clone();
+        received[20] = b'A';
+        received.truncate(35);
+
+        assert_eq!(
+            hexdump_diff(&sent, &received),
+            "- 00000010  10 11 12 13 14 15 16 17 18 19 1a 1b 1c 1d 1e 1f  |................|\n\
+             + 00000010  10 11 12 13 41 15 16 17 18 19 1a 1b 1c 1d 1e 1f  |....A...........|\n\
+             - 00000020  20 21 22 23 24 25 26 27                          | !\"#$%&'|\n\
+             + 00000020  20 21 22                                         | !\"|\n"
+        );
+    }
+
+    #[test]
+    fn diff_is_cut_short() {
+        let sent = vec![0; ROW_LENGTH * (MAX_DIFF_ROWS + 2)];
+        let received = vec![1; sent.len()];
+
+        let diff = hexdump_diff(&sent, &received);
+        assert_eq!(diff.lines().count(), MAX_DIFF_ROWS * 2 + 1);
+        assert!(diff.ends_with("... 2 more differing row(s)\n"));
+    }
+
+    #[test]
+    fn full_hexdump() {
+        let data: Vec<u8> = (0x41..0x53).collect();
+        assert_eq!(
+            hexdump(&data),
+            "00000000  41 42 43 44 45 46 47 48 49 4a 4b 4c 4d 4e 4f 50  |ABCDEFGHIJKLMNOP|\n\
+             00000010  51 52                                            |QR|\n"
+        );
+        assert_eq!(hexdump(b""), "");
+    }
+
+    #[test]
+    fn tally() {
+        let mut tally = Tally::default();
+        assert!(tally.is_empty());
+
+        tally.record(true);
+        tally.record(false);
+        tally.record(true);
+        assert_eq!(
+            tally,
+            Tally {
+                passed: 2,
+                failed: 1
+            }
+        );
+    }
+}